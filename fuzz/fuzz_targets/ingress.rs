@@ -0,0 +1,14 @@
+//! Raw frames, as the device would deliver them, however malformed.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use trust_fuzz::Harness;
+
+fuzz_target!(|frames: Vec<&[u8]>| {
+    let mut harness = Harness::new();
+    for frame in frames {
+        harness.inject(frame);
+    }
+    harness.settle();
+});
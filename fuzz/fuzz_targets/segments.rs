@@ -0,0 +1,76 @@
+//! Well-formed segments on an established connection, interleaved with what the application and
+//! the clock might do, to get deeper into the state machine than raw frames would.
+
+#![no_main]
+
+use std::io::Write;
+use std::net::Shutdown;
+use std::time::Duration;
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use trust_fuzz::{Harness, PEER_ISN};
+
+#[derive(Arbitrary, Debug)]
+enum Action {
+    /// A segment whose sequence and acknowledgment numbers are relative to the start of either
+    /// side's data.
+    Segment {
+        seq: u32,
+        ack: Option<u32>,
+        flags: u8,
+        window: u16,
+        payload: Vec<u8>,
+    },
+    Write(u16),
+    Shutdown,
+    Close,
+    /// Milliseconds.
+    Advance(u16),
+}
+
+fuzz_target!(|actions: Vec<Action>| {
+    let mut harness = Harness::new();
+    // keep reading, so that the receive window doesn't just stay shut
+    let mut reader = harness.stream.as_ref().unwrap().try_clone().unwrap();
+    std::thread::spawn(
+        move || while let Ok(1..) = std::io::Read::read(&mut reader, &mut [0u8; 1024]) {},
+    );
+
+    for action in actions {
+        match action {
+            Action::Segment {
+                seq,
+                ack,
+                flags,
+                window,
+                payload,
+            } => {
+                let seq = PEER_ISN.wrapping_add(1).wrapping_add(seq);
+                let ack = ack.map(|ack| ack.wrapping_add(1));
+                let frame = harness.segment(seq, ack, flags, window, &payload);
+                harness.inject(&frame);
+                harness.settle();
+            }
+            Action::Write(n) => {
+                if let Some(stream) = harness.stream.as_mut() {
+                    let _ = stream.write(&vec![0u8; n as usize]);
+                }
+            }
+            Action::Shutdown => {
+                if let Some(stream) = harness.stream.as_ref() {
+                    let _ = stream.shutdown(Shutdown::Write);
+                }
+            }
+            Action::Close => {
+                if let Some(stream) = harness.stream.take() {
+                    let _ = stream.shutdown(Shutdown::Both);
+                }
+            }
+            Action::Advance(ms) => {
+                harness.clock.advance(Duration::from_millis(ms.into()));
+                harness.tick();
+            }
+        }
+    }
+});
@@ -0,0 +1,149 @@
+//! What the fuzz targets share: an interface on an in-memory device, with a listener and an
+//! established connection for the fuzzer to find.
+
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use trust::{
+    Device, Interface, InterfaceBuilder, MemoryDevice, TcpListener, TcpStream, VirtualClock,
+};
+
+pub const US: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+pub const PEER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+pub const LISTEN_PORT: u16 = 8000;
+pub const PEER_PORT: u16 = 40000;
+/// The peer's initial sequence number on the established connection.
+pub const PEER_ISN: u32 = 1000;
+
+pub const FIN: u8 = 0x01;
+pub const SYN: u8 = 0x02;
+pub const RST: u8 = 0x04;
+pub const PSH: u8 = 0x08;
+
+#[path = "../../tests/common/tracked.rs"]
+mod tracked;
+use tracked::{Progress, Tracked};
+
+/// How long the packet loop gets to catch up before we decide it has died.
+const STALL: Duration = Duration::from_secs(5);
+
+pub struct Harness {
+    pub clock: VirtualClock,
+    pub stream: Option<TcpStream>,
+    peer: MemoryDevice,
+    progress: Arc<Progress>,
+    injected: usize,
+    listener: TcpListener,
+    // dropped last, so that the streams and the listener can go first
+    _iface: Interface,
+}
+
+impl Harness {
+    /// Sets up an interface at [`US`] that listens on [`LISTEN_PORT`], with one connection from
+    /// [`PEER`]:[`PEER_PORT`] already established.
+    pub fn new() -> Self {
+        let clock = VirtualClock::new();
+        let (nic, peer) = MemoryDevice::pair();
+        let (nic, progress) = Tracked::new(nic);
+        let mut iface = InterfaceBuilder::new()
+            .address(US, 24)
+            .clock(clock.clone())
+            .trust_checksum_offload(true)
+            .build_with_device(nic)
+            .unwrap();
+        let listener = iface.bind(LISTEN_PORT).unwrap();
+
+        let mut harness = Harness {
+            clock,
+            stream: None,
+            peer,
+            progress,
+            injected: 0,
+            listener,
+            _iface: iface,
+        };
+        harness.inject(&harness.segment(PEER_ISN, None, SYN, 65535, &[]));
+        harness.inject(&harness.segment(PEER_ISN.wrapping_add(1), Some(1), 0, 65535, &[]));
+        harness.settle();
+        harness.stream = Some(harness.listener.accept().unwrap());
+        harness
+    }
+
+    /// An IPv4 frame carrying a TCP segment on the established connection.
+    pub fn segment(
+        &self,
+        seq: u32,
+        ack: Option<u32>,
+        flags: u8,
+        window: u16,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let mut builder = etherparse::PacketBuilder::ipv4(PEER.octets(), US.octets(), 64).tcp(
+            PEER_PORT,
+            LISTEN_PORT,
+            seq,
+            window,
+        );
+        if let Some(ack) = ack {
+            builder = builder.ack(ack);
+        }
+        if flags & FIN != 0 {
+            builder = builder.fin();
+        }
+        if flags & SYN != 0 {
+            builder = builder.syn();
+        }
+        if flags & RST != 0 {
+            builder = builder.rst();
+        }
+        if flags & PSH != 0 {
+            builder = builder.psh();
+        }
+        let mut frame = Vec::with_capacity(builder.size(payload.len()));
+        builder.write(&mut frame, payload).unwrap();
+        frame
+    }
+
+    /// Hands a frame to the stack.
+    pub fn inject(&mut self, frame: &[u8]) {
+        self.peer.send(frame).unwrap();
+        self.injected += 1;
+    }
+
+    /// Waits until the stack has dealt with everything it was given, and throws away its replies.
+    pub fn settle(&mut self) {
+        let deadline = Instant::now() + STALL;
+        while self.progress.done.load(Ordering::SeqCst) < self.injected {
+            assert!(Instant::now() < deadline, "the packet loop has stopped");
+            self.drain(Duration::from_millis(1));
+        }
+        self.drain(Duration::ZERO);
+    }
+
+    /// Waits for the packet loop to go all the way around, so that its timers see the clock as it
+    /// is now.
+    pub fn tick(&mut self) {
+        let deadline = Instant::now() + STALL;
+        let polls = self.progress.polls.load(Ordering::SeqCst);
+        while self.progress.polls.load(Ordering::SeqCst) < polls + 2 {
+            assert!(Instant::now() < deadline, "the packet loop has stopped");
+            self.drain(Duration::from_millis(1));
+        }
+        self.drain(Duration::ZERO);
+    }
+
+    fn drain(&mut self, timeout: Duration) {
+        let mut buf = [0u8; 65535];
+        while self.peer.poll(timeout).unwrap() {
+            self.peer.recv(&mut buf).unwrap();
+        }
+    }
+}
+
+impl Default for Harness {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -9,9 +9,6 @@ sudo setcap cap_net_admin=eip ./target/release/trust
 ./target/release/trust &
 pid=$!
 
-sudo ip addr add 192.168.0.1/24 dev tun0
-sudo ip link set up dev tun0
-
 # lowercase 'kill', and catch Ctrl+C (INT) too
 trap "kill $pid" INT TERM
 
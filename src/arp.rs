@@ -0,0 +1,165 @@
+//! ARP (RFC 826): finding out the Ethernet addresses of IPv4 neighbours, and telling them ours.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+/// An Ethernet address.
+pub(crate) type MacAddr = [u8; 6];
+
+pub(crate) const BROADCAST: MacAddr = [0xff; 6];
+
+pub(crate) const REQUEST: u16 = 1;
+pub(crate) const REPLY: u16 = 2;
+
+/// The length of an ARP packet for IPv4 over Ethernet.
+const PACKET_LEN: usize = 28;
+
+/// How long we believe what a neighbour told us before asking again.
+const REACHABLE: Duration = Duration::from_secs(60);
+/// How long we wait for a reply before asking again, and how often we ask before giving up
+/// (RFC 1122 S2.3.2.1).
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_REQUESTS: u32 = 3;
+/// How many packets we hold on to for a neighbour we're still asking about. RFC 1122 S2.3.2.2 asks
+/// for at least one.
+const MAX_QUEUED: usize = 16;
+
+/// An ARP packet, for IPv4 over Ethernet.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Packet {
+    pub(crate) op: u16,
+    pub(crate) sender_mac: MacAddr,
+    pub(crate) sender_ip: Ipv4Addr,
+    pub(crate) target_mac: MacAddr,
+    pub(crate) target_ip: Ipv4Addr,
+}
+
+impl Packet {
+    /// Parses `buf`, or returns `None` if it isn't about IPv4 over Ethernet.
+    pub(crate) fn parse(buf: &[u8]) -> Option<Self> {
+        let buf = buf.get(..PACKET_LEN)?;
+        // hardware type Ethernet, protocol type IPv4, and the lengths of their addresses
+        if buf[..6] != [0, 1, 0x08, 0x00, 6, 4] {
+            return None;
+        }
+        Some(Packet {
+            op: u16::from_be_bytes([buf[6], buf[7]]),
+            sender_mac: buf[8..14].try_into().unwrap(),
+            sender_ip: <[u8; 4]>::try_from(&buf[14..18]).unwrap().into(),
+            target_mac: buf[18..24].try_into().unwrap(),
+            target_ip: <[u8; 4]>::try_from(&buf[24..28]).unwrap().into(),
+        })
+    }
+
+    pub(crate) fn to_bytes(self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(PACKET_LEN);
+        buf.extend_from_slice(&[0, 1, 0x08, 0x00, 6, 4]);
+        buf.extend_from_slice(&self.op.to_be_bytes());
+        buf.extend_from_slice(&self.sender_mac);
+        buf.extend_from_slice(&self.sender_ip.octets());
+        buf.extend_from_slice(&self.target_mac);
+        buf.extend_from_slice(&self.target_ip.octets());
+        buf
+    }
+}
+
+enum Entry {
+    Resolved {
+        mac: MacAddr,
+        expires: Instant,
+    },
+    /// we've asked, and are holding on to what is to be sent once we hear back
+    Pending {
+        queue: VecDeque<Vec<u8>>,
+        requests: u32,
+        retry: Instant,
+    },
+}
+
+/// What we know about our neighbours' Ethernet addresses.
+#[derive(Default)]
+pub(crate) struct Cache {
+    entries: HashMap<Ipv4Addr, Entry>,
+}
+
+impl Cache {
+    /// The Ethernet address of `ip`, if we know it.
+    pub(crate) fn lookup(&self, ip: Ipv4Addr, now: Instant) -> Option<MacAddr> {
+        match self.entries.get(&ip) {
+            Some(&Entry::Resolved { mac, expires }) if now < expires => Some(mac),
+            _ => None,
+        }
+    }
+
+    /// Holds on to `packet` until we know the Ethernet address of `ip`, and returns whether that
+    /// means we need to start asking for it.
+    pub(crate) fn queue(&mut self, ip: Ipv4Addr, packet: &[u8], now: Instant) -> bool {
+        let ask = !matches!(self.entries.get(&ip), Some(Entry::Pending { .. }));
+        if ask {
+            let pending = Entry::Pending {
+                queue: VecDeque::new(),
+                requests: 1,
+                retry: now + RETRY_INTERVAL,
+            };
+            self.entries.insert(ip, pending);
+        }
+        let Some(Entry::Pending { queue, .. }) = self.entries.get_mut(&ip) else {
+            unreachable!();
+        };
+        if queue.len() == MAX_QUEUED {
+            // the oldest is the least likely to still be worth sending
+            queue.pop_front();
+        }
+        queue.push_back(packet.to_vec());
+        ask
+    }
+
+    /// Notes that `ip` is at `mac`, as a neighbour told us, and returns what was waiting to be
+    /// sent to it. Unless `create`, this only updates what we already knew or were asking about,
+    /// so that we don't fill up on every neighbour that asks anyone anything (RFC 826).
+    pub(crate) fn update(
+        &mut self,
+        ip: Ipv4Addr,
+        mac: MacAddr,
+        create: bool,
+        now: Instant,
+    ) -> VecDeque<Vec<u8>> {
+        if !create && !self.entries.contains_key(&ip) {
+            return VecDeque::new();
+        }
+        let resolved = Entry::Resolved {
+            mac,
+            expires: now + REACHABLE,
+        };
+        match self.entries.insert(ip, resolved) {
+            Some(Entry::Pending { queue, .. }) => queue,
+            _ => VecDeque::new(),
+        }
+    }
+
+    /// Forgets what we've believed for too long, gives up on neighbours that didn't answer, and
+    /// returns those we should ask again.
+    pub(crate) fn expire(&mut self, now: Instant) -> Vec<Ipv4Addr> {
+        let mut again = Vec::new();
+        self.entries.retain(|&ip, entry| match entry {
+            Entry::Resolved { expires, .. } => now < *expires,
+            Entry::Pending { retry, .. } if now < *retry => true,
+            Entry::Pending {
+                queue, requests, ..
+            } if *requests >= MAX_REQUESTS => {
+                eprintln!("no arp reply from {}, dropping {} packets", ip, queue.len());
+                false
+            }
+            Entry::Pending {
+                requests, retry, ..
+            } => {
+                *requests += 1;
+                *retry = now + RETRY_INTERVAL;
+                again.push(ip);
+                true
+            }
+        });
+        again
+    }
+}
@@ -0,0 +1,90 @@
+//! The Internet checksum (RFC 1071): verifying it on incoming packets, and computing it for the
+//! ICMP messages and UDP datagrams we send.
+//!
+//! The checksums of outgoing TCP segments are left to etherparse.
+
+use std::net::IpAddr;
+
+/// Adds up `data` as big-endian 16-bit words, padding an odd byte at the end with zero.
+fn sum(data: &[u8], mut acc: u32) -> u32 {
+    let mut words = data.chunks_exact(2);
+    for word in &mut words {
+        acc += u32::from(u16::from_be_bytes([word[0], word[1]]));
+    }
+    if let [last] = words.remainder() {
+        acc += u32::from(u16::from_be_bytes([*last, 0]));
+    }
+    acc
+}
+
+/// Folds `acc` into 16 bits, with end-around carry.
+fn fold(mut acc: u32) -> u16 {
+    while acc > 0xffff {
+        acc = (acc & 0xffff) + (acc >> 16);
+    }
+    acc as u16
+}
+
+/// Whether `acc`, including the checksum being verified, is all ones once folded to 16 bits.
+fn verify(acc: u32) -> bool {
+    fold(acc) == 0xffff
+}
+
+/// Whether the checksum of an IPv4 header is correct.
+pub(crate) fn ipv4_header(header: &[u8]) -> bool {
+    verify(sum(header, 0))
+}
+
+/// The octets of an address, as they appear in a pseudo-header.
+fn octets(addr: IpAddr) -> Vec<u8> {
+    match addr {
+        IpAddr::V4(addr) => addr.octets().to_vec(),
+        IpAddr::V6(addr) => addr.octets().to_vec(),
+    }
+}
+
+/// The sum of the pseudo-header (RFC 793 S3.1 for IPv4, RFC 8200 S8.1 for IPv6) for `len` bytes of
+/// `protocol`. The IPv6 one has a 32-bit length, but that adds up to the same once folded.
+fn pseudo_header(src: IpAddr, dst: IpAddr, protocol: u8, len: usize) -> u32 {
+    let mut acc = sum(&octets(src), 0);
+    acc = sum(&octets(dst), acc);
+    acc += u32::from(protocol);
+    acc += len as u32;
+    acc
+}
+
+/// Whether the checksum of a segment, carried from `src` to `dst`, is correct.
+pub(crate) fn payload(src: IpAddr, dst: IpAddr, protocol: u8, segment: &[u8]) -> bool {
+    verify(sum(
+        segment,
+        pseudo_header(src, dst, protocol, segment.len()),
+    ))
+}
+
+/// Whether the checksum of an ICMP message is correct. Only ICMPv6 covers a pseudo-header.
+pub(crate) fn icmp(src: IpAddr, dst: IpAddr, message: &[u8]) -> bool {
+    match src {
+        IpAddr::V4(_) => verify(sum(message, 0)),
+        IpAddr::V6(_) => payload(src, dst, 58, message),
+    }
+}
+
+/// Fills in the checksum of an ICMP message we are about to send from `src` to `dst`.
+pub(crate) fn fill_icmp(src: IpAddr, dst: IpAddr, message: &mut [u8]) {
+    message[2..4].copy_from_slice(&[0, 0]);
+    let acc = match src {
+        IpAddr::V4(_) => sum(message, 0),
+        IpAddr::V6(_) => sum(message, pseudo_header(src, dst, 58, message.len())),
+    };
+    message[2..4].copy_from_slice(&(!fold(acc)).to_be_bytes());
+}
+
+/// Fills in the checksum of a UDP datagram we are about to send from `src` to `dst`.
+pub(crate) fn fill_udp(src: IpAddr, dst: IpAddr, datagram: &mut [u8]) {
+    datagram[6..8].copy_from_slice(&[0, 0]);
+    let checksum = !fold(sum(datagram, pseudo_header(src, dst, 17, datagram.len())));
+    // all zeros would mean there is no checksum, and all ones is the same in ones' complement
+    // (RFC 768)
+    let checksum = if checksum == 0 { 0xffff } else { checksum };
+    datagram[6..8].copy_from_slice(&checksum.to_be_bytes());
+}
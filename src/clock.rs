@@ -0,0 +1,65 @@
+//! Where the stack gets the time from.
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A source of time for the stack's timers.
+///
+/// Retransmission, TIME-WAIT and shutdown deadlines are all measured against an
+/// [`Interface`](crate::Interface)'s clock, so a [`VirtualClock`] lets tests skip ahead through
+/// them instead of sleeping.
+pub trait Clock: fmt::Debug + Send + Sync + 'static {
+    fn now(&self) -> Instant;
+}
+
+impl<C: Clock + ?Sized> Clock for Arc<C> {
+    fn now(&self) -> Instant {
+        (**self).now()
+    }
+}
+
+/// The real time, as told by [`Instant::now`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that stands still until it is told to move.
+///
+/// Clones share the same time, so one can be given to an interface and another kept to
+/// [`advance`](VirtualClock::advance) it.
+/// [`Interface::settle`](crate::Interface::settle) then waits for the interface to catch up.
+#[derive(Clone, Debug)]
+pub struct VirtualClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+impl VirtualClock {
+    pub fn new() -> Self {
+        VirtualClock {
+            now: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Moves the clock forward by `by`.
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+impl Default for VirtualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
@@ -0,0 +1,123 @@
+//! The devices an [`Interface`](crate::Interface) can send and receive packets through.
+
+use std::collections::VecDeque;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+/// Something that carries IP packets to and from the stack.
+///
+/// The packet loop waits for the device with [`Device::poll`], so that it also wakes up in time
+/// to fire timers, and then takes one packet at a time with [`Device::recv`].
+pub trait Device: Send + 'static {
+    /// Receives a single packet into `buf`, returning its length.
+    ///
+    /// Returns an error of kind [`io::ErrorKind::WouldBlock`] if no packet is available.
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// Sends a single packet.
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize>;
+
+    /// Waits up to `timeout` for a packet to become available, and returns whether one has.
+    fn poll(&mut self, timeout: Duration) -> io::Result<bool>;
+
+    /// Whether the checksums of the packet last received have already been verified, by hardware
+    /// or by whatever is on the other side of the device.
+    ///
+    /// This is only taken into account if the interface was built with
+    /// [`InterfaceBuilder::trust_checksum_offload`](crate::InterfaceBuilder::trust_checksum_offload).
+    fn checksum_verified(&self) -> bool {
+        false
+    }
+
+    /// Returns a way to make a [`Device::poll`] in progress on another thread return early, or
+    /// the next one if none is, for when the packet loop has something to send.
+    ///
+    /// Without one, what is sent by sockets waits for the packet loop's next tick, which comes
+    /// every few milliseconds.
+    fn waker(&self) -> Option<Waker> {
+        None
+    }
+}
+
+/// Wakes up a [`Device::poll`], as returned by [`Device::waker`].
+pub type Waker = Arc<dyn Fn() + Send + Sync>;
+
+#[derive(Default)]
+struct Queue {
+    packets: Mutex<VecDeque<Vec<u8>>>,
+    ready: Condvar,
+    /// whether a poll should return without waiting for a packet
+    woken: AtomicBool,
+}
+
+/// One end of an in-memory point-to-point link.
+///
+/// Whatever is sent on one end of a pair created by [`MemoryDevice::pair`] is received on the
+/// other, which makes it possible to run the stack without a TUN device, for example in tests
+/// where one end is given to an [`Interface`](crate::Interface) and the other is used to inject
+/// and inspect packets.
+pub struct MemoryDevice {
+    rx: Arc<Queue>,
+    tx: Arc<Queue>,
+}
+
+impl MemoryDevice {
+    /// Creates two connected devices.
+    pub fn pair() -> (MemoryDevice, MemoryDevice) {
+        let a: Arc<Queue> = Arc::default();
+        let b: Arc<Queue> = Arc::default();
+        (
+            MemoryDevice {
+                rx: a.clone(),
+                tx: b.clone(),
+            },
+            MemoryDevice { rx: b, tx: a },
+        )
+    }
+}
+
+impl Device for MemoryDevice {
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let packet = self
+            .rx
+            .packets
+            .lock()
+            .unwrap()
+            .pop_front()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::WouldBlock, "no packet available"))?;
+        let n = std::cmp::min(buf.len(), packet.len());
+        buf[..n].copy_from_slice(&packet[..n]);
+        Ok(n)
+    }
+
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.tx.packets.lock().unwrap().push_back(buf.to_vec());
+        self.tx.ready.notify_all();
+        Ok(buf.len())
+    }
+
+    fn poll(&mut self, timeout: Duration) -> io::Result<bool> {
+        let packets = self.rx.packets.lock().unwrap();
+        let (packets, _) = self
+            .rx
+            .ready
+            .wait_timeout_while(packets, timeout, |packets| {
+                packets.is_empty() && !self.rx.woken.load(Ordering::SeqCst)
+            })
+            .unwrap();
+        self.rx.woken.store(false, Ordering::SeqCst);
+        Ok(!packets.is_empty())
+    }
+
+    fn waker(&self) -> Option<Waker> {
+        let rx = self.rx.clone();
+        Some(Arc::new(move || {
+            // with the lock held, so that a poll can't miss it between looking and waiting
+            let _packets = rx.packets.lock().unwrap();
+            rx.woken.store(true, Ordering::SeqCst);
+            rx.ready.notify_all();
+        }))
+    }
+}
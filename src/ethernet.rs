@@ -0,0 +1,204 @@
+//! Ethernet II framing (RFC 894) for TAP devices, with ARP to find out where frames go.
+
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::arp::{self, MacAddr};
+use crate::{Clock, Device, Waker};
+
+const HEADER_LEN: usize = 14;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+
+/// Where an interface on an Ethernet segment is, and how to get off it.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Settings {
+    pub(crate) mac: MacAddr,
+    pub(crate) addr: Ipv4Addr,
+    pub(crate) prefix_len: u8,
+    /// where packets for anywhere off the segment go, if anywhere
+    pub(crate) gateway: Option<Ipv4Addr>,
+}
+
+/// A device that carries Ethernet frames, made to look like one that carries IP packets.
+///
+/// ARP is handled along the way: requests for our address are answered when they are received,
+/// and packets for neighbours whose address we don't know yet wait while we ask. Retries happen
+/// in [`Device::poll`], which the packet loop calls often enough for that to be timely.
+pub(crate) struct Link<D> {
+    inner: D,
+    settings: Settings,
+    clock: Arc<dyn Clock>,
+    arp: arp::Cache,
+    frame: Vec<u8>,
+}
+
+impl<D: Device> Link<D> {
+    pub(crate) fn new(inner: D, settings: Settings, mtu: usize, clock: Arc<dyn Clock>) -> Self {
+        Link {
+            inner,
+            settings,
+            clock,
+            arp: arp::Cache::default(),
+            frame: vec![0; HEADER_LEN + mtu],
+        }
+    }
+
+    fn send_frame(&mut self, dst: MacAddr, ethertype: u16, payload: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+        frame.extend_from_slice(&dst);
+        frame.extend_from_slice(&self.settings.mac);
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(payload);
+        self.inner.send(&frame)?;
+        Ok(())
+    }
+
+    fn send_arp(
+        &mut self,
+        op: u16,
+        dst: MacAddr,
+        target_mac: MacAddr,
+        target_ip: Ipv4Addr,
+    ) -> io::Result<()> {
+        let packet = arp::Packet {
+            op,
+            sender_mac: self.settings.mac,
+            sender_ip: self.settings.addr,
+            target_mac,
+            target_ip,
+        };
+        self.send_frame(dst, ETHERTYPE_ARP, &packet.to_bytes())
+    }
+
+    fn on_arp(&mut self, packet: arp::Packet) -> io::Result<()> {
+        let for_us = packet.target_ip == self.settings.addr;
+        let now = self.clock.now();
+        for waiting in self
+            .arp
+            .update(packet.sender_ip, packet.sender_mac, for_us, now)
+        {
+            self.send_frame(packet.sender_mac, ETHERTYPE_IPV4, &waiting)?;
+        }
+        if for_us && packet.op == arp::REQUEST {
+            self.send_arp(
+                arp::REPLY,
+                packet.sender_mac,
+                packet.sender_mac,
+                packet.sender_ip,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Whether `dst` is on the same segment as us.
+    fn is_on_link(&self, dst: Ipv4Addr) -> bool {
+        let mask = u32::MAX
+            .checked_shl(32 - u32::from(self.settings.prefix_len))
+            .unwrap_or(0);
+        u32::from(dst) & mask == u32::from(self.settings.addr) & mask
+    }
+
+    /// The Ethernet address that everyone a packet for `dst` is meant for listens to, if it is
+    /// meant for more than one host.
+    fn group(&self, dst: Ipv4Addr) -> Option<MacAddr> {
+        let host_bits = u32::MAX
+            .checked_shr(u32::from(self.settings.prefix_len))
+            .unwrap_or(0);
+        let directed =
+            host_bits > 1 && u32::from(dst) & host_bits == host_bits && self.is_on_link(dst);
+        if dst.is_broadcast() || directed {
+            return Some(arp::BROADCAST);
+        }
+        if dst.is_multicast() {
+            // the low 23 bits of the group, in a block set aside for them (RFC 1112 S6.4)
+            let [_, b, c, d] = dst.octets();
+            return Some([0x01, 0x00, 0x5e, b & 0x7f, c, d]);
+        }
+        None
+    }
+}
+
+impl<D: Device> Device for Link<D> {
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // one frame at a time, since the next one may be a while, and the packet loop has timers
+        // to fire in the meantime
+        let nothing = || io::Error::new(io::ErrorKind::WouldBlock, "no packet available");
+        let n = self.inner.recv(&mut self.frame)?;
+        let frame = &self.frame[..n];
+        if n < HEADER_LEN {
+            return Err(nothing());
+        }
+        let dst: MacAddr = frame[..6].try_into().unwrap();
+        // the group bit is set for broadcasts and multicasts; we don't keep track of which groups
+        // we belong to, so like a card in all-multicast mode, we take them all
+        if dst != self.settings.mac && dst[0] & 1 == 0 {
+            // someone else's, which a bridge may send us anyway
+            return Err(nothing());
+        }
+        let payload = &frame[HEADER_LEN..];
+        match u16::from_be_bytes([frame[12], frame[13]]) {
+            ETHERTYPE_IPV4 => {
+                let len = std::cmp::min(buf.len(), payload.len());
+                buf[..len].copy_from_slice(&payload[..len]);
+                Ok(len)
+            }
+            ETHERTYPE_ARP => {
+                if let Some(packet) = arp::Packet::parse(payload) {
+                    self.on_arp(packet)?;
+                }
+                Err(nothing())
+            }
+            // TODO: IPv6, which needs neighbor discovery (RFC 4861) rather than ARP
+            _ => Err(nothing()),
+        }
+    }
+
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.first().map(|b| b >> 4) != Some(4) || buf.len() < 20 {
+            eprintln!("can't send anything but IPv4 over ethernet");
+            return Ok(buf.len());
+        }
+        let dst = Ipv4Addr::new(buf[16], buf[17], buf[18], buf[19]);
+        if let Some(mac) = self.group(dst) {
+            self.send_frame(mac, ETHERTYPE_IPV4, buf)?;
+            return Ok(buf.len());
+        }
+        let next_hop = if self.is_on_link(dst) {
+            dst
+        } else if let Some(gateway) = self.settings.gateway {
+            gateway
+        } else {
+            eprintln!("no gateway to send to {} through", dst);
+            return Ok(buf.len());
+        };
+
+        let now = self.clock.now();
+        match self.arp.lookup(next_hop, now) {
+            Some(mac) => self.send_frame(mac, ETHERTYPE_IPV4, buf)?,
+            None => {
+                if self.arp.queue(next_hop, buf, now) {
+                    self.send_arp(arp::REQUEST, arp::BROADCAST, [0; 6], next_hop)?;
+                }
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn poll(&mut self, timeout: Duration) -> io::Result<bool> {
+        for ip in self.arp.expire(self.clock.now()) {
+            self.send_arp(arp::REQUEST, arp::BROADCAST, [0; 6], ip)?;
+        }
+        self.inner.poll(timeout)
+    }
+
+    fn checksum_verified(&self) -> bool {
+        self.inner.checksum_verified()
+    }
+
+    fn waker(&self) -> Option<Waker> {
+        self.inner.waker()
+    }
+}
@@ -0,0 +1,220 @@
+//! Reassembly of fragmented IPv4 datagrams (RFC 791 S3.2, RFC 815).
+//!
+//! Fragments are an easy way to make a host hold on to memory, or to sneak something past it, so
+//! we're strict about them: anything that overlaps differently from what we already have throws
+//! away the whole datagram (as RFC 5722 does for IPv6), nothing may add up to more than the
+//! largest datagram there is, and every source gets a limited share of memory.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use crate::ip::{Fragment, Packet};
+
+/// How long we wait for the rest of a datagram once its first fragment arrived. RFC 791 suggests
+/// 15 seconds; this is what Linux uses.
+const TIMEOUT: Duration = Duration::from_secs(30);
+/// How many bytes of incomplete datagrams a single source may have us hold on to.
+const MAX_PER_SOURCE: usize = 256 * 1024;
+/// How many incomplete datagrams a single source may have, however small.
+const MAX_DATAGRAMS_PER_SOURCE: usize = 64;
+/// How many bytes of incomplete datagrams we hold on to across all sources.
+const MAX_TOTAL: usize = 4 * 1024 * 1024;
+/// The largest a datagram can be, header and all.
+const MAX_DATAGRAM: usize = 65535;
+
+/// Fragments belong to the same datagram if all of these match (RFC 791 S3.2).
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+struct Key {
+    src: IpAddr,
+    dst: IpAddr,
+    protocol: u8,
+    id: u16,
+}
+
+/// A datagram we have some of the fragments of.
+struct Datagram {
+    started: Instant,
+    /// the header of the first fragment, once we have it
+    header: Option<Vec<u8>>,
+    data: Vec<u8>,
+    /// the parts of `data` we have, in order, and with adjacent parts merged
+    have: Vec<(usize, usize)>,
+    /// where the datagram ends, once we have its last fragment
+    end: Option<usize>,
+}
+
+/// A datagram put back together.
+pub(crate) struct Reassembled {
+    /// the header of the first fragment, including its options
+    pub(crate) header: Vec<u8>,
+    pub(crate) payload: Vec<u8>,
+}
+
+/// What became of a fragment.
+pub(crate) enum Outcome {
+    /// It completed a datagram.
+    Complete(Reassembled),
+    /// It was kept (or was a duplicate of something kept), and we wait for more.
+    Incomplete,
+    /// It was dropped, and with it whatever we had of its datagram.
+    Dropped,
+}
+
+#[derive(Default)]
+pub(crate) struct Reassembler {
+    datagrams: HashMap<Key, Datagram>,
+    /// bytes held for each source
+    used_by: HashMap<IpAddr, usize>,
+    /// bytes held in total
+    used: usize,
+}
+
+impl Reassembler {
+    /// Adds `fragment`, which was carried by `packet`, to the datagram it belongs to.
+    pub(crate) fn insert(
+        &mut self,
+        packet: &Packet<'_>,
+        fragment: Fragment,
+        now: Instant,
+    ) -> Outcome {
+        let key = Key {
+            src: packet.src,
+            dst: packet.dst,
+            protocol: packet.protocol,
+            id: fragment.id,
+        };
+        let start = fragment.offset;
+        let end = start + packet.payload.len();
+
+        if fragment.more && (packet.payload.is_empty() || !packet.payload.len().is_multiple_of(8)) {
+            // only the last fragment may end anywhere but an 8-byte boundary
+            self.remove(key);
+            return Outcome::Dropped;
+        }
+        if packet.header.len() + end > MAX_DATAGRAM {
+            // the ping of death
+            self.remove(key);
+            return Outcome::Dropped;
+        }
+        if packet.protocol == 6 && start == 8 {
+            // this could only be rewriting the flags of a TCP header (RFC 1858 S3.1)
+            self.remove(key);
+            return Outcome::Dropped;
+        }
+
+        if self
+            .datagrams
+            .get(&key)
+            .is_some_and(|d| now >= d.started + TIMEOUT)
+        {
+            // an ID that has come around again; whatever we had is stale
+            self.remove(key);
+        }
+        if !self.datagrams.contains_key(&key) {
+            let datagrams = self.datagrams.keys().filter(|k| k.src == key.src).count();
+            if datagrams >= MAX_DATAGRAMS_PER_SOURCE {
+                return Outcome::Dropped;
+            }
+            self.datagrams.insert(
+                key,
+                Datagram {
+                    started: now,
+                    header: None,
+                    data: Vec::new(),
+                    have: Vec::new(),
+                    end: None,
+                },
+            );
+        }
+        let d = self.datagrams.get_mut(&key).unwrap();
+
+        // the last fragment says where the datagram ends, and nothing may disagree
+        let consistent = match d.end {
+            Some(known) => end <= known && (fragment.more || end == known),
+            None => fragment.more || d.have.last().is_none_or(|&(_, e)| e <= end),
+        };
+        if !consistent {
+            self.remove(key);
+            return Outcome::Dropped;
+        }
+
+        // a duplicate of something we have is harmless, but any other overlap is an attack
+        for &(s, e) in &d.have {
+            if s < end && start < e {
+                if s <= start && end <= e && d.data[start..end] == *packet.payload {
+                    return Outcome::Incomplete;
+                }
+                self.remove(key);
+                return Outcome::Dropped;
+            }
+        }
+
+        let extra = end.saturating_sub(d.data.len());
+        let used_by = self.used_by.get(&key.src).copied().unwrap_or(0);
+        if used_by + extra > MAX_PER_SOURCE || self.used + extra > MAX_TOTAL {
+            self.remove(key);
+            return Outcome::Dropped;
+        }
+        *self.used_by.entry(key.src).or_default() += extra;
+        self.used += extra;
+
+        let d = self.datagrams.get_mut(&key).unwrap();
+        if d.data.len() < end {
+            d.data.resize(end, 0);
+        }
+        d.data[start..end].copy_from_slice(packet.payload);
+        if !fragment.more {
+            d.end = Some(end);
+        }
+        if start == 0 {
+            d.header = Some(packet.header.to_vec());
+        }
+        let at = d.have.partition_point(|&(s, _)| s < start);
+        d.have.insert(at, (start, end));
+        d.have.dedup_by(|next, prev| {
+            if prev.1 == next.0 {
+                prev.1 = next.1;
+                true
+            } else {
+                false
+            }
+        });
+
+        if d.header.is_none() || d.end.is_none() || d.have != [(0, d.data.len())] {
+            return Outcome::Incomplete;
+        }
+        let d = self.remove(key).unwrap();
+        Outcome::Complete(Reassembled {
+            header: d.header.unwrap(),
+            payload: d.data,
+        })
+    }
+
+    /// Gives up on datagrams that have taken too long to arrive, and returns how many there were.
+    pub(crate) fn expire(&mut self, now: Instant) -> usize {
+        let expired: Vec<Key> = self
+            .datagrams
+            .iter()
+            .filter(|(_, d)| now >= d.started + TIMEOUT)
+            .map(|(key, _)| *key)
+            .collect();
+        for &key in &expired {
+            self.remove(key);
+        }
+        expired.len()
+    }
+
+    /// Stops keeping track of a datagram, and gives back the memory it was charged for.
+    fn remove(&mut self, key: Key) -> Option<Datagram> {
+        let d = self.datagrams.remove(&key)?;
+        self.used -= d.data.len();
+        if let Some(used_by) = self.used_by.get_mut(&key.src) {
+            *used_by -= d.data.len();
+            if *used_by == 0 {
+                self.used_by.remove(&key.src);
+            }
+        }
+        Some(d)
+    }
+}
@@ -0,0 +1,325 @@
+//! ICMP (RFC 792) and ICMPv6 (RFC 4443): answering pings, telling peers about protocols and
+//! ports we don't serve, and passing on what the network tells us about our traffic.
+
+use std::io;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use crate::{Device, Quad, checksum, ip, pmtu, udp};
+
+const ICMPV4: u8 = 1;
+const ICMPV6: u8 = 58;
+
+/// How much of an offending packet an ICMPv6 error quotes: as much as fits in the smallest MTU an
+/// IPv6 link may have (RFC 4443 S2.4(c)), after our own IPv6 and ICMPv6 headers.
+const V6_QUOTE: usize = 1280 - 40 - 8;
+
+/// How many errors we send in a burst, and how often we may send another after that.
+const ERROR_BURST: u32 = 10;
+const ERROR_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The ICMP protocol number in packets between `addr` and its peers.
+pub(crate) fn protocol(addr: IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => ICMPV4,
+        IpAddr::V6(_) => ICMPV6,
+    }
+}
+
+/// An error reported about a segment we sent (RFC 1122 S4.2.3.9).
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Error {
+    /// what the application is told
+    pub(crate) kind: io::ErrorKind,
+    /// whether the connection should be aborted; soft errors may well be transient
+    pub(crate) hard: bool,
+}
+
+impl Error {
+    fn hard(kind: io::ErrorKind) -> Option<Self> {
+        Some(Error { kind, hard: true })
+    }
+
+    fn soft(kind: io::ErrorKind) -> Option<Self> {
+        Some(Error { kind, hard: false })
+    }
+}
+
+/// An incoming ICMP message, as far as we care.
+pub(crate) enum Message {
+    EchoRequest,
+    /// An error about a TCP segment we sent on `quad`, whose sequence number was `seq`.
+    TcpError {
+        quad: Quad,
+        seq: u32,
+        error: Error,
+    },
+    /// A TCP segment we sent on `quad` was too big for the path, which takes at most `mtu`.
+    PacketTooBig {
+        quad: Quad,
+        seq: u32,
+        mtu: usize,
+    },
+    /// A UDP datagram we sent, from and to the ports in `quad`, was too big for the path.
+    UdpTooBig {
+        quad: Quad,
+        mtu: usize,
+    },
+    /// An error about a UDP datagram we sent, from and to the ports in `quad`.
+    UdpError {
+        quad: Quad,
+        error: Error,
+    },
+    Other,
+}
+
+impl Message {
+    /// Makes sense of the ICMP message `packet` carries.
+    pub(crate) fn parse(packet: &ip::Packet<'_>) -> Self {
+        let message = packet.payload;
+        if message.len() < 8 {
+            return Message::Other;
+        }
+        let (kind, code) = (message[0], message[1]);
+        if let Some(mtu) = next_hop_mtu(packet.src, message) {
+            return match quoted(packet.src, &message[8..]) {
+                Some((6, quad, rest)) => Message::PacketTooBig {
+                    quad,
+                    seq: u32::from_be_bytes(rest),
+                    mtu,
+                },
+                Some((udp::PROTOCOL, quad, _)) => Message::UdpTooBig { quad, mtu },
+                _ => Message::Other,
+            };
+        }
+        let error = match (packet.src, kind) {
+            (IpAddr::V4(_), 8) | (IpAddr::V6(_), 128) => return Message::EchoRequest,
+            (IpAddr::V4(_), 3) => match code {
+                // protocol and port unreachable
+                2 | 3 => Error::hard(io::ErrorKind::ConnectionRefused),
+                0 | 6 | 9 | 11 => Error::soft(io::ErrorKind::NetworkUnreachable),
+                _ => Error::soft(io::ErrorKind::HostUnreachable),
+            },
+            (IpAddr::V6(_), 1) => match code {
+                // port unreachable
+                4 => Error::hard(io::ErrorKind::ConnectionRefused),
+                0 => Error::soft(io::ErrorKind::NetworkUnreachable),
+                _ => Error::soft(io::ErrorKind::HostUnreachable),
+            },
+            // time exceeded
+            (IpAddr::V4(_), 11) | (IpAddr::V6(_), 3) => Error::soft(io::ErrorKind::HostUnreachable),
+            // parameter problem; for IPv6, code 1 is an unrecognized next header
+            (IpAddr::V6(_), 4) if code == 1 => Error::hard(io::ErrorKind::ConnectionRefused),
+            (IpAddr::V4(_), 12) | (IpAddr::V6(_), 4) => Error::soft(io::ErrorKind::InvalidData),
+            // source quench is deprecated (RFC 6633), and everything else is none of our business
+            _ => None,
+        };
+
+        let Some(error) = error else {
+            return Message::Other;
+        };
+        match quoted(packet.src, &message[8..]) {
+            Some((6, quad, rest)) => Message::TcpError {
+                quad,
+                seq: u32::from_be_bytes(rest),
+                error,
+            },
+            Some((udp::PROTOCOL, quad, _)) => Message::UdpError { quad, error },
+            _ => Message::Other,
+        }
+    }
+}
+
+/// The MTU of the path a packet we sent couldn't take, if `message` says that's what went wrong
+/// (RFC 1191 S4, RFC 8201 S4).
+fn next_hop_mtu(family: IpAddr, message: &[u8]) -> Option<usize> {
+    let mtu = match (family, message[0], message[1]) {
+        // destination unreachable: fragmentation needed and DF set
+        (IpAddr::V4(_), 3, 4) => match usize::from(u16::from_be_bytes([message[6], message[7]])) {
+            // a router from before RFC 1191, which doesn't say, so guess from what it got
+            0 => {
+                let quoted = message.get(10..12)?;
+                pmtu::plateau_below(usize::from(u16::from_be_bytes([quoted[0], quoted[1]])))
+            }
+            mtu => mtu,
+        },
+        // packet too big
+        (IpAddr::V6(_), 2, _) => {
+            u32::from_be_bytes([message[4], message[5], message[6], message[7]]) as usize
+        }
+        _ => return None,
+    };
+    Some(std::cmp::max(mtu, pmtu::minimum(family)))
+}
+
+/// What an ICMP error quotes of a packet we sent: its protocol, the addresses and ports it was
+/// sent between, as incoming packets would see them, and the four bytes that follow the ports,
+/// which for TCP are the sequence number.
+fn quoted(family: IpAddr, quoted: &[u8]) -> Option<(u8, Quad, [u8; 4])> {
+    let (src, dst, protocol, header_len): (IpAddr, IpAddr, u8, usize) = match family {
+        IpAddr::V4(_) => {
+            if quoted.len() < 20 || quoted[0] >> 4 != 4 {
+                return None;
+            }
+            let src: [u8; 4] = quoted[12..16].try_into().unwrap();
+            let dst: [u8; 4] = quoted[16..20].try_into().unwrap();
+            let header_len = usize::from(quoted[0] & 0x0f) * 4;
+            (src.into(), dst.into(), quoted[9], header_len.max(20))
+        }
+        IpAddr::V6(_) => {
+            if quoted.len() < 40 || quoted[0] >> 4 != 6 {
+                return None;
+            }
+            let src: [u8; 16] = quoted[8..24].try_into().unwrap();
+            let dst: [u8; 16] = quoted[24..40].try_into().unwrap();
+            (src.into(), dst.into(), quoted[6], 40)
+        }
+    };
+
+    // the first 8 bytes of the transport header are all we get to see for sure
+    let transport = quoted.get(header_len..header_len + 8)?;
+    let quad = Quad {
+        src: (dst, u16::from_be_bytes([transport[2], transport[3]])),
+        dst: (src, u16::from_be_bytes([transport[0], transport[1]])),
+    };
+    Some((protocol, quad, transport[4..8].try_into().unwrap()))
+}
+
+/// Whether a single host can be reached at `addr`. We only ever answer those (RFC 1122 S3.2.2,
+/// RFC 4443 S2.4(e)).
+fn is_unicast(addr: IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(addr) => !(addr.is_unspecified() || addr.is_broadcast() || addr.is_multicast()),
+        IpAddr::V6(addr) => !(addr.is_unspecified() || addr.is_multicast()),
+    }
+}
+
+/// Answers the echo request `packet` carries with a reply of the same data.
+pub(crate) fn send_echo_reply(
+    nic: &mut dyn Device,
+    packet: &ip::Packet<'_>,
+    mtu: usize,
+) -> io::Result<()> {
+    if !is_unicast(packet.src) || !is_unicast(packet.dst) {
+        // nobody needs a thousand hosts answering one ping (RFC 1122 S3.2.2.6)
+        return Ok(());
+    }
+    let mut reply = packet.payload.to_vec();
+    reply[0] = match packet.src {
+        IpAddr::V4(_) => 0,
+        IpAddr::V6(_) => 129,
+    };
+    send(nic, packet.dst, packet.src, reply, mtu)
+}
+
+/// Tells the sender of `packet` that we don't speak the protocol it carries.
+pub(crate) fn send_protocol_unreachable(
+    nic: &mut dyn Device,
+    packet: &ip::Packet<'_>,
+    mtu: usize,
+) -> io::Result<()> {
+    let header = match packet.src {
+        // destination unreachable: protocol unreachable
+        IpAddr::V4(_) => [3, 2, 0, 0, 0, 0, 0, 0],
+        // parameter problem: unrecognized next header, at the next header field
+        IpAddr::V6(_) => [4, 1, 0, 0, 0, 0, 0, 6],
+    };
+    send_error(nic, packet, header, mtu)
+}
+
+/// Tells the sender of the UDP datagram `packet` carries that nobody is bound to its port.
+pub(crate) fn send_port_unreachable(
+    nic: &mut dyn Device,
+    packet: &ip::Packet<'_>,
+    mtu: usize,
+) -> io::Result<()> {
+    let header = match packet.src {
+        // destination unreachable: port unreachable
+        IpAddr::V4(_) => [3, 3, 0, 0, 0, 0, 0, 0],
+        IpAddr::V6(_) => [1, 4, 0, 0, 0, 0, 0, 0],
+    };
+    send_error(nic, packet, header, mtu)
+}
+
+/// Sends an ICMP error about `packet`, starting with `header`, and quoting as much of `packet` as
+/// the version of ICMP asks for.
+fn send_error(
+    nic: &mut dyn Device,
+    packet: &ip::Packet<'_>,
+    header: [u8; 8],
+    mtu: usize,
+) -> io::Result<()> {
+    if !is_unicast(packet.src) || !is_unicast(packet.dst) {
+        return Ok(());
+    }
+    let quote = match packet.src {
+        // the header, and the first 64 bits of what follows (RFC 792)
+        IpAddr::V4(_) => 8,
+        IpAddr::V6(_) => V6_QUOTE - packet.header.len(),
+    };
+    let mut message = header.to_vec();
+    message.extend_from_slice(packet.header);
+    message.extend_from_slice(&packet.payload[..std::cmp::min(quote, packet.payload.len())]);
+    send(nic, packet.dst, packet.src, message, mtu)
+}
+
+fn send(
+    nic: &mut dyn Device,
+    src: IpAddr,
+    dst: IpAddr,
+    mut message: Vec<u8>,
+    mtu: usize,
+) -> io::Result<()> {
+    checksum::fill_icmp(src, dst, &mut message);
+    let protocol = match src {
+        IpAddr::V4(_) => etherparse::IpTrafficClass::Icmp,
+        IpAddr::V6(_) => etherparse::IpTrafficClass::IPv6Icmp,
+    };
+    let mut ip = ip::Header::new(src, dst, protocol);
+    if ip.header_len() + message.len() > mtu {
+        // we don't fragment what we send, so a ping too big for the link goes unanswered
+        return Ok(());
+    }
+    ip.set_payload_len(message.len())
+        .expect("message fits in an ip packet");
+
+    let mut buf = Vec::with_capacity(ip.header_len() + message.len());
+    ip.write(&mut buf)?;
+    buf.extend_from_slice(&message);
+    nic.send(&buf)?;
+    Ok(())
+}
+
+/// Limits how many errors we send, so that nobody can use us to flood someone else
+/// (RFC 4443 S2.4(f), RFC 1812 S4.3.2.8).
+pub(crate) struct RateLimit {
+    tokens: u32,
+    refilled: Option<Instant>,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit {
+            tokens: ERROR_BURST,
+            refilled: None,
+        }
+    }
+}
+
+impl RateLimit {
+    /// Whether we may send an error now, which counts as sending one.
+    pub(crate) fn allow(&mut self, now: Instant) -> bool {
+        let refilled = *self.refilled.get_or_insert(now);
+        let earned = now.saturating_duration_since(refilled).as_nanos() / ERROR_INTERVAL.as_nanos();
+        if earned > 0 {
+            self.tokens =
+                std::cmp::min(u128::from(self.tokens) + earned, u128::from(ERROR_BURST)) as u32;
+            self.refilled = Some(now);
+        }
+        if self.tokens == 0 {
+            return false;
+        }
+        self.tokens -= 1;
+        true
+    }
+}
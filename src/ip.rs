@@ -0,0 +1,149 @@
+//! The parts of IPv4 and IPv6 that the rest of the stack needs, behind one interface.
+
+use std::io;
+use std::net::IpAddr;
+
+/// IPv6 headers have a fixed size, unlike IPv4 headers with their options.
+const IPV6_HEADER_LEN: usize = 40;
+
+/// An incoming IP packet, of either version.
+pub(crate) struct Packet<'a> {
+    pub(crate) src: IpAddr,
+    pub(crate) dst: IpAddr,
+    /// the protocol of the payload; for IPv6, the first next header
+    pub(crate) protocol: u8,
+    /// the IP header, including any IPv4 options
+    pub(crate) header: &'a [u8],
+    /// as much of what follows the header as the header says is there, without any padding
+    pub(crate) payload: &'a [u8],
+    /// set if this is only part of a datagram
+    pub(crate) fragment: Option<Fragment>,
+}
+
+/// Where a fragment of an IPv4 datagram belongs.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Fragment {
+    pub(crate) id: u16,
+    /// in bytes, from the start of the datagram's payload
+    pub(crate) offset: usize,
+    /// whether more of the datagram follows this fragment
+    pub(crate) more: bool,
+}
+
+impl<'a> Packet<'a> {
+    /// Parses the IP header at the start of `buf`, or returns `None` if there isn't a
+    /// well-formed one.
+    ///
+    /// IPv6 extension headers are not followed, so a packet that has them looks like one of a
+    /// protocol we don't speak.
+    pub(crate) fn parse(buf: &'a [u8]) -> Option<Self> {
+        match buf.first()? >> 4 {
+            4 => {
+                let iph = etherparse::Ipv4HeaderSlice::from_slice(buf).ok()?;
+                let header_len = iph.slice().len();
+                let end = usize::from(iph.total_len());
+                if end < header_len || end > buf.len() {
+                    return None;
+                }
+                Some(Packet {
+                    src: iph.source_addr().into(),
+                    dst: iph.destination_addr().into(),
+                    protocol: iph.protocol(),
+                    header: iph.slice(),
+                    payload: &buf[header_len..end],
+                    fragment: (iph.more_fragments() || iph.fragments_offset() != 0).then(|| {
+                        Fragment {
+                            id: iph.identification(),
+                            offset: usize::from(iph.fragments_offset()) * 8,
+                            more: iph.more_fragments(),
+                        }
+                    }),
+                })
+            }
+            6 => {
+                let iph = etherparse::Ipv6HeaderSlice::from_slice(buf).ok()?;
+                let header_len = iph.slice().len();
+                let end = header_len + usize::from(iph.payload_length());
+                if end > buf.len() {
+                    return None;
+                }
+                Some(Packet {
+                    src: iph.source_addr().into(),
+                    dst: iph.destination_addr().into(),
+                    protocol: iph.next_header(),
+                    header: iph.slice(),
+                    payload: &buf[header_len..end],
+                    fragment: None,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The IP header of packets we send, of either version.
+#[derive(Clone, Debug)]
+pub(crate) enum Header {
+    V4(etherparse::Ipv4Header),
+    V6(etherparse::Ipv6Header),
+}
+
+impl Header {
+    /// A header for packets of `protocol` from `src` to `dst`, which must be of the same family.
+    pub(crate) fn new(src: IpAddr, dst: IpAddr, protocol: etherparse::IpTrafficClass) -> Self {
+        match (src, dst) {
+            (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                let mut header =
+                    etherparse::Ipv4Header::new(0, 64, protocol, src.octets(), dst.octets());
+                // we never fragment what we send, and would rather hear from routers that would
+                // have to (RFC 1191 S3)
+                header.dont_fragment = true;
+                Header::V4(header)
+            }
+            (IpAddr::V6(src), IpAddr::V6(dst)) => Header::V6(etherparse::Ipv6Header {
+                traffic_class: 0,
+                flow_label: 0,
+                payload_length: 0,
+                next_header: protocol as u8,
+                hop_limit: 64,
+                source: src.octets(),
+                destination: dst.octets(),
+            }),
+            _ => unreachable!("no route from {} to {}", src, dst),
+        }
+    }
+
+    pub(crate) fn header_len(&self) -> usize {
+        match self {
+            Header::V4(ip) => ip.header_len(),
+            Header::V6(_) => IPV6_HEADER_LEN,
+        }
+    }
+
+    pub(crate) fn set_payload_len(&mut self, len: usize) -> Result<(), etherparse::ValueError> {
+        match self {
+            Header::V4(ip) => ip.set_payload_len(len),
+            Header::V6(ip) => ip.set_payload_length(len),
+        }
+    }
+
+    pub(crate) fn write<W: io::Write>(&self, w: &mut W) -> io::Result<()> {
+        match self {
+            Header::V4(ip) => ip.write(w),
+            Header::V6(ip) => ip.write(w),
+        }
+        .map_err(|e| io::Error::other(format!("{:?}", e)))
+    }
+
+    /// The checksum of `tcp` carrying `payload` in a packet with this header.
+    pub(crate) fn tcp_checksum(
+        &self,
+        tcp: &etherparse::TcpHeader,
+        payload: &[u8],
+    ) -> Result<u16, etherparse::ValueError> {
+        match self {
+            Header::V4(ip) => tcp.calc_checksum_ipv4(ip, payload),
+            Header::V6(ip) => tcp.calc_checksum_ipv6(ip, payload),
+        }
+    }
+}
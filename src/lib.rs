@@ -1,29 +1,134 @@
 use std::collections::{HashMap, VecDeque};
 use std::io;
 use std::io::prelude::*;
-use std::net::Ipv4Addr;
-use std::sync::{Arc, Condvar, Mutex};
+use std::io::{IoSlice, IoSliceMut};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
 use std::thread;
+use std::time::{Duration, Instant};
 
+mod arp;
+mod checksum;
+mod clock;
+mod device;
+mod ethernet;
+mod frag;
+mod icmp;
+mod ip;
+pub mod pcap;
+mod pmtu;
+mod seq;
+pub mod sim;
 mod tcp;
+mod tun;
+mod udp;
+
+pub use clock::{Clock, SystemClock, VirtualClock};
+pub use device::{Device, MemoryDevice, Waker};
+pub use tcp::State;
+pub use tun::TunDevice;
 
 const SENDQUEUE_SIZE: usize = 1024;
+const RECVQUEUE_SIZE: usize = 1024;
+const MTU: usize = 1500;
 
+/// A connection as seen by incoming packets: `src` is the peer, and `dst` is us.
 #[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
 struct Quad {
-    src: (Ipv4Addr, u16),
-    dst: (Ipv4Addr, u16),
+    src: (IpAddr, u16),
+    dst: (IpAddr, u16),
+}
+
+/// Settings that are fixed once an [`Interface`] has been built.
+pub(crate) struct Config {
+    /// our own addresses; for a family without one, we answer for anything routed to the device
+    pub(crate) ipv4: Option<Ipv4Addr>,
+    pub(crate) ipv6: Option<Ipv6Addr>,
+    pub(crate) mtu: usize,
+    pub(crate) send_buffer_size: usize,
+    pub(crate) recv_buffer_size: usize,
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) trust_checksum_offload: bool,
+    /// search for the path MTU of every connection, rather than only those that seem to need it
+    pub(crate) mtu_probing: bool,
+}
+
+impl Config {
+    /// Whether packets for `dst` are meant for us.
+    fn is_local(&self, dst: IpAddr) -> bool {
+        match dst {
+            IpAddr::V4(dst) => self.ipv4.is_none_or(|addr| addr == dst),
+            IpAddr::V6(dst) => self.ipv6.is_none_or(|addr| addr == dst),
+        }
+    }
+
+    /// The address of something bound to a port for every address we have: the only one we have,
+    /// or else an unspecified one, IPv6 if we have both families.
+    fn any_address(&self) -> IpAddr {
+        match (self.ipv4, self.ipv6) {
+            (Some(addr), None) => IpAddr::V4(addr),
+            (None, Some(addr)) => IpAddr::V6(addr),
+            (Some(_), Some(_)) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            (None, None) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        }
+    }
+
+    /// Our address of the same family as `remote`, to talk to it from.
+    fn local_address(&self, remote: IpAddr) -> Option<IpAddr> {
+        match remote {
+            IpAddr::V4(_) => self.ipv4.map(IpAddr::V4),
+            IpAddr::V6(_) => self.ipv6.map(IpAddr::V6),
+        }
+    }
 }
 
-#[derive(Default)]
 struct Foobar {
+    config: Config,
+    counters: Counters,
     manager: Mutex<ConnectionManager>,
     pending_var: Condvar,
     rcv_var: Condvar,
+    snd_var: Condvar,
+    /// signalled whenever the packet loop has caught up, for [`Interface::settle`]
+    idle_var: Condvar,
+    /// wakes up the packet loop when it has something to send
+    waker: Option<Waker>,
 }
 
 type InterfaceHandle = Arc<Foobar>;
 
+/// What the packet loop counts, as it goes.
+#[derive(Default)]
+struct Counters {
+    ip_checksum_errors: AtomicU64,
+    tcp_checksum_errors: AtomicU64,
+    icmp_checksum_errors: AtomicU64,
+    udp_checksum_errors: AtomicU64,
+    reassembled: AtomicU64,
+    reassembly_failures: AtomicU64,
+}
+
+/// Counters for what an [`Interface`] has seen, as returned by [`Interface::stats`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct Stats {
+    /// Packets dropped because their IPv4 header checksum was wrong.
+    pub ip_checksum_errors: u64,
+    /// Segments dropped because their TCP checksum was wrong.
+    pub tcp_checksum_errors: u64,
+    /// ICMP messages dropped because their checksum was wrong.
+    pub icmp_checksum_errors: u64,
+    /// UDP datagrams dropped because their checksum was wrong, or missing over IPv6.
+    pub udp_checksum_errors: u64,
+    /// IPv4 datagrams put back together from fragments.
+    pub reassembled: u64,
+    /// Fragmented IPv4 datagrams given up on, because the rest never came, or their fragments
+    /// were malformed, overlapped, or would have taken up more memory than we allow.
+    pub reassembly_failures: u64,
+}
+
 pub struct Interface {
     ih: Option<InterfaceHandle>,
     jh: Option<thread::JoinHandle<io::Result<()>>>,
@@ -31,143 +136,729 @@ pub struct Interface {
 
 impl Drop for Interface {
     fn drop(&mut self) {
-        self.ih.as_mut().unwrap().manager.lock().unwrap().terminate = true;
+        if let Some(ih) = self.ih.take() {
+            ih.manager
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .terminate = true;
+        }
 
-        drop(self.ih.take());
-        self.jh
-            .take()
-            .expect("interface dropped more than once")
-            .join()
-            .unwrap()
-            .unwrap();
+        if let Some(jh) = self.jh.take() {
+            match jh.join() {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => eprintln!("packet loop failed: {}", e),
+                Err(_) => eprintln!("packet loop panicked"),
+            }
+        }
     }
 }
 
+/// What happens to live connections when an [`Interface`] is dropped.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ShutdownPolicy {
+    /// Reset every connection immediately.
+    #[default]
+    Reset,
+    /// Send a FIN on every connection, and wait up to the given duration for them to be
+    /// acknowledged before resetting whatever is left.
+    Close(Duration),
+}
+
 #[derive(Default)]
 struct ConnectionManager {
     terminate: bool,
+    shutdown_policy: ShutdownPolicy,
     connections: HashMap<Quad, tcp::Connection>,
     pending: HashMap<u16, VecDeque<Quad>>,
+    next_port: u16,
+    /// UDP sockets, by the port they are bound to
+    udp: HashMap<u16, udp::Socket>,
+    /// what we have learnt about the paths to our peers, which UDP sockets check what they send
+    /// against
+    path_mtu: pmtu::Cache,
+    /// how many times the packet loop has found nothing to receive, and fired the timers due by then
+    idle_rounds: u64,
+}
+
+/// The first port of the IANA dynamic range, used for connections we initiate.
+const EPHEMERAL_PORTS: u16 = 49152;
+
+impl ConnectionManager {
+    /// Find a free local port for a new connection from `local` to `remote`.
+    fn ephemeral_port(&mut self, local: IpAddr, remote: (IpAddr, u16)) -> io::Result<u16> {
+        self.free_port(|cm, port| {
+            let quad = Quad {
+                src: remote,
+                dst: (local, port),
+            };
+            cm.connections.contains_key(&quad) || cm.pending.contains_key(&port)
+        })
+    }
+
+    /// Find a free port to bind a UDP socket to. UDP ports are separate from TCP ones.
+    fn ephemeral_udp_port(&mut self) -> io::Result<u16> {
+        self.free_port(|cm, port| cm.udp.contains_key(&port))
+    }
+
+    /// Find the next port in the ephemeral range that isn't `in_use`.
+    fn free_port(&mut self, in_use: impl Fn(&Self, u16) -> bool) -> io::Result<u16> {
+        let nports = u16::MAX - EPHEMERAL_PORTS + 1;
+        for _ in 0..nports {
+            let port = EPHEMERAL_PORTS + self.next_port % nports;
+            self.next_port = self.next_port.wrapping_add(1);
+            if !in_use(self, port) {
+                return Ok(port);
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            "no free local ports",
+        ))
+    }
+
+    /// Start tearing down all connections, and return when we should give up on them.
+    fn start_teardown(&mut self, now: Instant) -> Instant {
+        // nothing new will be accepted, and anything not yet accepted is torn down with the rest
+        self.pending.clear();
+
+        match self.shutdown_policy {
+            ShutdownPolicy::Reset => now,
+            ShutdownPolicy::Close(timeout) => {
+                for c in self.connections.values_mut() {
+                    let _ = c.close();
+                }
+                now + timeout
+            }
+        }
+    }
 }
 
-fn packet_loop(mut nic: tun_tap::Iface, ih: InterfaceHandle) -> io::Result<()> {
-    let mut buf = [0u8; 1504];
+/// Make sure nobody keeps waiting on an interface whose packet loop has exited.
+fn abandon(ih: &InterfaceHandle) {
+    let mut cm = ih.manager.lock().unwrap_or_else(PoisonError::into_inner);
+    cm.terminate = true;
+    cm.pending.clear();
+    cm.connections.clear();
+    cm.udp.clear();
+    drop(cm);
+
+    ih.pending_var.notify_all();
+    ih.rcv_var.notify_all();
+    ih.snd_var.notify_all();
+    ih.idle_var.notify_all();
+}
+
+/// Abandons the interface when dropped, so that it happens even if the packet loop panics.
+struct Abandon(InterfaceHandle);
+
+impl Drop for Abandon {
+    fn drop(&mut self) {
+        abandon(&self.0);
+    }
+}
+
+/// What the packet loop keeps track of besides connections, which nobody else needs to see.
+#[derive(Default)]
+struct LoopState {
+    fragments: frag::Reassembler,
+    icmp_errors: icmp::RateLimit,
+}
+
+fn packet_loop<D: Device>(mut nic: D, ih: InterfaceHandle) -> io::Result<()> {
+    let mut buf = vec![0u8; ih.config.mtu];
+    let mut state = LoopState::default();
+    let mut teardown_deadline = None;
 
     loop {
+        {
+            let mut cmg = ih.manager.lock().unwrap();
+            if cmg.terminate && teardown_deadline.is_none() {
+                teardown_deadline = Some(cmg.start_teardown(ih.config.clock.now()));
+                drop(cmg);
+                ih.pending_var.notify_all();
+                cmg = ih.manager.lock().unwrap();
+            }
+
+            if let Some(deadline) = teardown_deadline
+                && (ih.config.clock.now() >= deadline
+                    || cmg.connections.values().all(|c| c.is_snd_closed()))
+            {
+                // take the connections with us, so that streams see them aborted, not closed
+                for (_, mut c) in cmg.connections.drain() {
+                    if !c.is_snd_closed() {
+                        c.abort();
+                    }
+                    c.on_tick(&mut nic)?;
+                }
+                return Ok(());
+            }
+        }
+
         // we want to read from nic, but we want to make sure that we'll wake up when the next
         // timer has to be triggered!
-        use std::os::unix::io::AsRawFd;
-        let mut pfd = [nix::poll::PollFd::new(
-            nic.as_raw_fd(),
-            nix::poll::EventFlags::POLLIN,
-        )];
-        let n = nix::poll::poll(&mut pfd[..], 10).map_err(|e| e.as_errno().unwrap())?;
-        assert_ne!(n, -1);
-        if n == 0 {
-            let mut cmg = ih.manager.lock().unwrap();
-            for connection in cmg.connections.values_mut() {
-                // XXX: don't die on errors?
-                connection.on_tick(&mut nic)?;
-            }
-            continue;
-        }
-        assert_eq!(n, 1);
-        let nbytes = nic.recv(&mut buf[..])?;
-
-        // TODO: if self.terminate && Arc::get_strong_refs(ih) == 1; then tear down all connections and return.
-
-        // if s/without_packet_info/new/:
-        //
-        // let _eth_flags = u16::from_be_bytes([buf[0], buf[1]]);
-        // let eth_proto = u16::from_be_bytes([buf[2], buf[3]]);
-        // if eth_proto != 0x0800 {
-        //     // not ipv4
-        //     continue;
-        // }
-        //
-        // and also include on send
-
-        match etherparse::Ipv4HeaderSlice::from_slice(&buf[..nbytes]) {
-            Ok(iph) => {
-                let src = iph.source_addr();
-                let dst = iph.destination_addr();
-                if iph.protocol() != 0x06 {
-                    eprintln!("BAD PROTOCOL");
-                    // not tcp
-                    continue;
+        let mut idle = true;
+        if nic.poll(Duration::from_millis(10))? {
+            match nic.recv(&mut buf[..]) {
+                Ok(nbytes) => {
+                    idle = false;
+                    on_packet(&mut nic, &ih, &mut state, &buf[..nbytes])?;
                 }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
 
-                match etherparse::TcpHeaderSlice::from_slice(&buf[iph.slice().len()..nbytes]) {
-                    Ok(tcph) => {
-                        use std::collections::hash_map::Entry;
-                        let datai = iph.slice().len() + tcph.slice().len();
-                        let mut cmg = ih.manager.lock().unwrap();
-                        let cm = &mut *cmg;
-                        let q = Quad {
-                            src: (src, tcph.source_port()),
-                            dst: (dst, tcph.destination_port()),
-                        };
-
-                        match cm.connections.entry(q) {
-                            Entry::Occupied(mut c) => {
-                                eprintln!("got packet for known quad {:?}", q);
-                                let a = c.get_mut().on_packet(
-                                    &mut nic,
-                                    iph,
-                                    tcph,
-                                    &buf[datai..nbytes],
-                                )?;
-
-                                // TODO: compare before/after
-                                drop(cmg);
-                                if a.contains(tcp::Available::READ) {
-                                    ih.rcv_var.notify_all()
-                                }
-                                if a.contains(tcp::Available::WRITE) {
-                                    // TODO: ih.snd_var.notify_all()
-                                }
-                            }
-                            Entry::Vacant(e) => {
-                                eprintln!("got packet for unknown quad {:?}", q);
-                                if let Some(pending) = cm.pending.get_mut(&tcph.destination_port())
-                                {
-                                    eprintln!("listening, so accepting");
-                                    if let Some(c) = tcp::Connection::accept(
-                                        &mut nic,
-                                        iph,
-                                        tcph,
-                                        &buf[datai..nbytes],
-                                    )? {
-                                        e.insert(c);
-                                        pending.push_back(q);
-                                        drop(cmg);
-                                        ih.pending_var.notify_all()
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!("ignoring weird tcp packet {:?}", e);
-                    }
+        let expired = state.fragments.expire(ih.config.clock.now());
+        ih.counters
+            .reassembly_failures
+            .fetch_add(expired as u64, Ordering::Relaxed);
+
+        let mut cmg = ih.manager.lock().unwrap();
+        let cm = &mut *cmg;
+        cm.path_mtu.expire(ih.config.clock.now());
+        for (quad, connection) in cm.connections.iter_mut() {
+            connection.set_path_mtu(cm.path_mtu.get(quad.src.0).unwrap_or(ih.config.mtu));
+            // XXX: don't die on errors?
+            connection.on_tick(&mut nic)?;
+        }
+        // nobody can observe a closed connection once its stream is gone
+        cmg.connections
+            .retain(|_, c| !(c.orphaned && c.is_closed()));
+        for (&port, socket) in cmg.udp.iter_mut() {
+            socket.flush(&mut nic, port, |remote| ih.config.local_address(remote))?;
+        }
+        if idle {
+            cmg.idle_rounds += 1;
+            drop(cmg);
+            ih.idle_var.notify_all();
+        }
+    }
+}
+
+/// Blocks until the packet loop of the interface behind `ih` has caught up; see
+/// [`Interface::settle`].
+fn settle(ih: &InterfaceHandle) {
+    let mut cm = ih.manager.lock().unwrap();
+    // the round under way may have polled the device before we got here, so only the one after
+    // it is sure to have seen everything
+    let caught_up = cm.idle_rounds + 2;
+    if let Some(wake) = &ih.waker {
+        wake();
+    }
+    while cm.idle_rounds < caught_up && !cm.terminate {
+        cm = ih.idle_var.wait(cm).unwrap();
+    }
+}
+
+fn on_packet(
+    nic: &mut dyn Device,
+    ih: &InterfaceHandle,
+    state: &mut LoopState,
+    buf: &[u8],
+) -> io::Result<()> {
+    let Some(ip) = ip::Packet::parse(buf) else {
+        // eprintln!("ignoring weird packet");
+        return Ok(());
+    };
+
+    let verify = !(ih.config.trust_checksum_offload && nic.checksum_verified());
+    // only IPv4 has a header checksum
+    if verify && ip.src.is_ipv4() && !checksum::ipv4_header(ip.header) {
+        ih.counters
+            .ip_checksum_errors
+            .fetch_add(1, Ordering::Relaxed);
+        return Ok(());
+    }
+
+    let group = match ip.dst {
+        IpAddr::V4(dst) => dst.is_broadcast() || dst.is_multicast(),
+        IpAddr::V6(dst) => dst.is_multicast(),
+    };
+    // there's no talking to a group over TCP (RFC 1122 S4.2.3.10), but anyone may listen over UDP
+    let for_us = ih.config.is_local(ip.dst) || group && ip.protocol == udp::PROTOCOL;
+    if !for_us {
+        // not for us
+        return Ok(());
+    }
+
+    // a fragment is held on to until the rest of its datagram arrives
+    let reassembled;
+    let ip = match ip.fragment {
+        None => ip,
+        Some(fragment) => match state.fragments.insert(&ip, fragment, ih.config.clock.now()) {
+            frag::Outcome::Complete(datagram) => {
+                ih.counters.reassembled.fetch_add(1, Ordering::Relaxed);
+                reassembled = datagram;
+                ip::Packet {
+                    header: &reassembled.header,
+                    payload: &reassembled.payload,
+                    fragment: None,
+                    ..ip
                 }
             }
-            Err(e) => {
-                // eprintln!("ignoring weird packet {:?}", e);
+            frag::Outcome::Incomplete => return Ok(()),
+            frag::Outcome::Dropped => {
+                ih.counters
+                    .reassembly_failures
+                    .fetch_add(1, Ordering::Relaxed);
+                return Ok(());
             }
+        },
+    };
+
+    match ip.protocol {
+        0x06 => on_segment(nic, ih, &ip, verify),
+        udp::PROTOCOL => on_datagram(nic, ih, state, &ip, verify),
+        p if p == icmp::protocol(ip.src) => on_icmp(nic, ih, &ip, verify),
+        _ => {
+            eprintln!("BAD PROTOCOL");
+            if state.icmp_errors.allow(ih.config.clock.now()) {
+                icmp::send_protocol_unreachable(nic, &ip, ih.config.mtu)?;
+            }
+            Ok(())
         }
     }
 }
 
-impl Interface {
-    pub fn new() -> io::Result<Self> {
-        let nic = tun_tap::Iface::without_packet_info("tun0", tun_tap::Mode::Tun)?;
+fn on_icmp(
+    nic: &mut dyn Device,
+    ih: &InterfaceHandle,
+    ip: &ip::Packet<'_>,
+    verify: bool,
+) -> io::Result<()> {
+    if verify && !checksum::icmp(ip.src, ip.dst, ip.payload) {
+        ih.counters
+            .icmp_checksum_errors
+            .fetch_add(1, Ordering::Relaxed);
+        return Ok(());
+    }
+
+    match icmp::Message::parse(ip) {
+        icmp::Message::EchoRequest => icmp::send_echo_reply(nic, ip, ih.config.mtu),
+        icmp::Message::TcpError { quad, seq, error } => {
+            let mut cm = ih.manager.lock().unwrap();
+            let Some(c) = cm.connections.get_mut(&quad) else {
+                return Ok(());
+            };
+            eprintln!("got {:?} for {:?}", error, quad);
+            let a = c.on_icmp_error(seq, error);
+            drop(cm);
+            if a.contains(tcp::Available::READ) {
+                ih.rcv_var.notify_all()
+            }
+            if a.contains(tcp::Available::WRITE) {
+                ih.snd_var.notify_all()
+            }
+            Ok(())
+        }
+        icmp::Message::PacketTooBig { quad, seq, mtu } => {
+            let mut cm = ih.manager.lock().unwrap();
+            if let Some(c) = cm.connections.get_mut(&quad)
+                && c.on_packet_too_big(seq, mtu)
+            {
+                // every other connection to the peer finds out on the next tick
+                cm.path_mtu.update(quad.src.0, mtu, ih.config.clock.now());
+            }
+            Ok(())
+        }
+        icmp::Message::UdpTooBig { quad, mtu } => {
+            let mut cm = ih.manager.lock().unwrap();
+            // there's no sequence number to check, so only believe it about a port we send from,
+            // and only if it makes the path smaller; we don't fragment datagrams, so what we
+            // learn is for the next ones we send, and for the connections to the peer
+            let dst = quad.src.0;
+            if cm.udp.contains_key(&quad.dst.1)
+                && mtu < cm.path_mtu.get(dst).unwrap_or(ih.config.mtu)
+            {
+                cm.path_mtu.update(dst, mtu, ih.config.clock.now());
+            }
+            Ok(())
+        }
+        icmp::Message::UdpError { quad, error } => {
+            let mut cm = ih.manager.lock().unwrap();
+            let peer = SocketAddr::new(quad.src.0, quad.src.1);
+            // like BSD, only connected sockets hear about it, since they're the ones that know
+            // who they are talking to
+            if let Some(socket) = cm.udp.get_mut(&quad.dst.1)
+                && socket.peer == Some(peer)
+            {
+                eprintln!("got {:?} for udp {:?}", error, quad);
+                socket.error = Some(error.kind);
+                drop(cm);
+                ih.rcv_var.notify_all();
+            }
+            Ok(())
+        }
+        icmp::Message::Other => Ok(()),
+    }
+}
+
+fn on_datagram(
+    nic: &mut dyn Device,
+    ih: &InterfaceHandle,
+    state: &mut LoopState,
+    ip: &ip::Packet<'_>,
+    verify: bool,
+) -> io::Result<()> {
+    let Some(datagram) = udp::Datagram::parse(ip.payload) else {
+        eprintln!("ignoring weird udp packet");
+        return Ok(());
+    };
+    if verify && !datagram.checksum_ok(ip) {
+        ih.counters
+            .udp_checksum_errors
+            .fetch_add(1, Ordering::Relaxed);
+        return Ok(());
+    }
+
+    let mut cm = ih.manager.lock().unwrap();
+    let Some(socket) = cm.udp.get_mut(&datagram.dst_port) else {
+        drop(cm);
+        // nobody is bound to the port (RFC 1122 S4.1.3.1)
+        if state.icmp_errors.allow(ih.config.clock.now()) {
+            icmp::send_port_unreachable(nic, ip, ih.config.mtu)?;
+        }
+        return Ok(());
+    };
+    let from = SocketAddr::new(ip.src, datagram.src_port);
+    if socket.on_datagram(from, datagram.data(), ih.config.recv_buffer_size) {
+        drop(cm);
+        ih.rcv_var.notify_all();
+    }
+    Ok(())
+}
 
-        let ih: InterfaceHandle = Arc::default();
+fn on_segment(
+    nic: &mut dyn Device,
+    ih: &InterfaceHandle,
+    ip: &ip::Packet<'_>,
+    verify: bool,
+) -> io::Result<()> {
+    if verify && !checksum::payload(ip.src, ip.dst, ip.protocol, ip.payload) {
+        ih.counters
+            .tcp_checksum_errors
+            .fetch_add(1, Ordering::Relaxed);
+        return Ok(());
+    }
+
+    let tcph = match etherparse::TcpHeaderSlice::from_slice(ip.payload) {
+        Ok(tcph) => tcph,
+        Err(e) => {
+            eprintln!("ignoring weird tcp packet {:?}", e);
+            return Ok(());
+        }
+    };
+
+    use std::collections::hash_map::Entry;
+    let data = &ip.payload[tcph.slice().len()..];
+    let mut cmg = ih.manager.lock().unwrap();
+    let cm = &mut *cmg;
+    let q = Quad {
+        src: (ip.src, tcph.source_port()),
+        dst: (ip.dst, tcph.destination_port()),
+    };
+
+    match cm.connections.entry(q) {
+        Entry::Occupied(mut c) => {
+            eprintln!("got packet for known quad {:?}", q);
+            let a = c.get_mut().on_packet(nic, ip, tcph, data)?;
+
+            // TODO: compare before/after
+            drop(cmg);
+            if a.contains(tcp::Available::READ) {
+                ih.rcv_var.notify_all()
+            }
+            if a.contains(tcp::Available::WRITE) {
+                ih.snd_var.notify_all()
+            }
+        }
+        Entry::Vacant(e) => {
+            eprintln!("got packet for unknown quad {:?}", q);
+            if let Some(pending) = cm.pending.get_mut(&tcph.destination_port()) {
+                eprintln!("listening, so accepting");
+                if let Some(c) = tcp::Connection::accept(nic, &ih.config, ip, tcph.clone(), data)? {
+                    e.insert(c);
+                    pending.push_back(q);
+                    drop(cmg);
+                    ih.pending_var.notify_all();
+                    return Ok(());
+                }
+            }
+
+            if !tcph.rst() {
+                // nobody is listening, or this isn't a SYN (RFC 793 S3.4)
+                tcp::send_reset(nic, ip, &tcph, data.len())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The kind of virtual device an [`Interface`] is attached to.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Mode {
+    /// A TUN device, which carries raw IP packets.
+    #[default]
+    Tun,
+    /// A TAP device, which carries Ethernet frames, so that the stack can sit on an Ethernet
+    /// segment with other hosts. Neighbours are found with ARP, which makes this IPv4 only.
+    Tap,
+}
+
+/// Configures and creates an [`Interface`].
+///
+/// ```no_run
+/// use std::net::{Ipv4Addr, Ipv6Addr};
+///
+/// let iface = trust::InterfaceBuilder::new()
+///     .name("tun1")
+///     .address(Ipv4Addr::new(10, 0, 0, 2), 24)
+///     .host_address(Ipv4Addr::new(10, 0, 0, 1))
+///     .address(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2), 64)
+///     .host_address(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1))
+///     .build()?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Clone, Debug)]
+pub struct InterfaceBuilder {
+    name: String,
+    mode: Mode,
+    ipv4: Option<(Ipv4Addr, u8)>,
+    ipv6: Option<(Ipv6Addr, u8)>,
+    host_ipv4: Option<Ipv4Addr>,
+    host_ipv6: Option<Ipv6Addr>,
+    mtu: usize,
+    send_buffer_size: usize,
+    recv_buffer_size: usize,
+    clock: Arc<dyn Clock>,
+    capture: Option<PathBuf>,
+    trust_checksum_offload: bool,
+    mtu_probing: bool,
+    hardware_address: Option<[u8; 6]>,
+    gateway: Option<Ipv4Addr>,
+}
+
+impl Default for InterfaceBuilder {
+    fn default() -> Self {
+        InterfaceBuilder {
+            name: String::from("tun0"),
+            mode: Mode::Tun,
+            ipv4: None,
+            ipv6: None,
+            host_ipv4: None,
+            host_ipv6: None,
+            mtu: MTU,
+            send_buffer_size: SENDQUEUE_SIZE,
+            recv_buffer_size: RECVQUEUE_SIZE,
+            clock: Arc::new(SystemClock),
+            capture: None,
+            trust_checksum_offload: false,
+            mtu_probing: false,
+            hardware_address: None,
+            gateway: None,
+        }
+    }
+}
+
+impl InterfaceBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the name of the device to create or attach to. Defaults to `tun0`.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    /// Sets whether to use a TUN or a TAP device. Defaults to [`Mode::Tun`].
+    pub fn mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Sets an address of this stack, and the prefix length of the network it is on.
+    ///
+    /// The stack has at most one IPv4 and one IPv6 address, and setting another of the same
+    /// family replaces the first. Packets for other addresses of a family are ignored. For a
+    /// family without an address, the stack answers for any address the host routes to the
+    /// device.
+    pub fn address(mut self, addr: impl Into<IpAddr>, prefix_len: u8) -> Self {
+        match addr.into() {
+            IpAddr::V4(addr) => self.ipv4 = Some((addr, prefix_len)),
+            IpAddr::V6(addr) => self.ipv6 = Some((addr, prefix_len)),
+        }
+        self
+    }
+
+    /// Sets an address the host itself uses on the device, one per family like
+    /// [`InterfaceBuilder::address`].
+    ///
+    /// When set, the device is assigned this address (with the prefix length given to
+    /// [`InterfaceBuilder::address`] for the same family, or else /24 for IPv4 and /64 for IPv6),
+    /// so that the host routes the network through it.
+    pub fn host_address(mut self, addr: impl Into<IpAddr>) -> Self {
+        match addr.into() {
+            IpAddr::V4(addr) => self.host_ipv4 = Some(addr),
+            IpAddr::V6(addr) => self.host_ipv6 = Some(addr),
+        }
+        self
+    }
+
+    /// Sets the MTU of the device. Defaults to 1500.
+    pub fn mtu(mut self, mtu: usize) -> Self {
+        self.mtu = mtu;
+        self
+    }
+
+    /// Sets how many bytes each connection buffers for sending.
+    pub fn send_buffer_size(mut self, size: usize) -> Self {
+        self.send_buffer_size = size;
+        self
+    }
+
+    /// Sets how many bytes each connection buffers for receiving, which also determines the
+    /// window we advertise.
+    pub fn recv_buffer_size(mut self, size: usize) -> Self {
+        self.recv_buffer_size = size;
+        self
+    }
+
+    /// Sets the clock that timers are measured against. Defaults to [`SystemClock`].
+    pub fn clock(mut self, clock: impl Clock) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Records every packet sent and received to a pcap file at `path`.
+    ///
+    /// This works for any device, and needs no privileges to watch, unlike `tshark -i tun0`.
+    pub fn capture(mut self, path: impl Into<PathBuf>) -> Self {
+        self.capture = Some(path.into());
+        self
+    }
+
+    /// Skips verifying the checksums of packets the device says it has verified already (see
+    /// [`Device::checksum_verified`]). Defaults to `false`.
+    pub fn trust_checksum_offload(mut self, trust: bool) -> Self {
+        self.trust_checksum_offload = trust;
+        self
+    }
+
+    /// Searches for the largest packets that get through to the peer of every connection, by
+    /// probing with ever larger segments (RFC 4821). Defaults to `false`, in which case
+    /// connections send packets as large as the link and ICMP allow, and only search once they
+    /// seem to have run into a path that drops large packets without telling us.
+    pub fn mtu_probing(mut self, probing: bool) -> Self {
+        self.mtu_probing = probing;
+        self
+    }
+
+    /// Sets the Ethernet address of the stack, in [`Mode::Tap`]. Defaults to a random locally
+    /// administered one.
+    pub fn hardware_address(mut self, mac: [u8; 6]) -> Self {
+        self.hardware_address = Some(mac);
+        self
+    }
+
+    /// Sets the router that packets for addresses outside the network go through, in
+    /// [`Mode::Tap`]. Defaults to the host's IPv4 address, if there is one; without either,
+    /// such packets are dropped.
+    pub fn gateway(mut self, addr: Ipv4Addr) -> Self {
+        self.gateway = Some(addr);
+        self
+    }
+
+    /// Creates the device, configures it, and starts processing packets.
+    pub fn build(self) -> io::Result<Interface> {
+        self.validate()?;
+
+        let nic = match self.mode {
+            Mode::Tun => TunDevice::new(&self.name)?,
+            Mode::Tap => TunDevice::new_tap(&self.name)?,
+        };
+        // before the addresses, since Linux turns IPv6 off on links that are too small for it
+        tun::set_mtu(nic.name(), self.mtu)?;
+        if let Some(host) = self.host_ipv4 {
+            tun::set_address(
+                nic.name(),
+                host,
+                self.ipv4.map_or(24, |(_, prefix_len)| prefix_len),
+            )?;
+        }
+        if let Some(host) = self.host_ipv6 {
+            tun::set_address6(
+                nic.name(),
+                host,
+                self.ipv6.map_or(64, |(_, prefix_len)| prefix_len),
+            )?;
+        }
+        tun::set_up(nic.name())?;
+
+        self.build_with_device(nic)
+    }
+
+    /// Starts processing packets on an existing device.
+    ///
+    /// The device name and host address only apply to devices created by
+    /// [`InterfaceBuilder::build`], and are ignored. In [`Mode::Tap`], `nic` carries Ethernet
+    /// frames.
+    pub fn build_with_device<D: Device>(self, nic: D) -> io::Result<Interface> {
+        self.validate()?;
+        let link = match (self.mode, self.ipv4) {
+            (Mode::Tap, Some((addr, prefix_len))) => Some(ethernet::Settings {
+                mac: self.hardware_address.unwrap_or_else(random_mac),
+                addr,
+                prefix_len,
+                gateway: self.gateway.or(self.host_ipv4),
+            }),
+            // checked by validate
+            _ => None,
+        };
+        let capture = match &self.capture {
+            Some(path) => Some(pcap::Writer::new(io::BufWriter::new(
+                std::fs::File::create(path)?,
+            ))?),
+            None => None,
+        };
+
+        let ih: InterfaceHandle = Arc::new(Foobar {
+            config: Config {
+                ipv4: self.ipv4.map(|(addr, _)| addr),
+                ipv6: self.ipv6.map(|(addr, _)| addr),
+                mtu: self.mtu,
+                send_buffer_size: self.send_buffer_size,
+                recv_buffer_size: self.recv_buffer_size,
+                clock: self.clock,
+                trust_checksum_offload: self.trust_checksum_offload,
+                mtu_probing: self.mtu_probing,
+            },
+            counters: Counters::default(),
+            manager: Mutex::default(),
+            pending_var: Condvar::new(),
+            rcv_var: Condvar::new(),
+            snd_var: Condvar::new(),
+            idle_var: Condvar::new(),
+            waker: nic.waker(),
+        });
 
         let jh = {
             let ih = ih.clone();
-            thread::spawn(move || packet_loop(nic, ih))
+            thread::spawn(move || {
+                let _abandon = Abandon(ih.clone());
+                match link {
+                    Some(settings) => {
+                        let nic = ethernet::Link::new(
+                            nic,
+                            settings,
+                            ih.config.mtu,
+                            ih.config.clock.clone(),
+                        );
+                        capture_and_loop(nic, capture, ih)
+                    }
+                    None => capture_and_loop(nic, capture, ih),
+                }
+            })
         };
 
         Ok(Interface {
@@ -176,9 +867,199 @@ impl Interface {
         })
     }
 
+    fn validate(&self) -> io::Result<()> {
+        if self.ipv4.is_some_and(|(_, prefix_len)| prefix_len > 32) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "prefix length must be at most 32",
+            ));
+        }
+        if self.ipv6.is_some_and(|(_, prefix_len)| prefix_len > 128) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "prefix length must be at most 128",
+            ));
+        }
+        if self.mtu < 68 {
+            // the smallest MTU an IPv4 link may have (RFC 791)
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "MTU must be at least 68",
+            ));
+        }
+        if (self.ipv6.is_some() || self.host_ipv6.is_some()) && self.mtu < 1280 {
+            // the smallest MTU an IPv6 link may have (RFC 8200 S5)
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "MTU must be at least 1280 for IPv6",
+            ));
+        }
+        if self.mode == Mode::Tap && (self.ipv6.is_some() || self.host_ipv6.is_some()) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "IPv6 on TAP devices needs neighbor discovery, which is not supported",
+            ));
+        }
+        if self.mode == Mode::Tap && self.ipv4.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "TAP devices need an IPv4 address to answer ARP for",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Runs the packet loop on `nic`, recording what goes through it if asked to. Captures are of IP
+/// packets, even on TAP devices.
+fn capture_and_loop<D: Device>(
+    nic: D,
+    capture: Option<pcap::Writer<io::BufWriter<std::fs::File>>>,
+    ih: InterfaceHandle,
+) -> io::Result<()> {
+    match capture {
+        Some(w) => {
+            let clock = ih.config.clock.clone();
+            packet_loop(pcap::Capture::new(nic, w).clock(clock), ih)
+        }
+        None => packet_loop(nic, ih),
+    }
+}
+
+/// A random unicast Ethernet address, from the block that is up to us to assign (IEEE 802
+/// locally administered addresses).
+fn random_mac() -> [u8; 6] {
+    use std::hash::BuildHasher;
+    let bits = std::collections::hash_map::RandomState::new().hash_one(std::process::id());
+    let [a, b, c, d, e, f, _, _] = bits.to_le_bytes();
+    [(a & 0xfc) | 0x02, b, c, d, e, f]
+}
+
+impl Interface {
+    /// Creates an interface on `tun0` with the default settings.
+    ///
+    /// See [`InterfaceBuilder`] for more control.
+    pub fn new() -> io::Result<Self> {
+        InterfaceBuilder::new().build()
+    }
+
+    /// Creates an interface on the given device with the default settings.
+    pub fn with_device<D: Device>(nic: D) -> io::Result<Self> {
+        InterfaceBuilder::new().build_with_device(nic)
+    }
+
+    /// Returns what the interface has counted so far.
+    pub fn stats(&self) -> Stats {
+        let counters = &self.ih.as_ref().unwrap().counters;
+        Stats {
+            ip_checksum_errors: counters.ip_checksum_errors.load(Ordering::Relaxed),
+            tcp_checksum_errors: counters.tcp_checksum_errors.load(Ordering::Relaxed),
+            icmp_checksum_errors: counters.icmp_checksum_errors.load(Ordering::Relaxed),
+            udp_checksum_errors: counters.udp_checksum_errors.load(Ordering::Relaxed),
+            reassembled: counters.reassembled.load(Ordering::Relaxed),
+            reassembly_failures: counters.reassembly_failures.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Blocks until the packet loop has dealt with every packet the device has for it, and fired
+    /// every timer that is due by the interface's [`Clock`].
+    ///
+    /// This is how tests on a [`VirtualClock`] find out that the stack has caught up with a
+    /// [`VirtualClock::advance`] or with a packet they sent, without sleeping and hoping.
+    pub fn settle(&self) {
+        settle(self.ih.as_ref().unwrap());
+    }
+
+    /// Returns a way for another thread to [`settle`](Interface::settle) this interface, even
+    /// once the interface itself has moved elsewhere.
+    pub(crate) fn settler(&self) -> impl Fn() + Send + 'static {
+        let ih = self.ih.clone().unwrap();
+        move || settle(&ih)
+    }
+
+    /// Sets what happens to live connections when this interface is dropped.
+    pub fn set_shutdown_policy(&mut self, policy: ShutdownPolicy) {
+        self.ih
+            .as_mut()
+            .unwrap()
+            .manager
+            .lock()
+            .unwrap()
+            .shutdown_policy = policy;
+    }
+
+    /// Opens a connection to `addr`, and blocks until it is established.
+    ///
+    /// The interface must have been given an address of the same family as `addr` with
+    /// [`InterfaceBuilder::address`].
+    pub fn connect(&mut self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let ih = self.ih.as_mut().unwrap();
+        let local = ih.config.local_address(addr.ip()).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                "interface has no address to connect from",
+            )
+        })?;
+
+        let mut cm = ih.manager.lock().unwrap();
+        if cm.terminate {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "interface has shut down",
+            ));
+        }
+        let remote = (addr.ip(), addr.port());
+        let port = cm.ephemeral_port(local, remote)?;
+        let quad = Quad {
+            src: remote,
+            dst: (local, port),
+        };
+        cm.connections
+            .insert(quad, tcp::Connection::connect(&ih.config, quad));
+
+        loop {
+            let c = cm.connections.get(&quad).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "connection was terminated unexpectedly",
+                )
+            })?;
+            if c.is_reset() {
+                let err = reset_error(c, io::ErrorKind::ConnectionRefused, "connection refused");
+                cm.connections.remove(&quad);
+                return Err(err);
+            }
+            if c.is_closed() {
+                cm.connections.remove(&quad);
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "connection was terminated unexpectedly",
+                ));
+            }
+            if !c.is_connecting() {
+                break;
+            }
+            cm = ih.snd_var.wait(cm).unwrap();
+        }
+        drop(cm);
+
+        Ok(TcpStream {
+            inner: Arc::new(StreamInner {
+                quad,
+                h: ih.clone(),
+            }),
+        })
+    }
+
     pub fn bind(&mut self, port: u16) -> io::Result<TcpListener> {
         use std::collections::hash_map::Entry;
         let mut cm = self.ih.as_mut().unwrap().manager.lock().unwrap();
+        if cm.terminate {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "interface has shut down",
+            ));
+        }
         match cm.pending.entry(port) {
             Entry::Vacant(v) => {
                 v.insert(VecDeque::new());
@@ -196,6 +1077,34 @@ impl Interface {
             h: self.ih.as_mut().unwrap().clone(),
         })
     }
+
+    /// Binds a UDP socket to `port`, or to a free port if it is 0.
+    pub fn bind_udp(&mut self, port: u16) -> io::Result<UdpSocket> {
+        let ih = self.ih.as_mut().unwrap();
+        let mut cm = ih.manager.lock().unwrap();
+        if cm.terminate {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "interface has shut down",
+            ));
+        }
+        let port = match port {
+            0 => cm.ephemeral_udp_port()?,
+            port if cm.udp.contains_key(&port) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    "port already bound",
+                ));
+            }
+            port => port,
+        };
+        cm.udp.insert(port, udp::Socket::default());
+        drop(cm);
+        Ok(UdpSocket {
+            port,
+            h: ih.clone(),
+        })
+    }
 }
 
 pub struct TcpListener {
@@ -205,24 +1114,46 @@ pub struct TcpListener {
 
 impl Drop for TcpListener {
     fn drop(&mut self) {
-        let mut cm = self.h.manager.lock().unwrap();
+        let mut cm = self
+            .h
+            .manager
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
 
-        let pending = cm
-            .pending
-            .remove(&self.port)
-            .expect("port closed while listener still active");
+        let Some(pending) = cm.pending.remove(&self.port) else {
+            return;
+        };
 
+        // nobody will ever accept these, so reset them
         for quad in pending {
-            // TODO: terminate cm.connections[quad]
-            unimplemented!();
+            if let Some(c) = cm.connections.get_mut(&quad) {
+                c.abort();
+                c.orphaned = true;
+            }
         }
     }
 }
 
 impl TcpListener {
+    /// Returns the local socket address of this listener.
+    ///
+    /// Listeners accept connections of either family. If the interface has exactly one address,
+    /// that is the one returned; otherwise the returned address is unspecified, and IPv6 if the
+    /// interface has addresses of both families.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(SocketAddr::new(self.h.config.any_address(), self.port))
+    }
+
     pub fn accept(&mut self) -> io::Result<TcpStream> {
         let mut cm = self.h.manager.lock().unwrap();
         loop {
+            if cm.terminate {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "interface has shut down",
+                ));
+            }
+
             if let Some(quad) = cm
                 .pending
                 .get_mut(&self.port)
@@ -230,8 +1161,10 @@ impl TcpListener {
                 .pop_front()
             {
                 return Ok(TcpStream {
-                    quad,
-                    h: self.h.clone(),
+                    inner: Arc::new(StreamInner {
+                        quad,
+                        h: self.h.clone(),
+                    }),
                 });
             }
 
@@ -240,21 +1173,109 @@ impl TcpListener {
     }
 }
 
+/// A TCP connection on an [`Interface`].
+///
+/// The connection is closed once the stream and every handle obtained from
+/// [`TcpStream::try_clone`] or [`TcpStream::split`] have been dropped.
 pub struct TcpStream {
+    inner: Arc<StreamInner>,
+}
+
+/// The parts of a stream shared by all of its handles.
+struct StreamInner {
     quad: Quad,
     h: InterfaceHandle,
 }
 
-impl Drop for TcpStream {
+impl Drop for StreamInner {
     fn drop(&mut self) {
-        let cm = self.h.manager.lock().unwrap();
-        // TODO: send FIN on cm.connections[quad]
-        // TODO: _eventually_ remove self.quad from cm.connections
+        let mut cm = self
+            .h
+            .manager
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let Some(c) = cm.connections.get_mut(&self.quad) else {
+            return;
+        };
+
+        if c.is_closed() {
+            cm.connections.remove(&self.quad);
+            return;
+        }
+
+        c.orphaned = true;
+        match c.linger {
+            Some(timeout) if timeout.is_zero() => {
+                c.abort();
+            }
+            _ if !c.incoming.is_empty() => {
+                // like Linux, reset the connection if the user never read everything we received
+                c.abort();
+            }
+            None => {
+                // the packet loop removes the connection once the FIN exchange completes
+                let _ = c.close();
+            }
+            Some(timeout) => {
+                let _ = c.close();
+
+                // like BSD, wait for our FIN to be acknowledged, and reset if it takes too long
+                let clock = &self.h.config.clock;
+                let deadline = clock.now() + timeout;
+                while let Some(c) = cm.connections.get_mut(&self.quad) {
+                    if c.is_snd_closed() {
+                        break;
+                    }
+                    let now = clock.now();
+                    if now >= deadline {
+                        c.abort();
+                        break;
+                    }
+                    // nothing tells us when the clock moves, so look again as often as the
+                    // packet loop does
+                    let wait = std::cmp::min(deadline - now, Duration::from_millis(10));
+                    cm = self
+                        .h
+                        .snd_var
+                        .wait_timeout(cm, wait)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0;
+                }
+            }
+        }
     }
 }
 
-impl Read for TcpStream {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+/// The error for a connection that was reset, which is `kind` if it was by a RST, and whatever
+/// the network said if it was by a hard ICMP error.
+fn reset_error(c: &tcp::Connection, kind: io::ErrorKind, msg: &str) -> io::Error {
+    match c.error() {
+        Some(kind) => io::Error::new(kind, "connection failed, as reported by ICMP"),
+        None => io::Error::new(kind, msg),
+    }
+}
+
+/// Copy as much of `incoming` as fits into `buf`, skipping the first `skip` bytes.
+fn copy_incoming(incoming: &VecDeque<u8>, skip: usize, buf: &mut [u8]) -> usize {
+    let (mut head, mut tail) = incoming.as_slices();
+    if skip < head.len() {
+        head = &head[skip..];
+    } else {
+        tail = &tail[(skip - head.len())..];
+        head = &[];
+    }
+
+    let hread = std::cmp::min(buf.len(), head.len());
+    buf[..hread].copy_from_slice(&head[..hread]);
+    let tread = std::cmp::min(buf.len() - hread, tail.len());
+    buf[hread..(hread + tread)].copy_from_slice(&tail[..tread]);
+    hread + tread
+}
+
+impl StreamInner {
+    /// Block until there is data to read, then fill `bufs` in order, draining what was read
+    /// unless `peek` is set.
+    fn recv(&self, bufs: &mut [IoSliceMut<'_>], peek: bool) -> io::Result<usize> {
         let mut cm = self.h.manager.lock().unwrap();
         loop {
             let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
@@ -264,6 +1285,14 @@ impl Read for TcpStream {
                 )
             })?;
 
+            if c.is_reset() {
+                return Err(reset_error(
+                    c,
+                    io::ErrorKind::ConnectionReset,
+                    "connection reset by peer",
+                ));
+            }
+
             if c.is_rcv_closed() && c.incoming.is_empty() {
                 // no more data to read, and no need to block, because there won't be any more
                 return Ok(0);
@@ -271,24 +1300,25 @@ impl Read for TcpStream {
 
             if !c.incoming.is_empty() {
                 let mut nread = 0;
-                let (head, tail) = c.incoming.as_slices();
-                let hread = std::cmp::min(buf.len(), head.len());
-                buf[..hread].copy_from_slice(&head[..hread]);
-                nread += hread;
-                let tread = std::cmp::min(buf.len() - nread, tail.len());
-                buf[hread..(hread + tread)].copy_from_slice(&tail[..tread]);
-                nread += tread;
-                drop(c.incoming.drain(..nread));
+                for buf in bufs.iter_mut() {
+                    let n = copy_incoming(&c.incoming, nread, buf);
+                    nread += n;
+                    if n < buf.len() {
+                        break;
+                    }
+                }
+                if !peek {
+                    drop(c.incoming.drain(..nread));
+                }
                 return Ok(nread);
             }
 
             cm = self.h.rcv_var.wait(cm).unwrap();
         }
     }
-}
 
-impl Write for TcpStream {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    /// Queue as much of `bufs` as fits in the send queue.
+    fn send(&self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
         let mut cm = self.h.manager.lock().unwrap();
         let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
             io::Error::new(
@@ -297,7 +1327,22 @@ impl Write for TcpStream {
             )
         })?;
 
-        if c.unacked.len() >= SENDQUEUE_SIZE {
+        if c.is_reset() {
+            return Err(reset_error(
+                c,
+                io::ErrorKind::ConnectionReset,
+                "connection reset by peer",
+            ));
+        }
+        if c.closed || c.is_closed() {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "stream has been shut down for writing",
+            ));
+        }
+
+        let size = self.h.config.send_buffer_size;
+        if c.unacked.len() >= size {
             // TODO: block
             return Err(io::Error::new(
                 io::ErrorKind::WouldBlock,
@@ -305,13 +1350,20 @@ impl Write for TcpStream {
             ));
         }
 
-        let nwrite = std::cmp::min(buf.len(), SENDQUEUE_SIZE - c.unacked.len());
-        c.unacked.extend(buf[..nwrite].iter());
+        let mut nwrite = 0;
+        for buf in bufs {
+            let n = std::cmp::min(buf.len(), size - c.unacked.len());
+            c.unacked.extend(buf[..n].iter());
+            nwrite += n;
+            if n < buf.len() {
+                break;
+            }
+        }
 
         Ok(nwrite)
     }
 
-    fn flush(&mut self) -> io::Result<()> {
+    fn flush(&self) -> io::Result<()> {
         let mut cm = self.h.manager.lock().unwrap();
         let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
             io::Error::new(
@@ -330,18 +1382,364 @@ impl Write for TcpStream {
             ))
         }
     }
+
+    fn peer_addr(&self) -> SocketAddr {
+        let (ip, port) = self.quad.src;
+        SocketAddr::new(ip, port)
+    }
+
+    fn local_addr(&self) -> SocketAddr {
+        let (ip, port) = self.quad.dst;
+        SocketAddr::new(ip, port)
+    }
+
+    fn shutdown(&self, how: std::net::Shutdown) -> io::Result<()> {
+        use std::net::Shutdown;
+        let mut cm = self.h.manager.lock().unwrap();
+        let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotConnected, "stream is not connected")
+        })?;
+
+        if let Shutdown::Read | Shutdown::Both = how {
+            c.shutdown_read();
+        }
+        if let Shutdown::Write | Shutdown::Both = how {
+            c.close()?;
+        }
+        drop(cm);
+
+        // readers blocked on this stream should now observe EOF, and writers and lingering drops
+        // that the connection is closing
+        self.h.rcv_var.notify_all();
+        self.h.snd_var.notify_all();
+        Ok(())
+    }
+}
+
+impl Read for TcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.recv(&mut [IoSliceMut::new(buf)], false)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        self.inner.recv(bufs, false)
+    }
+}
+
+impl Write for TcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.send(&[IoSlice::new(buf)])
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        self.inner.send(bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
 }
 
 impl TcpStream {
+    /// Returns the socket address of the remote peer of this connection.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.inner.peer_addr())
+    }
+
+    /// Returns the local socket address of this connection.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.inner.local_addr())
+    }
+
+    /// Receives data without removing it from the queue, so the next read returns it again.
+    ///
+    /// Like [`Read::read`], this blocks until data is available or the peer has closed the
+    /// connection.
+    pub fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.recv(&mut [IoSliceMut::new(buf)], true)
+    }
+
+    /// Shuts down the read, write, or both halves of this connection.
+    ///
+    /// Shutting down the write half sends a FIN once all buffered data has been sent. Shutting
+    /// down the read half discards any unread data, and makes reads return `Ok(0)`; if more data
+    /// arrives after that, the connection is reset.
     pub fn shutdown(&self, how: std::net::Shutdown) -> io::Result<()> {
+        self.inner.shutdown(how)
+    }
+
+    /// Creates a new handle to the same connection.
+    ///
+    /// Reads and writes through either handle operate on the same connection, and the
+    /// connection is only closed once all handles have been dropped.
+    pub fn try_clone(&self) -> io::Result<TcpStream> {
+        Ok(TcpStream {
+            inner: self.inner.clone(),
+        })
+    }
+
+    /// Splits this stream into a read half and a write half that can be moved to different
+    /// threads.
+    ///
+    /// As with [`TcpStream::try_clone`], the connection is only closed once both halves have
+    /// been dropped.
+    pub fn split(self) -> (ReadHalf, WriteHalf) {
+        (
+            ReadHalf {
+                inner: self.inner.clone(),
+            },
+            WriteHalf { inner: self.inner },
+        )
+    }
+
+    /// Sets the `SO_LINGER` behavior used when this stream is dropped.
+    ///
+    /// With `None`, dropping the stream closes the connection gracefully in the background. With
+    /// `Some(d)`, dropping the stream blocks for up to `d` waiting for the peer to acknowledge our
+    /// FIN, and resets the connection if it does not. `Some(Duration::ZERO)` resets the
+    /// connection immediately, discarding any unsent data.
+    pub fn set_linger(&self, linger: Option<Duration>) -> io::Result<()> {
+        let mut cm = self.inner.h.manager.lock().unwrap();
+        let c = cm.connections.get_mut(&self.inner.quad).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotConnected, "stream is not connected")
+        })?;
+        c.linger = linger;
+        Ok(())
+    }
+
+    /// Gets the value of the `SO_LINGER` option on this stream.
+    ///
+    /// See [`TcpStream::set_linger`].
+    pub fn linger(&self) -> io::Result<Option<Duration>> {
+        let cm = self.inner.h.manager.lock().unwrap();
+        let c = cm.connections.get(&self.inner.quad).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotConnected, "stream is not connected")
+        })?;
+        Ok(c.linger)
+    }
+
+    /// Takes the last soft error reported about this connection, like `SO_ERROR`.
+    ///
+    /// Soft errors are ICMP errors, such as a router telling us the peer is unreachable, that may
+    /// well go away, so the connection carries on regardless (RFC 1122 S4.2.3.9).
+    pub fn take_error(&self) -> io::Result<Option<io::Error>> {
+        let mut cm = self.inner.h.manager.lock().unwrap();
+        let c = cm.connections.get_mut(&self.inner.quad).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotConnected, "stream is not connected")
+        })?;
+        Ok(c.take_soft_error()
+            .map(|kind| io::Error::new(kind, "reported by ICMP")))
+    }
+
+    /// The state the connection is in.
+    pub fn state(&self) -> io::Result<State> {
+        let cm = self.inner.h.manager.lock().unwrap();
+        let c = cm.connections.get(&self.inner.quad).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotConnected, "stream is not connected")
+        })?;
+        Ok(c.state())
+    }
+}
+
+/// The read half of a [`TcpStream`], created by [`TcpStream::split`].
+pub struct ReadHalf {
+    inner: Arc<StreamInner>,
+}
+
+impl Read for ReadHalf {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.recv(&mut [IoSliceMut::new(buf)], false)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        self.inner.recv(bufs, false)
+    }
+}
+
+impl ReadHalf {
+    /// Receives data without removing it from the queue.
+    ///
+    /// See [`TcpStream::peek`].
+    pub fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.recv(&mut [IoSliceMut::new(buf)], true)
+    }
+
+    /// Returns the socket address of the remote peer of this connection.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.inner.peer_addr())
+    }
+
+    /// Returns the local socket address of this connection.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.inner.local_addr())
+    }
+}
+
+/// The write half of a [`TcpStream`], created by [`TcpStream::split`].
+pub struct WriteHalf {
+    inner: Arc<StreamInner>,
+}
+
+impl Write for WriteHalf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.send(&[IoSlice::new(buf)])
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        self.inner.send(bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl WriteHalf {
+    /// Returns the socket address of the remote peer of this connection.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.inner.peer_addr())
+    }
+
+    /// Returns the local socket address of this connection.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.inner.local_addr())
+    }
+
+    /// Shuts down the write half of the connection, sending a FIN once all buffered data has
+    /// been sent.
+    pub fn shutdown(&self) -> io::Result<()> {
+        self.inner.shutdown(std::net::Shutdown::Write)
+    }
+}
+
+/// A UDP socket on an [`Interface`], obtained with [`Interface::bind_udp`].
+///
+/// Datagrams too large for the path to their destination, as far as we know it, are refused
+/// rather than fragmented.
+pub struct UdpSocket {
+    port: u16,
+    h: InterfaceHandle,
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        let mut cm = self
+            .h
+            .manager
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        cm.udp.remove(&self.port);
+    }
+}
+
+impl UdpSocket {
+    /// Returns the local socket address of this socket.
+    ///
+    /// Once connected, that is our address of the peer's family. Before that, it is whatever
+    /// [`TcpListener::local_addr`] would say.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        let peer = self.peer_addr().ok();
+        if let Some(ip) = peer.and_then(|peer| self.h.config.local_address(peer.ip())) {
+            return Ok(SocketAddr::new(ip, self.port));
+        }
+        Ok(SocketAddr::new(self.h.config.any_address(), self.port))
+    }
+
+    /// Returns the address this socket is connected to.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        let cm = self.h.manager.lock().unwrap();
+        cm.udp
+            .get(&self.port)
+            .and_then(|socket| socket.peer)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "socket is not connected"))
+    }
+
+    /// Sends to `addr` by default, and only receives from it, from now on. Datagrams from anyone
+    /// else that were already received are still there to be read.
+    pub fn connect(&self, addr: SocketAddr) -> io::Result<()> {
+        self.h.config.local_address(addr.ip()).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                "interface has no address to connect from",
+            )
+        })?;
         let mut cm = self.h.manager.lock().unwrap();
-        let c = cm.connections.get_mut(&self.quad).ok_or_else(|| {
+        let socket = cm.udp.get_mut(&self.port).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::ConnectionAborted, "interface has shut down")
+        })?;
+        socket.peer = Some(addr);
+        socket.error = None;
+        Ok(())
+    }
+
+    /// Queues `buf` as one datagram to `addr`, and returns its length.
+    ///
+    /// The interface must have been given an address of the same family as `addr` with
+    /// [`InterfaceBuilder::address`]. A datagram too large for the path fails like it would on a
+    /// [`std::net::UdpSocket`], with `EMSGSIZE`.
+    pub fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let local = self.h.config.local_address(addr.ip()).ok_or_else(|| {
             io::Error::new(
-                io::ErrorKind::ConnectionAborted,
-                "stream was terminated unexpectedly",
+                io::ErrorKind::AddrNotAvailable,
+                "interface has no address to send from",
             )
         })?;
 
-        c.close()
+        let mut cm = self.h.manager.lock().unwrap();
+        let mtu = cm.path_mtu.get(addr.ip()).unwrap_or(self.h.config.mtu);
+        if buf.len() > udp::max_data(local, mtu) {
+            // what the kernel says, for a datagram that doesn't fit the path and mustn't be
+            // fragmented
+            return Err(io::Error::from_raw_os_error(libc::EMSGSIZE));
+        }
+        let socket = cm.udp.get_mut(&self.port).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::ConnectionAborted, "interface has shut down")
+        })?;
+        socket.queue(addr, buf, self.h.config.send_buffer_size)?;
+        drop(cm);
+        // rather than wait for the packet loop to get round to it
+        if let Some(wake) = &self.h.waker {
+            wake();
+        }
+        Ok(buf.len())
+    }
+
+    /// Queues `buf` as one datagram to the address this socket is connected to.
+    pub fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.send_to(buf, self.peer_addr()?)
+    }
+
+    /// Blocks until a datagram arrives, and reads it into `buf`, returning its length and who
+    /// sent it. Whatever doesn't fit in `buf` is discarded.
+    ///
+    /// If ICMP said a datagram sent by a connected socket was refused, that is returned instead,
+    /// once.
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut cm = self.h.manager.lock().unwrap();
+        loop {
+            let socket = cm.udp.get_mut(&self.port).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::ConnectionAborted, "interface has shut down")
+            })?;
+
+            if let Some(kind) = socket.error.take() {
+                return Err(io::Error::new(
+                    kind,
+                    "datagram was refused, as reported by ICMP",
+                ));
+            }
+
+            if let Some((from, data)) = socket.incoming.pop_front() {
+                let n = std::cmp::min(buf.len(), data.len());
+                buf[..n].copy_from_slice(&data[..n]);
+                return Ok((n, from));
+            }
+
+            cm = self.h.rcv_var.wait(cm).unwrap();
+        }
     }
-}
\ No newline at end of file
+
+    /// Like [`UdpSocket::recv_from`], for a connected socket.
+    pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.recv_from(buf).map(|(n, _)| n)
+    }
+}
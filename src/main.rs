@@ -1,14 +1,32 @@
 use std::io::prelude::*;
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::{io, thread};
 
 fn main() -> io::Result<()> {
-    let mut i = trust::Interface::new()?;
+    let mut builder = trust::InterfaceBuilder::new()
+        .name("tun0")
+        .address(Ipv4Addr::new(192, 168, 0, 2), 24)
+        .host_address(Ipv4Addr::new(192, 168, 0, 1))
+        .address(Ipv6Addr::new(0xfd00, 1, 0, 0, 0, 0, 0, 2), 64)
+        .host_address(Ipv6Addr::new(0xfd00, 1, 0, 0, 0, 0, 0, 1));
+    if let Some(path) = std::env::var_os("TRUST_CAPTURE") {
+        builder = builder.capture(path);
+    }
+    let mut i = builder.build()?;
     eprintln!("created interface");
+    let udp = i.bind_udp(8000)?;
+    thread::spawn(move || {
+        let mut buf = [0; 1500];
+        while let Ok((n, from)) = udp.recv_from(&mut buf) {
+            eprintln!("echoing {}b of udp to {}", n, from);
+            let _ = udp.send_to(&buf[..n], from);
+        }
+    });
     let mut listener = i.bind(8000)?;
     while let Ok(mut stream) = listener.accept() {
-        eprintln!("got connection!");
+        eprintln!("got connection from {}!", stream.peer_addr()?);
         thread::spawn(move || {
-            stream.write(b"hello from trust\n").unwrap();
+            stream.write_all(b"hello from trust\n").unwrap();
             stream.shutdown(std::net::Shutdown::Write).unwrap();
             loop {
                 let mut buf = [0; 512];
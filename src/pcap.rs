@@ -0,0 +1,211 @@
+//! Recording packets in the pcap format, for Wireshark and friends.
+
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::{Clock, Device, SystemClock, Waker};
+
+/// Magic number of a pcap file with microsecond timestamps.
+const MAGIC_MICROS: u32 = 0xa1b2_c3d4;
+/// Magic number of a pcap file with nanosecond timestamps.
+const MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+/// Link type for packets that start with an IPv4 or IPv6 header.
+const LINKTYPE_RAW: u32 = 101;
+/// Link type for packets that start with an IPv4 header.
+const LINKTYPE_IPV4: u32 = 228;
+const SNAPLEN: u32 = 65535;
+
+/// Writes packets to a pcap file.
+pub struct Writer<W: Write> {
+    w: W,
+}
+
+impl<W: Write> Writer<W> {
+    /// Writes the file header to `w`.
+    pub fn new(mut w: W) -> io::Result<Self> {
+        w.write_all(&MAGIC_NANOS.to_le_bytes())?;
+        w.write_all(&2u16.to_le_bytes())?; // version 2.4
+        w.write_all(&4u16.to_le_bytes())?;
+        w.write_all(&0i32.to_le_bytes())?; // timestamps are in UTC
+        w.write_all(&0u32.to_le_bytes())?;
+        w.write_all(&SNAPLEN.to_le_bytes())?;
+        w.write_all(&LINKTYPE_RAW.to_le_bytes())?;
+        Ok(Writer { w })
+    }
+
+    /// Appends `packet`, as seen at `time`.
+    pub fn write_packet(&mut self, time: SystemTime, packet: &[u8]) -> io::Result<()> {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO);
+        let captured = std::cmp::min(packet.len(), SNAPLEN as usize);
+        self.w
+            .write_all(&(since_epoch.as_secs() as u32).to_le_bytes())?;
+        self.w
+            .write_all(&since_epoch.subsec_nanos().to_le_bytes())?;
+        self.w.write_all(&(captured as u32).to_le_bytes())?;
+        self.w.write_all(&(packet.len() as u32).to_le_bytes())?;
+        self.w.write_all(&packet[..captured])
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.w.flush()
+    }
+}
+
+/// Reads packets from a pcap file, such as one written by [`Writer`] or `tshark -F pcap`.
+///
+/// Only files of raw IP packets are supported, which is what is captured on a TUN device.
+pub struct Reader<R: Read> {
+    r: R,
+    big_endian: bool,
+    nanos: bool,
+}
+
+impl<R: Read> Reader<R> {
+    /// Reads and checks the file header from `r`.
+    pub fn new(mut r: R) -> io::Result<Self> {
+        let mut header = [0u8; 24];
+        r.read_exact(&mut header)?;
+        let magic = [header[0], header[1], header[2], header[3]];
+        let (big_endian, nanos) = match (u32::from_le_bytes(magic), u32::from_be_bytes(magic)) {
+            (MAGIC_MICROS, _) => (false, false),
+            (MAGIC_NANOS, _) => (false, true),
+            (_, MAGIC_MICROS) => (true, false),
+            (_, MAGIC_NANOS) => (true, true),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "not a pcap file (pcapng is not supported)",
+                ));
+            }
+        };
+
+        let reader = Reader {
+            r,
+            big_endian,
+            nanos,
+        };
+        let linktype = reader.u32_at(&header, 20);
+        if linktype != LINKTYPE_RAW && linktype != LINKTYPE_IPV4 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported link type {}, expected raw IP", linktype),
+            ));
+        }
+        Ok(reader)
+    }
+
+    fn u32_at(&self, buf: &[u8], at: usize) -> u32 {
+        let bytes = [buf[at], buf[at + 1], buf[at + 2], buf[at + 3]];
+        if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    }
+
+    /// Reads the next packet and when it was seen, or `None` at the end of the file.
+    pub fn read_packet(&mut self) -> io::Result<Option<(SystemTime, Vec<u8>)>> {
+        let mut header = [0u8; 16];
+        match self.r.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+
+        let secs = self.u32_at(&header, 0);
+        let frac = self.u32_at(&header, 4);
+        let nanos = if self.nanos { frac } else { frac * 1000 };
+        let time = UNIX_EPOCH + Duration::new(u64::from(secs), nanos);
+
+        let mut packet = vec![0u8; self.u32_at(&header, 8) as usize];
+        self.r.read_exact(&mut packet)?;
+        Ok(Some((time, packet)))
+    }
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    type Item = io::Result<(SystemTime, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_packet().transpose()
+    }
+}
+
+/// A device that records every packet sent and received through another device.
+///
+/// The recording is flushed whenever the device goes idle, and when it is dropped. If it can't be
+/// written, capturing stops, but packets keep flowing through the device.
+pub struct Capture<D, W: Write> {
+    inner: D,
+    writer: Option<Writer<W>>,
+    clock: Arc<dyn Clock>,
+    /// the wall clock time at which `clock` read `started`, to timestamp packets from
+    epoch: SystemTime,
+    started: Instant,
+}
+
+impl<D: Device, W: Write> Capture<D, W> {
+    pub fn new(inner: D, writer: Writer<W>) -> Self {
+        Capture {
+            inner,
+            writer: Some(writer),
+            clock: Arc::new(SystemClock),
+            epoch: SystemTime::now(),
+            started: Instant::now(),
+        }
+    }
+
+    /// Timestamp packets with `clock` rather than the [`SystemClock`], counting from when this
+    /// is called.
+    pub fn clock(mut self, clock: impl Clock) -> Self {
+        self.started = clock.now();
+        self.epoch = SystemTime::now();
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    fn record(&mut self, packet: &[u8]) {
+        let time = self.epoch + (self.clock.now() - self.started);
+        self.keep_capturing(|writer| writer.write_packet(time, packet));
+    }
+
+    /// Run `f` on the writer, if we are still capturing, and stop if it fails.
+    fn keep_capturing(&mut self, f: impl FnOnce(&mut Writer<W>) -> io::Result<()>) {
+        if let Some(writer) = &mut self.writer
+            && let Err(e) = f(writer)
+        {
+            eprintln!("stopped capturing: {}", e);
+            self.writer = None;
+        }
+    }
+}
+
+impl<D: Device, W: Write + Send + 'static> Device for Capture<D, W> {
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.recv(buf)?;
+        self.record(&buf[..n]);
+        Ok(n)
+    }
+
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.record(buf);
+        self.inner.send(buf)
+    }
+
+    fn poll(&mut self, timeout: Duration) -> io::Result<bool> {
+        let ready = self.inner.poll(timeout)?;
+        if !ready {
+            self.keep_capturing(Writer::flush);
+        }
+        Ok(ready)
+    }
+
+    fn checksum_verified(&self) -> bool {
+        self.inner.checksum_verified()
+    }
+
+    fn waker(&self) -> Option<Waker> {
+        self.inner.waker()
+    }
+}
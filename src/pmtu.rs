@@ -0,0 +1,204 @@
+//! Path MTU discovery: remembering what ICMP tells us about the paths to our peers (RFC 1191,
+//! RFC 8201), and finding out for ourselves on paths where those messages get lost (RFC 4821).
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// How long what we learned about a path holds, after which we try the link MTU again in case
+/// the path has changed (RFC 1191 S6.3).
+const AGE: Duration = Duration::from_secs(10 * 60);
+/// How many destinations we remember at most.
+const MAX_ENTRIES: usize = 1024;
+/// The smallest path MTU we believe an IPv4 router about. Anything down to 68 is legal, but
+/// segments that small are mostly a way for an attacker to slow us down (RFC 5927 S7.2).
+const MIN_V4: usize = 552;
+/// The smallest MTU an IPv6 link may have (RFC 8200 S5).
+const MIN_V6: usize = 1280;
+/// Common MTUs, for routers that don't say what the next hop takes (RFC 1191 S7).
+const PLATEAUS: [usize; 10] = [32000, 17914, 8166, 4352, 2002, 1492, 1006, 508, 296, 68];
+
+/// Where a search starts: small enough for nearly any path, yet not too wasteful (RFC 4821 S7.2).
+const BASE_V4: usize = 1024;
+const BASE_V6: usize = 1280;
+/// A search stops once the largest packet that got through and the smallest that didn't are this
+/// close.
+const PRECISION: usize = 16;
+/// How long a finished search holds before we look for a larger MTU again (RFC 4821 S7.7).
+const RESEARCH_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// How many retransmission timeouts in a row make us suspect a black hole: a path that drops
+/// large packets without an ICMP message to say so (RFC 2923 S2.1).
+const BLACK_HOLE_TIMEOUTS: u32 = 3;
+
+/// The smallest path MTU we accept for paths to `addr`.
+pub(crate) fn minimum(addr: IpAddr) -> usize {
+    match addr {
+        IpAddr::V4(_) => MIN_V4,
+        IpAddr::V6(_) => MIN_V6,
+    }
+}
+
+/// A guess at the MTU of a path that couldn't carry a packet of `len` bytes.
+pub(crate) fn plateau_below(len: usize) -> usize {
+    PLATEAUS.into_iter().find(|&p| p < len).unwrap_or(68)
+}
+
+/// The path MTUs ICMP has told us about, for each destination.
+#[derive(Default)]
+pub(crate) struct Cache {
+    entries: HashMap<IpAddr, (usize, Instant)>,
+}
+
+impl Cache {
+    /// The path MTU to `dst`, if it is any less than that of the link.
+    pub(crate) fn get(&self, dst: IpAddr) -> Option<usize> {
+        self.entries.get(&dst).map(|&(mtu, _)| mtu)
+    }
+
+    /// Remembers that packets to `dst` must fit in `mtu`.
+    pub(crate) fn update(&mut self, dst: IpAddr, mtu: usize, now: Instant) {
+        if !self.entries.contains_key(&dst) && self.entries.len() >= MAX_ENTRIES {
+            // make room by forgetting the oldest, which would be the first to go anyway
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, updated))| *updated)
+                .map(|(dst, _)| *dst);
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(dst, (mtu, now));
+    }
+
+    /// Forgets what we learned too long ago.
+    pub(crate) fn expire(&mut self, now: Instant) {
+        self.entries.retain(|_, (_, updated)| now < *updated + AGE);
+    }
+}
+
+/// Packetization layer path MTU discovery for one connection (RFC 4821): sending probes, segments
+/// larger than we know get through, and seeing whether they are acknowledged.
+pub(crate) struct Search {
+    /// whether we are searching at all, rather than trusting the link and ICMP
+    active: bool,
+    /// the largest packet we know gets through, which is as large as we send
+    low: usize,
+    /// the largest packet that might, so that larger probes are pointless
+    high: usize,
+    /// the largest packet the link takes
+    link: usize,
+    /// where a search starts
+    base: usize,
+    /// the probe in flight: the sequence number just past it, and its size
+    probe: Option<(u32, usize)>,
+    /// when to search again, once we've found what we were looking for
+    again: Option<Instant>,
+    /// retransmission timeouts since something was last acknowledged
+    timeouts: u32,
+}
+
+impl Search {
+    /// Starts looking for the path MTU to `addr` over a link of `link` bytes right away if
+    /// `active`, and otherwise only once we seem to have hit a black hole.
+    pub(crate) fn new(addr: IpAddr, link: usize, active: bool) -> Self {
+        let base = std::cmp::min(base(addr), link);
+        Search {
+            active,
+            low: if active { base } else { link },
+            high: link,
+            link,
+            base,
+            probe: None,
+            again: None,
+            timeouts: 0,
+        }
+    }
+
+    /// The largest packet we should send, as far as the search is concerned.
+    pub(crate) fn mtu(&self) -> usize {
+        self.low
+    }
+
+    /// The size of the next probe to send, if it's time for one. Probes are never larger than
+    /// `limit`, which is what ICMP and the peer allow.
+    pub(crate) fn probe_size(&mut self, limit: usize, now: Instant) -> Option<usize> {
+        if !self.active || self.probe.is_some() {
+            return None;
+        }
+        if self.again.is_some_and(|again| now >= again) {
+            self.again = None;
+            self.high = self.link;
+        }
+        let high = std::cmp::min(self.high, limit);
+        if high < self.low + PRECISION {
+            return None;
+        }
+        Some((self.low + high).div_ceil(2))
+    }
+
+    /// Notes that a probe of `size` bytes was sent, ending just before `end`.
+    pub(crate) fn on_probe(&mut self, end: u32, size: usize) {
+        self.probe = Some((end, size));
+    }
+
+    /// Notes that everything before `ackn` has been acknowledged.
+    pub(crate) fn on_ack(&mut self, ackn: u32, now: Instant) {
+        self.timeouts = 0;
+        let Some((end, size)) = self.probe else {
+            return;
+        };
+        if !crate::seq::wrapping_lt(ackn, end) {
+            // the probe got through
+            self.probe = None;
+            self.low = size;
+            self.on_result(now);
+        }
+    }
+
+    /// Notes that nothing past `una` was acknowledged in time, and returns whether that was the
+    /// probe getting lost, rather than anything that says the network is congested
+    /// (RFC 4821 S7.6.1).
+    pub(crate) fn on_timeout(&mut self, una: u32, now: Instant) -> bool {
+        if let Some((end, size)) = self.probe.take()
+            && crate::seq::wrapping_lt(una, end)
+        {
+            self.high = size - 1;
+            self.on_result(now);
+            return true;
+        }
+
+        self.timeouts += 1;
+        if self.timeouts >= BLACK_HOLE_TIMEOUTS && self.low > self.base {
+            // whatever is being lost may well be too large for the path, so fall back to what
+            // surely fits, and search upwards from there
+            self.active = true;
+            self.high = self.low - 1;
+            self.low = self.base;
+            self.again = None;
+            self.timeouts = 0;
+        }
+        false
+    }
+
+    /// Notes that ICMP says packets larger than `mtu` don't get through.
+    pub(crate) fn on_packet_too_big(&mut self, mtu: usize) {
+        self.probe = None;
+        self.high = std::cmp::min(self.high, mtu);
+        self.low = std::cmp::min(self.low, mtu);
+    }
+
+    fn on_result(&mut self, now: Instant) {
+        if self.high < self.low + PRECISION {
+            self.again = Some(now + RESEARCH_INTERVAL);
+        }
+    }
+}
+
+/// Where a search for the MTU of a path to `addr` starts.
+fn base(addr: IpAddr) -> usize {
+    match addr {
+        IpAddr::V4(_) => BASE_V4,
+        IpAddr::V6(_) => BASE_V6,
+    }
+}
@@ -0,0 +1,80 @@
+//! Sequence number arithmetic (RFC 1982, as TCP uses it).
+//!
+//! Sequence numbers wrap around at 2^32, so they can only be compared when they are known to be
+//! close to each other: within 2^31 of each other, `a` comes before `b` if `b - a` is "positive".
+
+/// Whether `lhs` comes strictly before `rhs`.
+///
+/// Numbers exactly 2^31 apart are incomparable, so neither comes before the other.
+pub fn wrapping_lt(lhs: u32, rhs: u32) -> bool {
+    // From RFC1323:
+    //     TCP determines if a data segment is "old" or "new" by testing
+    //     whether its sequence number is within 2**31 bytes of the left edge
+    //     of the window, and if it is not, discarding the data as "old".  To
+    //     insure that new data is never mistakenly considered old and vice-
+    //     versa, the left edge of the sender's window has to be at most
+    //     2**31 away from the right edge of the receiver's window.
+    lhs.wrapping_sub(rhs) > (1 << 31)
+}
+
+/// Whether `x` lies strictly between `start` and `end`.
+pub fn is_between_wrapped(start: u32, x: u32, end: u32) -> bool {
+    wrapping_lt(start, x) && wrapping_lt(x, end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    const HALF: u32 = 1 << 31;
+
+    /// Sequence numbers anywhere, but mostly around where they wrap and where comparisons flip.
+    fn seq() -> impl Strategy<Value = u32> {
+        prop_oneof![
+            any::<u32>(),
+            0u32..16,
+            HALF - 16..HALF + 16,
+            u32::MAX - 16..=u32::MAX,
+        ]
+    }
+
+    /// Distances that comparisons should get right, including the edges of what they can tell
+    /// apart.
+    fn distance() -> impl Strategy<Value = u32> {
+        prop_oneof![1..HALF, Just(1), HALF - 16..HALF]
+    }
+
+    proptest! {
+        #[test]
+        fn nothing_comes_before_itself(a in seq()) {
+            prop_assert!(!wrapping_lt(a, a));
+        }
+
+        #[test]
+        fn lt_is_antisymmetric(a in seq(), b in seq()) {
+            prop_assert!(!(wrapping_lt(a, b) && wrapping_lt(b, a)));
+        }
+
+        #[test]
+        fn lt_within_half_the_space(a in seq(), d in distance()) {
+            prop_assert!(wrapping_lt(a, a.wrapping_add(d)));
+            prop_assert!(!wrapping_lt(a.wrapping_add(d), a));
+            prop_assert!(wrapping_lt(a.wrapping_sub(d), a));
+        }
+
+        #[test]
+        fn half_the_space_apart_is_incomparable(a in seq()) {
+            prop_assert!(!wrapping_lt(a, a.wrapping_add(HALF)));
+            prop_assert!(!wrapping_lt(a.wrapping_add(HALF), a));
+        }
+
+        #[test]
+        fn between_is_strict(start in seq(), i in 0..HALF, j in 0..HALF) {
+            prop_assert_eq!(
+                is_between_wrapped(start, start.wrapping_add(i), start.wrapping_add(j)),
+                0 < i && i < j
+            );
+        }
+    }
+}
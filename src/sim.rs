@@ -0,0 +1,437 @@
+//! Simulated networks, for running several interfaces in one process.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::{
+    Clock, Device, Interface, InterfaceBuilder, MemoryDevice, SystemClock, VirtualClock, Waker,
+    pcap,
+};
+
+pub mod script;
+
+/// A point-to-point link between two interfaces in the same process.
+///
+/// Packets sent by one interface are delivered to the other through in-memory queues, so a
+/// client and a server can talk to each other without any TUN device:
+///
+/// ```
+/// use std::net::Ipv4Addr;
+/// use trust::{sim::Link, InterfaceBuilder};
+///
+/// # fn main() -> std::io::Result<()> {
+/// let (mut server, mut client) = Link::new().build(
+///     InterfaceBuilder::new().address(Ipv4Addr::new(10, 0, 0, 1), 24),
+///     InterfaceBuilder::new().address(Ipv4Addr::new(10, 0, 0, 2), 24),
+/// )?;
+/// let listener = server.bind(80)?;
+/// let stream = client.connect("10.0.0.1:80".parse().unwrap())?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Default)]
+pub struct Link {
+    impairments: Option<Impairments>,
+    clock: Option<Arc<dyn Clock>>,
+}
+
+impl Link {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Impair the packets sent in both directions.
+    ///
+    /// Each direction gets its own random number generator, so the two don't see the same
+    /// pattern of loss.
+    pub fn impair(mut self, impairments: Impairments) -> Self {
+        self.impairments = Some(impairments);
+        self
+    }
+
+    /// Use `clock` for both interfaces, and for delaying packets on the link.
+    pub fn clock(mut self, clock: impl Clock) -> Self {
+        self.clock = Some(Arc::new(clock));
+        self
+    }
+
+    /// Creates an interface from each builder, with the two connected by this link.
+    ///
+    /// The builders' device names are ignored, as no device is created.
+    pub fn build(
+        self,
+        a: InterfaceBuilder,
+        b: InterfaceBuilder,
+    ) -> io::Result<(Interface, Interface)> {
+        let (nic_a, nic_b) = MemoryDevice::pair();
+        let clock = self.clock.unwrap_or_else(|| Arc::new(SystemClock));
+        let (a, b) = (a.clock(clock.clone()), b.clock(clock.clone()));
+        match self.impairments {
+            Some(impairments) => {
+                let mut reverse = impairments.clone();
+                reverse.seed = !reverse.seed;
+                Ok((
+                    a.build_with_device(Impaired::new(nic_a, impairments).clock(clock.clone()))?,
+                    b.build_with_device(Impaired::new(nic_b, reverse).clock(clock))?,
+                ))
+            }
+            None => Ok((a.build_with_device(nic_a)?, b.build_with_device(nic_b)?)),
+        }
+    }
+}
+
+/// The ways in which [`Impaired`] mistreats the packets sent through it.
+///
+/// All randomness comes from a generator seeded with [`Impairments::seed`], so the same packets
+/// sent in the same order are mistreated the same way every time.
+#[derive(Clone, Debug, Default)]
+pub struct Impairments {
+    seed: u64,
+    loss: f64,
+    delay: Duration,
+    jitter: Duration,
+    reorder: f64,
+    reorder_delay: Duration,
+    duplicate: f64,
+    corrupt: f64,
+    bandwidth: Option<u64>,
+}
+
+impl Impairments {
+    /// No impairments at all.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Drop each packet with probability `p`.
+    pub fn loss(mut self, p: f64) -> Self {
+        self.loss = p;
+        self
+    }
+
+    /// Delay every packet by `delay`.
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Delay every packet by an additional random amount of up to `jitter`.
+    pub fn jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Hold back each packet with probability `p` for an extra `delay`, so that the packets sent
+    /// after it overtake it.
+    pub fn reorder(mut self, p: f64, delay: Duration) -> Self {
+        self.reorder = p;
+        self.reorder_delay = delay;
+        self
+    }
+
+    /// Send each packet twice with probability `p`.
+    pub fn duplicate(mut self, p: f64) -> Self {
+        self.duplicate = p;
+        self
+    }
+
+    /// Flip a random bit of each packet with probability `p`.
+    pub fn corrupt(mut self, p: f64) -> Self {
+        self.corrupt = p;
+        self
+    }
+
+    /// Limit the link to `bytes_per_sec`, queueing packets as they wait their turn.
+    pub fn bandwidth(mut self, bytes_per_sec: u64) -> Self {
+        self.bandwidth = Some(bytes_per_sec);
+        self
+    }
+}
+
+/// A small, seedable PRNG (xorshift64*), good enough for deciding the fate of packets.
+#[derive(Debug)]
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // xorshift gets stuck at zero; mix the seed so that nearby seeds diverge quickly
+        Rng(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// A uniformly distributed number in `[0, 1)`.
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&mut self, p: f64) -> bool {
+        p > 0.0 && self.next_f64() < p
+    }
+}
+
+/// A device that applies [`Impairments`] to the packets sent through another device.
+///
+/// Received packets are passed through untouched; impair the other end as well to mistreat
+/// traffic in both directions. Delayed packets are released from [`Device::poll`] and
+/// [`Device::send`], which the packet loop calls often enough for that to be timely.
+pub struct Impaired<D> {
+    inner: D,
+    impairments: Impairments,
+    rng: Rng,
+    /// packets waiting to go out, by when they should, and in the order they were sent otherwise
+    queue: BinaryHeap<Reverse<(Instant, u64, Vec<u8>)>>,
+    sent: u64,
+    /// when the link will be done transmitting what it has already been given
+    busy_until: Option<Instant>,
+    clock: Arc<dyn Clock>,
+}
+
+impl<D: Device> Impaired<D> {
+    pub fn new(inner: D, impairments: Impairments) -> Self {
+        Impaired {
+            inner,
+            rng: Rng::new(impairments.seed),
+            impairments,
+            queue: BinaryHeap::new(),
+            sent: 0,
+            busy_until: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Measure delays with `clock` rather than the [`SystemClock`].
+    pub fn clock(mut self, clock: impl Clock) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Send on all the packets that are due.
+    fn flush(&mut self) -> io::Result<()> {
+        let now = self.clock.now();
+        while let Some(Reverse((at, _, _))) = self.queue.peek() {
+            if *at > now {
+                break;
+            }
+            let Reverse((_, _, packet)) = self.queue.pop().unwrap();
+            self.inner.send(&packet)?;
+        }
+        Ok(())
+    }
+
+    fn enqueue(&mut self, mut packet: Vec<u8>) {
+        let imp = &self.impairments;
+        if !packet.is_empty() && self.rng.chance(imp.corrupt) {
+            let bit = self.rng.next_u64() % (packet.len() as u64 * 8);
+            packet[(bit / 8) as usize] ^= 1 << (bit % 8);
+        }
+
+        let now = self.clock.now();
+        let mut at = now;
+        if let Some(bandwidth) = imp.bandwidth {
+            let start = self
+                .busy_until
+                .map_or(now, |busy_until| std::cmp::max(now, busy_until));
+            at = start + Duration::from_secs_f64(packet.len() as f64 / bandwidth as f64);
+            self.busy_until = Some(at);
+        }
+        at += imp.delay + imp.jitter.mul_f64(self.rng.next_f64());
+        if self.rng.chance(imp.reorder) {
+            at += imp.reorder_delay;
+        }
+
+        self.queue.push(Reverse((at, self.sent, packet)));
+        self.sent += 1;
+    }
+}
+
+impl<D: Device> Device for Impaired<D> {
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.flush()?;
+        self.inner.recv(buf)
+    }
+
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.rng.chance(self.impairments.loss) {
+            self.enqueue(buf.to_vec());
+            if self.rng.chance(self.impairments.duplicate) {
+                self.enqueue(buf.to_vec());
+            }
+        }
+        self.flush()?;
+        Ok(buf.len())
+    }
+
+    fn poll(&mut self, timeout: Duration) -> io::Result<bool> {
+        self.flush()?;
+        // wake up in time to release the next delayed packet
+        let timeout = match self.queue.peek() {
+            Some(Reverse((at, _, _))) => {
+                std::cmp::min(timeout, at.saturating_duration_since(self.clock.now()))
+            }
+            None => timeout,
+        };
+        let ready = self.inner.poll(timeout)?;
+        self.flush()?;
+        Ok(ready)
+    }
+
+    fn waker(&self) -> Option<Waker> {
+        self.inner.waker()
+    }
+}
+
+/// Replays a recorded conversation against an interface, and checks that it answers the same.
+///
+/// Packets in the recording that are addressed to the interface are fed to it as if they came
+/// from the device, and all others are expected to be sent by it. A [`VirtualClock`] is moved
+/// forward along with the recorded timestamps, so timers fire as they did when recording.
+///
+/// The packets the interface sends have to match the recording byte for byte, but not in order,
+/// since application threads may race with the packet loop.
+pub struct Replay {
+    address: IpAddr,
+    packets: Vec<(SystemTime, Vec<u8>)>,
+    timeout: Duration,
+}
+
+impl Replay {
+    /// Replays `packets` to an interface with `address`.
+    pub fn new(address: impl Into<IpAddr>, packets: Vec<(SystemTime, Vec<u8>)>) -> Self {
+        Replay {
+            address: address.into(),
+            packets,
+            timeout: Duration::from_secs(1),
+        }
+    }
+
+    /// Replays the pcap file at `path` to an interface with `address`.
+    pub fn from_file(address: impl Into<IpAddr>, path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = io::BufReader::new(File::open(path)?);
+        let packets = pcap::Reader::new(file)?.collect::<io::Result<_>>()?;
+        Ok(Replay::new(address, packets))
+    }
+
+    /// Sets how long to wait for each expected packet. Defaults to one second.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Builds an interface with `builder`, lets `setup` start the application on it, and replays
+    /// the recording.
+    ///
+    /// The builder's address is replaced by the one being replayed to, and its clock by a
+    /// [`VirtualClock`]. Whatever `setup` returns, such as a listener, is kept alive until the
+    /// replay is done.
+    pub fn run<T>(
+        &self,
+        builder: InterfaceBuilder,
+        setup: impl FnOnce(&mut Interface) -> T,
+    ) -> io::Result<()> {
+        let clock = VirtualClock::new();
+        let (nic, mut peer) = MemoryDevice::pair();
+        let mut iface = builder
+            .address(self.address, if self.address.is_ipv4() { 32 } else { 128 })
+            .clock(clock.clone())
+            .build_with_device(nic)?;
+        let _app = setup(&mut iface);
+
+        let mismatch = |what: String| io::Error::other(what);
+        let mut then = self.packets.first().map(|(time, _)| *time);
+        let mut unexpected: Vec<Vec<u8>> = Vec::new();
+        let mut buf = vec![0u8; 65535];
+        for (i, (time, packet)) in self.packets.iter().enumerate() {
+            if let Some(elapsed) = then.and_then(|then| time.duration_since(then).ok()) {
+                clock.advance(elapsed);
+                then = Some(*time);
+            }
+
+            let Some(ip) = crate::ip::Packet::parse(packet) else {
+                return Err(mismatch(format!("packet {} in the recording is not IP", i)));
+            };
+            if ip.dst == self.address {
+                peer.send(packet)?;
+                continue;
+            }
+
+            // we sent this, so wait for it to come out again
+            let deadline = Instant::now() + self.timeout;
+            while !unexpected.contains(packet) {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left.is_zero() || !peer.poll(left)? {
+                    return Err(mismatch(format!(
+                        "packet {}: expected {}, but got {:?}",
+                        i,
+                        describe(packet),
+                        unexpected.iter().map(|p| describe(p)).collect::<Vec<_>>(),
+                    )));
+                }
+                let n = peer.recv(&mut buf)?;
+                unexpected.push(buf[..n].to_vec());
+            }
+            let matched = unexpected.iter().position(|p| p == packet).unwrap();
+            unexpected.remove(matched);
+        }
+
+        // and then nothing else
+        while peer.poll(self.timeout / 10)? {
+            let n = peer.recv(&mut buf)?;
+            unexpected.push(buf[..n].to_vec());
+        }
+        if !unexpected.is_empty() {
+            return Err(mismatch(format!(
+                "unexpected packets at the end of the replay: {:?}",
+                unexpected.iter().map(|p| describe(p)).collect::<Vec<_>>(),
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// A one-line summary of a TCP/IP packet, for telling packets apart in error messages.
+fn describe(packet: &[u8]) -> String {
+    let Some(ip) = crate::ip::Packet::parse(packet) else {
+        return format!("{} bytes of garbage", packet.len());
+    };
+    let Ok(tcph) = etherparse::TcpHeaderSlice::from_slice(ip.payload) else {
+        return format!("{} -> {} protocol {}", ip.src, ip.dst, ip.protocol);
+    };
+    let flags: Vec<&str> = [
+        (tcph.syn(), "SYN"),
+        (tcph.ack(), "ACK"),
+        (tcph.fin(), "FIN"),
+        (tcph.rst(), "RST"),
+        (tcph.psh(), "PSH"),
+    ]
+    .iter()
+    .filter(|(set, _)| *set)
+    .map(|(_, name)| *name)
+    .collect();
+    format!(
+        "{} -> {} [{}] seq={} ack={} win={} len={}",
+        SocketAddr::new(ip.src, tcph.source_port()),
+        SocketAddr::new(ip.dst, tcph.destination_port()),
+        flags.join(", "),
+        tcph.sequence_number(),
+        tcph.acknowledgment_number(),
+        tcph.window_size(),
+        ip.payload.len() - tcph.slice().len(),
+    )
+}
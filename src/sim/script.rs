@@ -0,0 +1,569 @@
+//! Scripted conformance tests, in the spirit of [packetdrill].
+//!
+//! A script is a list of timed events, one per line:
+//!
+//! ```text
+//! // the peer opens a connection to a listening socket
+//! 0     listen 8000
+//! 0.1   < S 0:0(0) win 65535
+//! +0    > S. 0:0(0) ack 1 win 1024
+//! +0.1  < . 1:1(0) ack 1 win 65535
+//! +0    accept
+//! ```
+//!
+//! Every line starts with a time in seconds, either absolute or relative to the previous line
+//! with a leading `+`. The [`VirtualClock`] is moved forward to that time before the line runs,
+//! after checking that the stack has not sent anything the script did not expect.
+//!
+//! `<` injects a segment from the peer, and `>` expects the stack to send one. Segments are
+//! written as flags (`S`, `F`, `R`, `P`, and `.` for ACK), then `seq:end(len)`, then optionally
+//! `ack N` and `win N`. Sequence numbers are absolute; the stack always starts from 0. The
+//! acknowledgment number and window of an expected segment are only checked if given.
+//!
+//! Anything else is a call made by the application, on a thread of its own so that it can block:
+//! `listen PORT`, `accept`, `connect PORT`, `write LEN`, `read LEN` (where a length of 0 expects
+//! end of file), `shutdown` (for writing) and `close`. A call can end in `= ErrorKind`, such as
+//! `= ConnectionReset`, if it should fail. Lines that start with `@` change the interface's
+//! settings before the script starts: `@mtu`, `@send_buffer_size` and `@recv_buffer_size`.
+//!
+//! The stack is at 10.0.0.1, and the peer at 10.0.0.2, port 40000 unless it is connected to.
+//!
+//! [packetdrill]: https://github.com/google/packetdrill
+
+use std::io::{self, prelude::*};
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::describe;
+use crate::{
+    Device, Interface, InterfaceBuilder, MemoryDevice, TcpListener, TcpStream, VirtualClock,
+};
+
+const LOCAL: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const REMOTE: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+const REMOTE_PORT: u16 = 40000;
+
+/// How long to wait, in real time, for something the script expects to happen.
+const TIMEOUT: Duration = Duration::from_secs(1);
+/// How long to give a call, in real time, to return before the script moves on without it, since
+/// it may well be waiting for the peer.
+const CALL_TIMEOUT: Duration = Duration::from_millis(20);
+
+/// A parsed script, ready to be run.
+#[derive(Debug)]
+pub struct Script {
+    builder: InterfaceBuilder,
+    lines: Vec<Line>,
+}
+
+#[derive(Debug)]
+struct Line {
+    number: usize,
+    /// since the start of the script
+    time: Duration,
+    event: Event,
+}
+
+#[derive(Debug)]
+enum Event {
+    Inject(Segment),
+    Expect(Segment),
+    Call(Call, Option<io::ErrorKind>),
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Segment {
+    syn: bool,
+    fin: bool,
+    rst: bool,
+    psh: bool,
+    ack: bool,
+    seq: u32,
+    len: u32,
+    ackn: Option<u32>,
+    win: Option<u16>,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Call {
+    Listen(u16),
+    Accept,
+    Connect(u16),
+    Write(usize),
+    Read(usize),
+    Shutdown,
+    Close,
+}
+
+fn parse_error(number: usize, what: impl std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("line {}: {}", number, what),
+    )
+}
+
+fn parse_num<T: std::str::FromStr>(number: usize, word: Option<&str>) -> io::Result<T> {
+    let word = word.ok_or_else(|| parse_error(number, "expected a number"))?;
+    word.parse()
+        .map_err(|_| parse_error(number, format!("expected a number, got `{}`", word)))
+}
+
+/// Parses a time in seconds, which must be finite and not negative.
+fn parse_time(number: usize, word: &str) -> io::Result<Duration> {
+    let secs: f64 = parse_num(number, Some(word))?;
+    if !secs.is_finite() || secs < 0.0 {
+        return Err(parse_error(number, format!("`{}` is not a time", word)));
+    }
+    Duration::try_from_secs_f64(secs)
+        .map_err(|_| parse_error(number, format!("`{}` is too long", word)))
+}
+
+fn parse_error_kind(number: usize, name: &str) -> io::Result<io::ErrorKind> {
+    use io::ErrorKind::*;
+    Ok(match name {
+        "ConnectionRefused" => ConnectionRefused,
+        "ConnectionReset" => ConnectionReset,
+        "ConnectionAborted" => ConnectionAborted,
+        "NotConnected" => NotConnected,
+        "BrokenPipe" => BrokenPipe,
+        "WouldBlock" => WouldBlock,
+        "TimedOut" => TimedOut,
+        _ => {
+            return Err(parse_error(
+                number,
+                format!("unknown error kind `{}`", name),
+            ));
+        }
+    })
+}
+
+impl Segment {
+    fn parse<'a>(number: usize, mut words: impl Iterator<Item = &'a str>) -> io::Result<Self> {
+        let mut segment = Segment::default();
+        for flag in words.next().unwrap_or_default().chars() {
+            match flag {
+                'S' => segment.syn = true,
+                'F' => segment.fin = true,
+                'R' => segment.rst = true,
+                'P' => segment.psh = true,
+                '.' => segment.ack = true,
+                _ => return Err(parse_error(number, format!("unknown flag `{}`", flag))),
+            }
+        }
+
+        // seq:end(len)
+        let range = words.next().unwrap_or_default();
+        let (seq, rest) = range.split_once(':').ok_or_else(|| {
+            parse_error(number, format!("expected seq:end(len), got `{}`", range))
+        })?;
+        let (end, len) = rest
+            .strip_suffix(')')
+            .and_then(|rest| rest.split_once('('))
+            .ok_or_else(|| {
+                parse_error(number, format!("expected seq:end(len), got `{}`", range))
+            })?;
+        segment.seq = parse_num(number, Some(seq))?;
+        segment.len = parse_num(number, Some(len))?;
+        if parse_num::<u32>(number, Some(end))? != segment.seq.wrapping_add(segment.len) {
+            return Err(parse_error(number, format!("`{}` is inconsistent", range)));
+        }
+
+        while let Some(word) = words.next() {
+            match word {
+                "ack" => segment.ackn = Some(parse_num(number, words.next())?),
+                "win" => segment.win = Some(parse_num(number, words.next())?),
+                _ => return Err(parse_error(number, format!("unexpected `{}`", word))),
+            }
+        }
+        if segment.ackn.is_some() && !segment.ack {
+            return Err(parse_error(number, "ack number given without the ACK flag"));
+        }
+        Ok(segment)
+    }
+
+    fn from_packet(packet: &[u8]) -> Option<(u16, u16, Self)> {
+        let iph = etherparse::Ipv4HeaderSlice::from_slice(packet).ok()?;
+        let tcph = etherparse::TcpHeaderSlice::from_slice(&packet[iph.slice().len()..]).ok()?;
+        let len = packet.len() - iph.slice().len() - tcph.slice().len();
+        let segment = Segment {
+            syn: tcph.syn(),
+            fin: tcph.fin(),
+            rst: tcph.rst(),
+            psh: tcph.psh(),
+            ack: tcph.ack(),
+            seq: tcph.sequence_number(),
+            len: len as u32,
+            ackn: Some(tcph.acknowledgment_number()),
+            win: Some(tcph.window_size()),
+        };
+        Some((tcph.source_port(), tcph.destination_port(), segment))
+    }
+
+    /// Whether `actual` is what this expects.
+    fn matches(&self, actual: &Segment) -> bool {
+        (
+            self.syn, self.fin, self.rst, self.psh, self.ack, self.seq, self.len,
+        ) == (
+            actual.syn, actual.fin, actual.rst, actual.psh, actual.ack, actual.seq, actual.len,
+        ) && (self.ackn.is_none() || self.ackn == actual.ackn)
+            && (self.win.is_none() || self.win == actual.win)
+    }
+
+    fn to_packet(&self, src_port: u16, dst_port: u16) -> Vec<u8> {
+        let mut builder = etherparse::PacketBuilder::ipv4(REMOTE.octets(), LOCAL.octets(), 64).tcp(
+            src_port,
+            dst_port,
+            self.seq,
+            self.win.unwrap_or(u16::MAX),
+        );
+        if self.syn {
+            builder = builder.syn();
+        }
+        if self.fin {
+            builder = builder.fin();
+        }
+        if self.rst {
+            builder = builder.rst();
+        }
+        if self.psh {
+            builder = builder.psh();
+        }
+        if self.ack {
+            builder = builder.ack(self.ackn.unwrap_or(0));
+        }
+        let payload = vec![b'x'; self.len as usize];
+        let mut packet = Vec::with_capacity(builder.size(payload.len()));
+        builder
+            .write(&mut packet, &payload)
+            .expect("writing to a Vec cannot fail");
+        packet
+    }
+}
+
+impl std::fmt::Display for Segment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (set, flag) in [
+            (self.syn, 'S'),
+            (self.fin, 'F'),
+            (self.rst, 'R'),
+            (self.psh, 'P'),
+            (self.ack, '.'),
+        ] {
+            if set {
+                write!(f, "{}", flag)?;
+            }
+        }
+        let end = self.seq.wrapping_add(self.len);
+        write!(f, " {}:{}({})", self.seq, end, self.len)?;
+        if let Some(ackn) = self.ackn {
+            write!(f, " ack {}", ackn)?;
+        }
+        if let Some(win) = self.win {
+            write!(f, " win {}", win)?;
+        }
+        Ok(())
+    }
+}
+
+impl Call {
+    fn parse<'a>(
+        number: usize,
+        name: &str,
+        mut words: impl Iterator<Item = &'a str>,
+    ) -> io::Result<Self> {
+        Ok(match name {
+            "listen" => Call::Listen(parse_num(number, words.next())?),
+            "accept" => Call::Accept,
+            "connect" => Call::Connect(parse_num(number, words.next())?),
+            "write" => Call::Write(parse_num(number, words.next())?),
+            "read" => Call::Read(parse_num(number, words.next())?),
+            "shutdown" => Call::Shutdown,
+            "close" => Call::Close,
+            _ => return Err(parse_error(number, format!("unknown call `{}`", name))),
+        })
+    }
+}
+
+impl Script {
+    /// Parses a script.
+    pub fn parse(source: &str) -> io::Result<Self> {
+        let mut builder = InterfaceBuilder::new().address(LOCAL, 24);
+        let mut lines = Vec::new();
+        let mut time = Duration::ZERO;
+        for (i, line) in source.lines().enumerate() {
+            let number = i + 1;
+            let line = line.split("//").next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let mut words = line.split_whitespace();
+            let first = words.next().unwrap_or_default();
+            if let Some(option) = first.strip_prefix('@') {
+                if !lines.is_empty() {
+                    return Err(parse_error(
+                        number,
+                        "settings must come before the first event",
+                    ));
+                }
+                let value = parse_num(number, words.next())?;
+                builder = match option {
+                    "mtu" => builder.mtu(value),
+                    "send_buffer_size" => builder.send_buffer_size(value),
+                    "recv_buffer_size" => builder.recv_buffer_size(value),
+                    _ => return Err(parse_error(number, format!("unknown setting `{}`", option))),
+                };
+                continue;
+            }
+
+            match first.strip_prefix('+') {
+                Some(delta) => {
+                    time = time
+                        .checked_add(parse_time(number, delta)?)
+                        .ok_or_else(|| parse_error(number, "time runs out"))?;
+                }
+                None => {
+                    let at = parse_time(number, first)?;
+                    if at < time {
+                        return Err(parse_error(number, "time goes backwards"));
+                    }
+                    time = at;
+                }
+            }
+
+            let event = match words.next() {
+                Some("<") => Event::Inject(Segment::parse(number, words)?),
+                Some(">") => Event::Expect(Segment::parse(number, words)?),
+                Some(name) => {
+                    let mut words: Vec<&str> = words.collect();
+                    let error = match words.iter().position(|&w| w == "=") {
+                        Some(at) => {
+                            let kind = words
+                                .get(at + 1)
+                                .ok_or_else(|| parse_error(number, "expected an error kind"))?;
+                            let kind = parse_error_kind(number, kind)?;
+                            words.truncate(at);
+                            Some(kind)
+                        }
+                        None => None,
+                    };
+                    Event::Call(Call::parse(number, name, words.into_iter())?, error)
+                }
+                None => return Err(parse_error(number, "expected an event")),
+            };
+            lines.push(Line {
+                number,
+                time,
+                event,
+            });
+        }
+        Ok(Script { builder, lines })
+    }
+
+    /// Reads and parses the script at `path`.
+    pub fn from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        Script::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Runs the script against a fresh interface, and returns the first thing that didn't go as
+    /// the script expected.
+    pub fn run(&self) -> io::Result<()> {
+        let clock = VirtualClock::new();
+        let (nic, mut peer) = MemoryDevice::pair();
+        let iface = self
+            .builder
+            .clone()
+            .clock(clock.clone())
+            .build_with_device(nic)?;
+        let settle = iface.settler();
+
+        let (calls, rx) = mpsc::channel();
+        let (tx, mut outcomes) = mpsc::channel();
+        thread::spawn(move || App::default().run(iface, rx, tx));
+
+        let mismatch =
+            |number: usize, what: String| io::Error::other(format!("line {}: {}", number, what));
+        let mut now = Duration::ZERO;
+        let mut local_port = None;
+        let mut remote_port = REMOTE_PORT;
+        let mut outstanding = 0;
+        let mut buf = vec![0u8; 65535];
+        for line in &self.lines {
+            if line.time > now {
+                settle();
+                if let Some(packet) = recv(&mut peer, &mut buf, Duration::ZERO)? {
+                    return Err(mismatch(
+                        line.number,
+                        format!("sent {} before this line", describe(&packet)),
+                    ));
+                }
+                clock.advance(line.time - now);
+                now = line.time;
+                if let Event::Inject(_) = line.event {
+                    // let timers that ran out by now fire before the segment arrives
+                    settle();
+                }
+            }
+
+            match &line.event {
+                Event::Inject(segment) => {
+                    let local_port = local_port.ok_or_else(|| {
+                        mismatch(
+                            line.number,
+                            "don't know which port to send to yet".to_string(),
+                        )
+                    })?;
+                    peer.send(&segment.to_packet(remote_port, local_port))?;
+                }
+                Event::Expect(expected) => {
+                    let packet = recv(&mut peer, &mut buf, TIMEOUT)?.ok_or_else(|| {
+                        mismatch(
+                            line.number,
+                            format!("expected {}, but nothing was sent", expected),
+                        )
+                    })?;
+                    let Some((src_port, dst_port, actual)) = Segment::from_packet(&packet) else {
+                        return Err(mismatch(
+                            line.number,
+                            format!("expected {}, got {}", expected, describe(&packet)),
+                        ));
+                    };
+                    if !expected.matches(&actual)
+                        || local_port.is_some_and(|port| port != src_port)
+                        || dst_port != remote_port
+                    {
+                        return Err(mismatch(
+                            line.number,
+                            format!("expected {}, got {}", expected, describe(&packet)),
+                        ));
+                    }
+                    local_port = Some(src_port);
+                }
+                Event::Call(call, error) => {
+                    match *call {
+                        Call::Listen(port) => local_port = Some(port),
+                        Call::Connect(port) => remote_port = port,
+                        _ => {}
+                    }
+                    calls
+                        .send((line.number, *call, *error))
+                        .expect("application thread is gone");
+                    outstanding += 1;
+                    // give the call a moment to complete, but it may well be waiting for the peer
+                    outstanding -= wait_for_outcomes(&mut outcomes, CALL_TIMEOUT, 1)?;
+                }
+            }
+        }
+
+        // the application should be done, and the stack quiet
+        outstanding -= wait_for_outcomes(&mut outcomes, TIMEOUT, outstanding)?;
+        if outstanding != 0 {
+            return Err(io::Error::other(format!(
+                "{} calls never returned",
+                outstanding
+            )));
+        }
+        settle();
+        if let Some(packet) = recv(&mut peer, &mut buf, Duration::ZERO)? {
+            return Err(io::Error::other(format!(
+                "sent {} after the end of the script",
+                describe(&packet)
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Waits up to `timeout` for up to `n` calls to return, and fails if any did so unexpectedly.
+fn wait_for_outcomes(
+    outcomes: &mut mpsc::Receiver<(usize, Result<(), String>)>,
+    timeout: Duration,
+    n: usize,
+) -> io::Result<usize> {
+    let deadline = Instant::now() + timeout;
+    let mut done = 0;
+    while done < n {
+        let left = deadline.saturating_duration_since(Instant::now());
+        match outcomes.recv_timeout(left) {
+            Ok((_, Ok(()))) => done += 1,
+            Ok((number, Err(what))) => {
+                return Err(io::Error::other(format!("line {}: {}", number, what)));
+            }
+            Err(_) => break,
+        }
+    }
+    Ok(done)
+}
+
+fn recv(peer: &mut MemoryDevice, buf: &mut [u8], timeout: Duration) -> io::Result<Option<Vec<u8>>> {
+    if !peer.poll(timeout)? {
+        return Ok(None);
+    }
+    let n = peer.recv(buf)?;
+    Ok(Some(buf[..n].to_vec()))
+}
+
+/// The application side of a script, which makes the calls on its own thread.
+#[derive(Default)]
+struct App {
+    listener: Option<TcpListener>,
+    stream: Option<TcpStream>,
+}
+
+impl App {
+    fn run(
+        mut self,
+        mut iface: Interface,
+        calls: mpsc::Receiver<(usize, Call, Option<io::ErrorKind>)>,
+        outcomes: mpsc::Sender<(usize, Result<(), String>)>,
+    ) {
+        for (number, call, expected) in calls {
+            let outcome = match (self.call(&mut iface, call), expected) {
+                (Ok(()), None) => Ok(()),
+                (Err(e), Some(kind)) if e.kind() == kind => Ok(()),
+                (Ok(()), Some(kind)) => Err(format!(
+                    "{:?} succeeded, but should have failed with {:?}",
+                    call, kind
+                )),
+                (Err(e), _) => Err(format!("{:?} failed: {} ({:?})", call, e, e.kind())),
+            };
+            if outcomes.send((number, outcome)).is_err() {
+                break;
+            }
+        }
+    }
+
+    fn stream(&mut self) -> io::Result<&mut TcpStream> {
+        self.stream
+            .as_mut()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "no stream yet"))
+    }
+
+    fn call(&mut self, iface: &mut Interface, call: Call) -> io::Result<()> {
+        match call {
+            Call::Listen(port) => self.listener = Some(iface.bind(port)?),
+            Call::Accept => {
+                let listener = self
+                    .listener
+                    .as_mut()
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not listening"))?;
+                self.stream = Some(listener.accept()?);
+            }
+            Call::Connect(port) => self.stream = Some(iface.connect((REMOTE, port).into())?),
+            Call::Write(len) => self.stream()?.write_all(&vec![b'x'; len])?,
+            Call::Read(0) => {
+                let n = self.stream()?.read(&mut [0u8; 1])?;
+                if n != 0 {
+                    return Err(io::Error::other("read data, but expected end of file"));
+                }
+            }
+            Call::Read(len) => self.stream()?.read_exact(&mut vec![0u8; len])?,
+            Call::Shutdown => self.stream()?.shutdown(std::net::Shutdown::Write)?,
+            Call::Close => drop(self.stream.take()),
+        }
+        Ok(())
+    }
+}
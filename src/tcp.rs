@@ -1,7 +1,28 @@
+use crate::seq::{is_between_wrapped, wrapping_lt};
+use crate::{Clock, Device, Quad};
 use bitflags::bitflags;
 use std::collections::{BTreeMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::Arc;
 use std::{io, time};
 
+/// How long a connection lingers in TIME-WAIT (2*MSL, RFC 793 S3.5)
+const TIME_WAIT_TIMEOUT: time::Duration = time::Duration::from_secs(2 * 30);
+
+/// Retransmission timeout before we have measured the round-trip time (RFC 6298 S2.1)
+const INITIAL_RTO: time::Duration = time::Duration::from_secs(1);
+/// Lower bound on the retransmission timeout. RFC 6298 says 1s, but like Linux we go lower.
+const MIN_RTO: time::Duration = time::Duration::from_millis(200);
+/// Upper bound on the retransmission timeout (RFC 6298 S2.5)
+const MAX_RTO: time::Duration = time::Duration::from_secs(60);
+
+/// The largest segment a peer takes unless it says otherwise (RFC 9293 S3.7.1, RFC 8200 S8.3)
+const DEFAULT_MSS_V4: usize = 536;
+const DEFAULT_MSS_V6: usize = 1220;
+/// Lower bound on the MSS we believe a peer about, since tiny segments are mostly good for keeping
+/// us busy
+const MIN_MSS: usize = 88;
+
 bitflags! {
     pub(crate) struct Available: u8 {
         const READ = 0b00000001;
@@ -9,21 +30,35 @@ bitflags! {
     }
 }
 
-#[derive(Debug)]
-enum State {
-    //Listen,
+/// The state of a connection, as in the state diagram of RFC 9293 S3.3.2.
+///
+/// There is no LISTEN, since listening is what a [`TcpListener`](crate::TcpListener) does.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum State {
+    SynSent,
     SynRcvd,
     Estab,
     FinWait1,
     FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
     TimeWait,
+    Closed,
 }
 
 impl State {
     fn is_synchronized(&self) -> bool {
         match *self {
-            State::SynRcvd => false,
-            State::Estab | State::FinWait1 | State::FinWait2 | State::TimeWait => true,
+            State::SynSent | State::SynRcvd => false,
+            State::Estab
+            | State::FinWait1
+            | State::FinWait2
+            | State::CloseWait
+            | State::Closing
+            | State::LastAck
+            | State::TimeWait => true,
+            State::Closed => false,
         }
     }
 }
@@ -32,7 +67,7 @@ pub struct Connection {
     state: State,
     send: SendSequenceSpace,
     recv: RecvSequenceSpace,
-    ip: etherparse::Ipv4Header,
+    ip: crate::ip::Header,
     tcp: etherparse::TcpHeader,
     timers: Timers,
 
@@ -41,21 +76,117 @@ pub struct Connection {
 
     pub(crate) closed: bool,
     closed_at: Option<u32>,
+    /// the user has shut down the read half; incoming data is discarded
+    rd_closed: bool,
+    /// the connection was closed by a RST (or a hard ICMP error) rather than by a FIN exchange
+    reset: bool,
+    /// the hard ICMP error that closed the connection, if that's what did
+    error: Option<io::ErrorKind>,
+    /// the last soft ICMP error, until the user asks for it
+    soft_error: Option<io::ErrorKind>,
+    /// the connection should be reset on the next tick
+    abort: bool,
+    /// no user handle refers to this connection anymore
+    pub(crate) orphaned: bool,
+    /// SO_LINGER: how long dropping the stream waits for our FIN to be acknowledged
+    pub(crate) linger: Option<time::Duration>,
+
+    /// largest IP packet we may send
+    mtu: usize,
+    /// largest IP packet the path to the peer takes, as far as ICMP has told us (RFC 1191)
+    path_mtu: usize,
+    /// what we've found out about the path ourselves (RFC 4821)
+    search: crate::pmtu::Search,
+    /// largest segment the peer takes, from the MSS option on its SYN
+    peer_mss: usize,
+    /// retransmit what is in flight on the next tick, since it was too big for the path
+    resend: bool,
+    /// how much received data we are willing to buffer
+    recv_buffer_size: usize,
+    clock: Arc<dyn Clock>,
 }
 
 struct Timers {
     send_times: BTreeMap<u32, time::Instant>,
-    srtt: f64,
+    /// smoothed round-trip time, once we have a sample
+    srtt: Option<f64>,
+    /// round-trip time variation
+    rttvar: f64,
+    rto: time::Duration,
+    /// whether anything in flight has been retransmitted, which makes its RTT ambiguous (Karn)
+    retransmitted: bool,
+    /// when the peer's window was found shut, with nothing in flight to learn that it opened
+    persist: Option<time::Instant>,
+    time_wait: Option<time::Instant>,
+}
+
+impl Timers {
+    /// Feed a round-trip time measurement into the RTO estimate (RFC 6298 S2).
+    fn sample(&mut self, rtt: time::Duration) {
+        let r = rtt.as_secs_f64();
+        let srtt = match self.srtt {
+            None => {
+                self.rttvar = r / 2.0;
+                r
+            }
+            Some(srtt) => {
+                self.rttvar = 0.75 * self.rttvar + 0.25 * (srtt - r).abs();
+                0.875 * srtt + 0.125 * r
+            }
+        };
+        self.srtt = Some(srtt);
+        self.rto = time::Duration::from_secs_f64(srtt + 4.0 * self.rttvar).clamp(MIN_RTO, MAX_RTO);
+    }
 }
 
 impl Connection {
     pub(crate) fn is_rcv_closed(&self) -> bool {
-        if let State::TimeWait = self.state {
-            // TODO: any state after rcvd FIN, so also CLOSE-WAIT, LAST-ACK, CLOSED, CLOSING
-            true
-        } else {
-            false
-        }
+        // any state after rcvd FIN
+        self.rd_closed
+            || matches!(
+                self.state,
+                State::CloseWait
+                    | State::Closing
+                    | State::LastAck
+                    | State::TimeWait
+                    | State::Closed
+            )
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        matches!(self.state, State::Closed)
+    }
+
+    /// Whether the connection was reset by the peer (or refused, if we opened it).
+    pub(crate) fn is_reset(&self) -> bool {
+        self.reset
+    }
+
+    /// The hard ICMP error that closed the connection, if it was one rather than a RST.
+    pub(crate) fn error(&self) -> Option<io::ErrorKind> {
+        self.error
+    }
+
+    /// Takes the last soft ICMP error reported about the connection.
+    pub(crate) fn take_soft_error(&mut self) -> Option<io::ErrorKind> {
+        self.soft_error.take()
+    }
+
+    /// Returns the current state of the connection.
+    pub(crate) fn state(&self) -> State {
+        self.state
+    }
+
+    pub(crate) fn is_connecting(&self) -> bool {
+        matches!(self.state, State::SynSent | State::SynRcvd)
+    }
+
+    /// Whether everything we are going to send, including our FIN, has been acknowledged.
+    pub(crate) fn is_snd_closed(&self) -> bool {
+        matches!(
+            self.state,
+            State::FinWait2 | State::TimeWait | State::Closed
+        )
     }
 
     fn availability(&self) -> Available {
@@ -63,15 +194,17 @@ impl Connection {
         if self.is_rcv_closed() || !self.incoming.is_empty() {
             a |= Available::READ;
         }
+        if self.unacked.is_empty() || self.is_snd_closed() {
+            a |= Available::WRITE;
+        }
         // TODO: take into account self.state
-        // TODO: set Available::WRITE
         a
     }
 }
 
 /// State of the Send Sequence Space (RFC 793 S3.2 F4)
 ///
-/// ```
+/// ```text
 ///            1         2          3          4
 ///       ----------|----------|----------|----------
 ///              SND.UNA    SND.NXT    SND.UNA
@@ -89,19 +222,17 @@ struct SendSequenceSpace {
     nxt: u32,
     /// send window
     wnd: u16,
-    /// send urgent pointer
-    up: bool,
     /// segment sequence number used for last window update
-    wl1: usize,
+    wl1: u32,
     /// segment acknowledgment number used for last window update
-    wl2: usize,
+    wl2: u32,
     /// initial send sequence number
     iss: u32,
 }
 
 /// State of the Receive Sequence Space (RFC 793 S3.2 F5)
 ///
-/// ```
+/// ```text
 ///                1          2          3
 ///            ----------|----------|----------
 ///                   RCV.NXT    RCV.NXT
@@ -116,74 +247,95 @@ struct RecvSequenceSpace {
     nxt: u32,
     /// receive window
     wnd: u16,
-    /// receive urgent pointer
-    up: bool,
     /// initial receive sequence number
     irs: u32,
 }
 
 impl Connection {
-    pub fn accept<'a>(
-        nic: &mut tun_tap::Iface,
-        iph: etherparse::Ipv4HeaderSlice<'a>,
-        tcph: etherparse::TcpHeaderSlice<'a>,
-        data: &'a [u8],
-    ) -> io::Result<Option<Self>> {
-        let buf = [0u8; 1500];
-        if !tcph.syn() {
-            // only expected SYN packet
-            return Ok(None);
-        }
-
+    fn new(
+        config: &crate::Config,
+        local: (IpAddr, u16),
+        remote: (IpAddr, u16),
+        state: State,
+    ) -> Self {
         let iss = 0;
-        let wnd = 1024;
-        let mut c = Connection {
+        let wnd = std::cmp::min(config.recv_buffer_size, u16::MAX as usize) as u16;
+        Connection {
             timers: Timers {
                 send_times: Default::default(),
-                srtt: time::Duration::from_secs(1 * 60).as_secs_f64(),
+                srtt: None,
+                rttvar: 0.0,
+                rto: INITIAL_RTO,
+                retransmitted: false,
+                persist: None,
+                time_wait: None,
             },
-            state: State::SynRcvd,
+            state,
             send: SendSequenceSpace {
                 iss,
                 una: iss,
                 nxt: iss,
-                wnd: wnd,
-                up: false,
-
+                wnd: 0,
                 wl1: 0,
                 wl2: 0,
             },
             recv: RecvSequenceSpace {
-                irs: tcph.sequence_number(),
-                nxt: tcph.sequence_number() + 1,
-                wnd: tcph.window_size(),
-                up: false,
+                irs: 0,
+                nxt: 0,
+                wnd,
             },
-            tcp: etherparse::TcpHeader::new(tcph.destination_port(), tcph.source_port(), iss, wnd),
-            ip: etherparse::Ipv4Header::new(
-                0,
-                64,
-                etherparse::IpTrafficClass::Tcp,
-                [
-                    iph.destination()[0],
-                    iph.destination()[1],
-                    iph.destination()[2],
-                    iph.destination()[3],
-                ],
-                [
-                    iph.source()[0],
-                    iph.source()[1],
-                    iph.source()[2],
-                    iph.source()[3],
-                ],
-            ),
+            tcp: etherparse::TcpHeader::new(local.1, remote.1, iss, wnd),
+            ip: crate::ip::Header::new(local.0, remote.0, etherparse::IpTrafficClass::Tcp),
 
             incoming: Default::default(),
             unacked: Default::default(),
 
             closed: false,
             closed_at: None,
-        };
+            rd_closed: false,
+            reset: false,
+            error: None,
+            soft_error: None,
+            abort: false,
+            orphaned: false,
+            linger: None,
+
+            mtu: config.mtu,
+            path_mtu: config.mtu,
+            search: crate::pmtu::Search::new(remote.0, config.mtu, config.mtu_probing),
+            peer_mss: match remote.0 {
+                IpAddr::V4(_) => DEFAULT_MSS_V4,
+                IpAddr::V6(_) => DEFAULT_MSS_V6,
+            },
+            resend: false,
+            recv_buffer_size: config.recv_buffer_size,
+            clock: config.clock.clone(),
+        }
+    }
+
+    pub fn accept<'a>(
+        nic: &mut dyn Device,
+        config: &crate::Config,
+        ip: &crate::ip::Packet<'a>,
+        tcph: etherparse::TcpHeaderSlice<'a>,
+        _data: &'a [u8],
+    ) -> io::Result<Option<Self>> {
+        if !tcph.syn() || tcph.ack() || tcph.rst() {
+            // only expected SYN packet
+            return Ok(None);
+        }
+
+        let mut c = Connection::new(
+            config,
+            (ip.dst, tcph.destination_port()),
+            (ip.src, tcph.source_port()),
+            State::SynRcvd,
+        );
+        c.recv.irs = tcph.sequence_number();
+        c.recv.nxt = tcph.sequence_number().wrapping_add(1);
+        c.send.wnd = tcph.window_size();
+        c.send.wl1 = tcph.sequence_number();
+        c.on_mss_option(&tcph);
 
         // need to start establishing a connection
         c.tcp.syn = true;
@@ -192,25 +344,81 @@ impl Connection {
         Ok(Some(c))
     }
 
-    fn write(&mut self, nic: &mut tun_tap::Iface, seq: u32, mut limit: usize) -> io::Result<usize> {
-        let mut buf = [0u8; 1500];
+    /// Start an active open of the connection identified by `quad`.
+    ///
+    /// The SYN goes out on the next tick, since only the packet loop has access to the device.
+    pub(crate) fn connect(config: &crate::Config, quad: Quad) -> Self {
+        let mut c = Connection::new(config, quad.dst, quad.src, State::SynSent);
+        c.tcp.syn = true;
+        c
+    }
+
+    /// How much more data we can buffer, and thus the receive window we should advertise.
+    fn rcv_wnd(&self) -> u16 {
+        let free = self.recv_buffer_size.saturating_sub(self.incoming.len());
+        std::cmp::min(free, u16::MAX as usize) as u16
+    }
+
+    /// The most data we can fit in a single segment (RFC 1122 S4.2.2.6).
+    fn max_payload(&self) -> usize {
+        let mtu = std::cmp::min(self.path_mtu, self.search.mtu());
+        std::cmp::min(self.payload_in(mtu), self.peer_mss)
+    }
+
+    /// How much data fits in a packet of `size` bytes. Only SYNs carry options.
+    fn payload_in(&self, size: usize) -> usize {
+        size - self.ip.header_len() - etherparse::TCP_MINIMUM_HEADER_SIZE
+    }
+
+    /// Takes note of the largest segment the peer says it takes, if its SYN says.
+    fn on_mss_option(&mut self, tcph: &etherparse::TcpHeaderSlice<'_>) {
+        for option in tcph.options_iterator() {
+            if let Ok(etherparse::TcpOptionElement::MaximumSegmentSize(mss)) = option {
+                self.peer_mss = std::cmp::max(usize::from(mss), MIN_MSS);
+            }
+        }
+    }
+
+    /// Takes note of the path MTU to the peer, as we currently know it.
+    pub(crate) fn set_path_mtu(&mut self, mtu: usize) {
+        self.path_mtu = std::cmp::min(mtu, self.mtu);
+    }
+
+    fn write(&mut self, nic: &mut dyn Device, seq: u32, mut limit: usize) -> io::Result<usize> {
+        let mut buf = vec![0u8; self.mtu];
         self.tcp.sequence_number = seq;
         self.tcp.acknowledgment_number = self.recv.nxt;
+        self.recv.wnd = self.rcv_wnd();
+        self.tcp.window_size = self.recv.wnd;
+        let options = if self.tcp.syn {
+            // what we take is limited by our link, not by the path (RFC 1191 S3.1)
+            let mss = std::cmp::min(self.payload_in(self.mtu), u16::MAX as usize);
+            vec![etherparse::TcpOptionElement::MaximumSegmentSize(mss as u16)]
+        } else {
+            vec![]
+        };
+        self.tcp
+            .set_options(&options)
+            .expect("options fit in a tcp header");
 
         // TODO: return +1 for SYN/FIN
         println!(
             "write(ack: {}, seq: {}, limit: {}) syn {:?} fin {:?}",
-            self.recv.nxt - self.recv.irs, seq, limit, self.tcp.syn, self.tcp.fin,
+            self.recv.nxt.wrapping_sub(self.recv.irs),
+            seq,
+            limit,
+            self.tcp.syn,
+            self.tcp.fin,
         );
 
         let mut offset = seq.wrapping_sub(self.send.una) as usize;
         // we need to special-case the two "virtual" bytes SYN and FIN
-        if let Some(closed_at) = self.closed_at {
-            if seq == closed_at.wrapping_add(1) {
-                // trying to write following FIN
-                offset = 0;
-                limit = 0;
-            }
+        if let Some(closed_at) = self.closed_at
+            && seq == closed_at.wrapping_add(1)
+        {
+            // trying to write following FIN
+            offset = 0;
+            limit = 0;
         }
         println!(
             "using offset {} base {} in {:?}",
@@ -218,6 +426,8 @@ impl Connection {
             self.send.una,
             self.unacked.as_slices()
         );
+        // there may be nothing left to send from there, if we've thrown away what was buffered
+        let offset = std::cmp::min(offset, self.unacked.len());
         let (mut h, mut t) = self.unacked.as_slices();
         if h.len() >= offset {
             h = &h[offset..];
@@ -230,17 +440,18 @@ impl Connection {
         let max_data = std::cmp::min(limit, h.len() + t.len());
         let size = std::cmp::min(
             buf.len(),
-            self.tcp.header_len() as usize + self.ip.header_len() as usize + max_data,
+            self.tcp.header_len() as usize + self.ip.header_len() + max_data,
         );
         self.ip
-            .set_payload_len(size - self.ip.header_len() as usize);
+            .set_payload_len(size - self.ip.header_len())
+            .expect("payload fits in an ip packet");
 
         // write out the headers and the payload
         use std::io::Write;
         let buf_len = buf.len();
         let mut unwritten = &mut buf[..];
 
-        self.ip.write(&mut unwritten);
+        self.ip.write(&mut unwritten)?;
         let ip_header_ends_at = buf_len - unwritten.len();
 
         // postpone writing the tcp header because we need the payload as one contiguous slice to calculate the tcp checksum
@@ -266,12 +477,12 @@ impl Connection {
 
         // finally we can calculate the tcp checksum and write out the tcp header
         self.tcp.checksum = self
-            .tcp
-            .calc_checksum_ipv4(&self.ip, &buf[tcp_header_ends_at..payload_ends_at])
+            .ip
+            .tcp_checksum(&self.tcp, &buf[tcp_header_ends_at..payload_ends_at])
             .expect("failed to compute checksum");
 
         let mut tcp_header_buf = &mut buf[ip_header_ends_at..tcp_header_ends_at];
-        self.tcp.write(&mut tcp_header_buf);
+        self.tcp.write(&mut tcp_header_buf)?;
 
         let mut next_seq = seq.wrapping_add(payload_bytes as u32);
         if self.tcp.syn {
@@ -285,13 +496,16 @@ impl Connection {
         if wrapping_lt(self.send.nxt, next_seq) {
             self.send.nxt = next_seq;
         }
-        self.timers.send_times.insert(seq, time::Instant::now());
+        if next_seq != seq {
+            // only segments that occupy sequence space are acknowledged, and so can be timed
+            self.timers.send_times.insert(seq, self.clock.now());
+        }
 
         nic.send(&buf[..payload_ends_at])?;
         Ok(payload_bytes)
     }
 
-    fn send_rst(&mut self, nic: &mut tun_tap::Iface) -> io::Result<()> {
+    fn send_rst(&mut self, nic: &mut dyn Device) -> io::Result<()> {
         self.tcp.rst = true;
         // TODO: fix sequence numbers here
         // If the incoming segment has an ACK field, the reset takes its
@@ -310,12 +524,43 @@ impl Connection {
         // to be received, and the connection remains in the same state.
         self.tcp.sequence_number = 0;
         self.tcp.acknowledgment_number = 0;
-        self.write(nic, self.send.nxt, 0)?;
-        Ok(())
+        let r = self.write(nic, self.send.nxt, 0);
+        self.tcp.rst = false;
+        r.map(|_| ())
     }
 
-    pub(crate) fn on_tick(&mut self, nic: &mut tun_tap::Iface) -> io::Result<()> {
-        if let State::FinWait2 | State::TimeWait = self.state {
+    pub(crate) fn on_tick(&mut self, nic: &mut dyn Device) -> io::Result<()> {
+        let syn_unsent = matches!(self.state, State::SynSent) && self.send.nxt == self.send.iss;
+
+        if self.abort {
+            self.abort = false;
+            // if our SYN never went out, the peer doesn't know about us, so there's nothing to reset
+            if !self.is_closed() && !syn_unsent {
+                self.send_rst(nic)?;
+            }
+            self.state = State::Closed;
+            return Ok(());
+        }
+
+        if syn_unsent {
+            return self.write(nic, self.send.iss, 0).map(|_| ());
+        }
+
+        if let State::TimeWait = self.state {
+            let now = self.clock.now();
+            let since = *self.timers.time_wait.get_or_insert(now);
+            if now - since > TIME_WAIT_TIMEOUT {
+                self.state = State::Closed;
+            }
+            return Ok(());
+        }
+
+        if self.recv.wnd == 0 && self.rcv_wnd() != 0 && !self.is_rcv_closed() {
+            // the user has made room since we advertised a zero window, so let the peer know
+            self.write(nic, self.send.nxt, 0)?;
+        }
+
+        if let State::FinWait2 | State::Closed = self.state {
             // we have shutdown our write side and the other side acked, no need to (re)transmit anything
             return Ok(());
         }
@@ -323,47 +568,123 @@ impl Connection {
         // eprintln!("ON TICK: state {:?} una {} nxt {} unacked {:?}",
         //           self.state, self.send.una, self.send.nxt, self.unacked);
 
-        let nunacked_data = self.closed_at.unwrap_or(self.send.nxt).wrapping_sub(self.send.una);
-        let nunsent_data = self.unacked.len() as u32 - nunacked_data;
+        let nunacked_data = self
+            .closed_at
+            .unwrap_or(self.send.nxt)
+            .wrapping_sub(self.send.una);
+        // our SYN occupies a sequence number, but not a byte of `unacked`
+        let nunsent_data = (self.unacked.len() as u32).saturating_sub(nunacked_data);
 
         let waited_for = self
             .timers
             .send_times
             .range(self.send.una..)
             .next()
-            .map(|t| t.1.elapsed());
+            .map(|t| self.clock.now().saturating_duration_since(*t.1));
 
-        let should_retransmit = if let Some(waited_for) = waited_for {
-            waited_for > time::Duration::from_secs(1)
-                && waited_for.as_secs_f64() > 1.5 * self.timers.srtt
-        } else {
-            false
-        };
+        let timed_out = waited_for.is_some_and(|w| w > self.timers.rto);
+        let resend = std::mem::take(&mut self.resend) && nunacked_data != 0;
 
-        if should_retransmit {
-            let resend = std::cmp::min(self.unacked.len() as u32, self.send.wnd as u32);
-            if resend < self.send.wnd as u32 && self.closed {
-                // can we include the FIN?
-                self.tcp.fin = true;
-                self.closed_at = Some(self.send.una.wrapping_add(self.unacked.len() as u32));
+        if timed_out || resend {
+            // a lost probe says something about the path, not that the network is congested
+            // (RFC 4821 S7.6.1), and so does a segment too big for the path
+            let probe_lost = timed_out
+                && self.send.una != self.send.iss
+                && self.search.on_timeout(self.send.una, self.clock.now());
+            if timed_out && !probe_lost {
+                // back off (RFC 6298 S5.5)
+                self.timers.rto = std::cmp::min(self.timers.rto * 2, MAX_RTO);
+            }
+            // and don't trust RTT samples until we're past this (Karn)
+            self.timers.retransmitted = true;
+
+            if self.send.una == self.send.iss {
+                // it's our SYN that hasn't been acknowledged
+                self.tcp.syn = true;
+                return self.write(nic, self.send.una, 0).map(|_| ());
+            }
+
+            // go back N: we drop out-of-order segments, so the peer probably does too
+            let sent = std::cmp::min(self.unacked.len() as u32, nunacked_data);
+            // a zero window still lets through the byte we probed it with
+            let resend = std::cmp::min(sent, std::cmp::max(self.send.wnd, 1) as u32) as usize;
+            let mut offset = 0;
+            let max_payload = self.max_payload();
+            loop {
+                let remaining = resend - offset;
+                let last = remaining <= max_payload;
+                if last
+                    && resend == self.unacked.len()
+                    && resend < self.send.wnd as usize
+                    && self.closed
+                {
+                    // can we include the FIN?
+                    self.tcp.fin = true;
+                    self.closed_at = Some(self.send.una.wrapping_add(self.unacked.len() as u32));
+                }
+                let limit = std::cmp::min(remaining, max_payload);
+                offset += self.write(nic, self.send.una.wrapping_add(offset as u32), limit)?;
+                if last {
+                    break;
+                }
             }
-            self.write(nic, self.send.una, resend as usize)?;
         } else {
             // we should send new data if we have new data and space in the window
+            if self.send.una == self.send.iss {
+                // but not before our SYN has been acknowledged, even if the user has closed already
+                return Ok(());
+            }
             if nunsent_data == 0 && self.closed_at.is_some() {
                 return Ok(());
             }
 
-            let allowed = self.send.wnd as u32 - nunacked_data;
+            let allowed = (self.send.wnd as u32).saturating_sub(nunacked_data);
             if allowed == 0 {
+                if self.send.wnd == 0 && nunacked_data == 0 && nunsent_data != 0 {
+                    // probe the zero window once the persist timer runs out, in case we missed the
+                    // update that opens it (RFC 1122 S4.2.2.17). the probe is then retransmitted,
+                    // with backoff, like any other segment.
+                    let now = self.clock.now();
+                    let since = *self.timers.persist.get_or_insert(now);
+                    if now - since > self.timers.rto {
+                        self.timers.persist = None;
+                        self.write(nic, self.send.nxt, 1)?;
+                    }
+                }
                 return Ok(());
             }
+            self.timers.persist = None;
 
             let send = std::cmp::min(nunsent_data, allowed);
-            if send < allowed && self.closed && self.closed_at.is_none() {
+            // probe the path with a segment larger than we know gets through, once we have the
+            // data and the window for one, and nothing has been lost lately (RFC 4821 S7.4)
+            let headers = self.ip.header_len() + etherparse::TCP_MINIMUM_HEADER_SIZE;
+            let limit = std::cmp::min(self.path_mtu, self.peer_mss + headers);
+            if !self.timers.retransmitted
+                && let Some(size) = self.search.probe_size(limit, self.clock.now())
+            {
+                let payload = self.payload_in(size);
+                if send as usize >= payload {
+                    let seq = self.send.nxt;
+                    let sent = self.write(nic, seq, payload)?;
+                    self.search.on_probe(seq.wrapping_add(sent as u32), size);
+                    return Ok(());
+                }
+                if nunacked_data != 0 {
+                    // hold on to what we have until there's enough for a probe, which won't take
+                    // longer than it takes for what's in flight to be acknowledged
+                    return Ok(());
+                }
+            }
+            let send = std::cmp::min(send, self.max_payload() as u32);
+            if send == nunsent_data && send < allowed && self.closed && self.closed_at.is_none() {
                 self.tcp.fin = true;
                 self.closed_at = Some(self.send.una.wrapping_add(self.unacked.len() as u32));
             }
+            if send == 0 && !self.tcp.fin {
+                // nothing new to say
+                return Ok(());
+            }
 
             self.write(nic, self.send.nxt, send as usize)?;
         }
@@ -373,11 +694,22 @@ impl Connection {
 
     pub(crate) fn on_packet<'a>(
         &mut self,
-        nic: &mut tun_tap::Iface,
-        iph: etherparse::Ipv4HeaderSlice<'a>,
+        nic: &mut dyn Device,
+        ip: &crate::ip::Packet<'a>,
         tcph: etherparse::TcpHeaderSlice<'a>,
         data: &'a [u8],
     ) -> io::Result<Available> {
+        if let State::Closed = self.state {
+            return Ok(self.availability());
+        }
+
+        if let State::SynSent = self.state {
+            return self.on_syn_sent_packet(nic, ip, tcph);
+        }
+
+        // NOTE: we check against the window we last advertised, not what we'd advertise now. if the
+        // user has made room since we advertised a zero window, on_tick still has to tell the peer.
+
         // first, check that sequence numbers are valid (RFC 793 S3.3)
         let seqn = tcph.sequence_number();
         let mut slen = data.len() as u32;
@@ -391,44 +723,51 @@ impl Connection {
         let okay = if slen == 0 {
             // zero-length segment has separate rules for acceptance
             if self.recv.wnd == 0 {
-                if seqn != self.recv.nxt {
-                    false
-                } else {
-                    true
-                }
-            } else if !is_between_wrapped(self.recv.nxt.wrapping_sub(1), seqn, wend) {
-                false
+                seqn == self.recv.nxt
             } else {
-                true
+                is_between_wrapped(self.recv.nxt.wrapping_sub(1), seqn, wend)
             }
         } else {
-            if self.recv.wnd == 0 {
-                false
-            } else if !is_between_wrapped(self.recv.nxt.wrapping_sub(1), seqn, wend)
-                && !is_between_wrapped(
-                    self.recv.nxt.wrapping_sub(1),
-                    seqn.wrapping_add(slen - 1),
-                    wend,
-                )
-            {
-                false
-            } else {
-                true
-            }
+            self.recv.wnd != 0
+                && (is_between_wrapped(self.recv.nxt.wrapping_sub(1), seqn, wend)
+                    || is_between_wrapped(
+                        self.recv.nxt.wrapping_sub(1),
+                        seqn.wrapping_add(slen - 1),
+                        wend,
+                    ))
         };
 
         if !okay {
             eprintln!("NOT OKAY");
+            if let State::TimeWait = self.state
+                && tcph.fin()
+            {
+                // the peer didn't get our ACK of its FIN, so restart the 2 MSL timeout
+                self.timers.time_wait = Some(self.clock.now());
+            }
+            if !tcph.rst() {
+                self.write(nic, self.send.nxt, 0)?;
+            }
+            return Ok(self.availability());
+        }
+
+        if tcph.rst() {
+            // a connection we accepted, but never got to establish, isn't worth reporting
+            self.reset = self.state.is_synchronized();
+            self.state = State::Closed;
+            self.incoming.clear();
+            self.unacked.clear();
+            return Ok(self.availability());
+        }
+
+        if tcph.syn() {
+            // the peer's SYN is behind us, so this is an old duplicate or a forgery. either way,
+            // all we do is tell the peer where we are with a challenge ACK (RFC 5961 S4.2).
             self.write(nic, self.send.nxt, 0)?;
             return Ok(self.availability());
         }
 
         if !tcph.ack() {
-            if tcph.syn() {
-                // got SYN part of initial handshake
-                assert!(data.is_empty());
-                self.recv.nxt = seqn.wrapping_add(1);
-            }
             return Ok(self.availability());
         }
 
@@ -447,122 +786,312 @@ impl Connection {
             }
         }
 
-        if let State::Estab | State::FinWait1 | State::FinWait2 = self.state {
-            if is_between_wrapped(self.send.una, ackn, self.send.nxt.wrapping_add(1)) {
-                println!(
-                    "ack for {} (last: {}); prune in {:?}",
-                    ackn, self.send.una, self.unacked
-                );
-                if !self.unacked.is_empty() {
-                    let data_start = if self.send.una == self.send.iss {
-                        // send.una hasn't been updated yet with ACK for our SYN, so data starts just beyond it
-                        self.send.una.wrapping_add(1)
-                    } else {
-                        self.send.una
-                    };
-                    let acked_data_end = std::cmp::min(ackn.wrapping_sub(data_start) as usize, self.unacked.len());
-                    self.unacked.drain(..acked_data_end);
-
-                    let old = std::mem::replace(&mut self.timers.send_times, BTreeMap::new());
-
-                    let una = self.send.una;
-                    let mut srtt = &mut self.timers.srtt;
-                    self.timers
-                        .send_times
-                        .extend(old.into_iter().filter_map(|(seq, sent)| {
-                            if is_between_wrapped(una, seq, ackn) {
-                                *srtt = 0.8 * *srtt + (1.0 - 0.8) * sent.elapsed().as_secs_f64();
-                                None
-                            } else {
-                                Some((seq, sent))
-                            }
-                        }));
+        if self.state.is_synchronized()
+            && is_between_wrapped(self.send.una, ackn, self.send.nxt.wrapping_add(1))
+        {
+            println!(
+                "ack for {} (last: {}); prune in {:?}",
+                ackn, self.send.una, self.unacked
+            );
+            if !self.unacked.is_empty() {
+                let data_start = if self.send.una == self.send.iss {
+                    // send.una hasn't been updated yet with ACK for our SYN, so data starts just beyond it
+                    self.send.una.wrapping_add(1)
+                } else {
+                    self.send.una
+                };
+                let acked_data_end =
+                    std::cmp::min(ackn.wrapping_sub(data_start) as usize, self.unacked.len());
+                self.unacked.drain(..acked_data_end);
+            }
+
+            // forget about the segments this acknowledges, and time the most recent of them
+            let una = self.send.una;
+            let now = self.clock.now();
+            let mut rtt = None;
+            self.timers.send_times.retain(|&seq, sent| {
+                if seq.wrapping_sub(una) < ackn.wrapping_sub(una) {
+                    rtt = Some(now.saturating_duration_since(*sent));
+                    false
+                } else {
+                    true
                 }
-                self.send.una = ackn;
+            });
+            if self.timers.retransmitted {
+                self.timers.retransmitted = false;
+            } else if let Some(rtt) = rtt {
+                self.timers.sample(rtt);
             }
+            if ackn != una {
+                self.search.on_ack(ackn, now);
+            }
+            self.send.una = ackn;
+        }
+        // TODO: if unacked empty and waiting flush, notify
 
-            // TODO: if unacked empty and waiting flush, notify
-            // TODO: update window
+        if self.state.is_synchronized()
+            && (wrapping_lt(self.send.wl1, seqn)
+                || (self.send.wl1 == seqn && !wrapping_lt(ackn, self.send.wl2)))
+        {
+            // update the send window (RFC 793 S3.9)
+            self.send.wnd = tcph.window_size();
+            self.send.wl1 = seqn;
+            self.send.wl2 = ackn;
         }
 
-        if let State::FinWait1 = self.state {
-            if let Some(closed_at) = self.closed_at {
-                if self.send.una == closed_at.wrapping_add(1) {
-                    // our FIN has been ACKed!
-                    self.state = State::FinWait2;
-                }
+        if let Some(closed_at) = self.closed_at
+            && self.send.una == closed_at.wrapping_add(1)
+        {
+            // our FIN has been ACKed!
+            match self.state {
+                State::FinWait1 => self.state = State::FinWait2,
+                State::Closing => self.state = State::TimeWait,
+                State::LastAck => self.state = State::Closed,
+                _ => {}
             }
         }
 
-        if !data.is_empty() {
-            if let State::Estab | State::FinWait1 | State::FinWait2 = self.state {
-                let mut unread_data_at = self.recv.nxt.wrapping_sub(seqn) as usize;
-                if unread_data_at > data.len() {
-                    // we must have received a re-transmitted FIN that we have already seen
-                    // nxt points to beyond the fin, but the fin is not in data!
-                    assert_eq!(unread_data_at, data.len() + 1);
-                    unread_data_at = 0;
-                }
-                self.incoming.extend(&data[unread_data_at..]);
-
-                /*
-                Once the TCP takes responsibility for the data it advances
-                RCV.NXT over the data accepted, and adjusts RCV.WND as
-                apporopriate to the current buffer availability.  The total of
-                RCV.NXT and RCV.WND should not be reduced.
-                 */
-                self.recv.nxt = seqn.wrapping_add(data.len() as u32);
-
-                // Send an acknowledgment of the form: <SEQ=SND.NXT><ACK=RCV.NXT><CTL=ACK>
-                // TODO: maybe just tick to piggyback ack on data?
-                self.write(nic, self.send.nxt, 0)?;
+        if !data.is_empty()
+            && let State::Estab | State::FinWait1 | State::FinWait2 = self.state
+        {
+            // we have already seen everything before RCV.NXT; if the segment starts beyond it,
+            // we have missed something, and must wait for it to be retransmitted.
+            let unread_data_at =
+                std::cmp::min(self.recv.nxt.wrapping_sub(seqn) as usize, data.len());
+
+            if self.rd_closed && unread_data_at < data.len() {
+                // new data, which the user will never read (RFC 1122 S4.2.2.13)
+                self.send_rst(nic)?;
+                self.state = State::Closed;
+                return Ok(self.availability());
             }
+            // only accept as much as fits in the window we advertised
+            let accepted = std::cmp::min(data.len() - unread_data_at, self.recv.wnd as usize);
+            self.incoming
+                .extend(&data[unread_data_at..(unread_data_at + accepted)]);
+
+            /*
+            Once the TCP takes responsibility for the data it advances
+            RCV.NXT over the data accepted, and adjusts RCV.WND as
+            apporopriate to the current buffer availability.  The total of
+            RCV.NXT and RCV.WND should not be reduced.
+             */
+            self.recv.nxt = self.recv.nxt.wrapping_add(accepted as u32);
+
+            // Send an acknowledgment of the form: <SEQ=SND.NXT><ACK=RCV.NXT><CTL=ACK>
+            // TODO: maybe just tick to piggyback ack on data?
+            self.write(nic, self.send.nxt, 0)?;
         }
 
-        if tcph.fin() {
-            match self.state {
-                State::FinWait2 => {
-                    // we're done with the connection!
-                    self.recv.nxt = self.recv.nxt.wrapping_add(1);
-                    self.write(nic, self.send.nxt, 0)?;
-                    self.state = State::TimeWait;
-                }
-                _ => unimplemented!(),
+        if tcph.fin() && seqn.wrapping_add(data.len() as u32) == self.recv.nxt {
+            let next_state = match self.state {
+                State::SynRcvd | State::Estab => Some(State::CloseWait),
+                // our FIN has not been ACKed yet, otherwise we'd be in FIN-WAIT-2
+                State::FinWait1 => Some(State::Closing),
+                // we're done with the connection!
+                State::FinWait2 => Some(State::TimeWait),
+                // handled by on_syn_sent_packet
+                State::SynSent => None,
+                // retransmitted FIN, which we have already accounted for
+                State::CloseWait
+                | State::Closing
+                | State::LastAck
+                | State::TimeWait
+                | State::Closed => None,
+            };
+            if let Some(next_state) = next_state {
+                self.recv.nxt = self.recv.nxt.wrapping_add(1);
+                self.state = next_state;
             }
+            self.write(nic, self.send.nxt, 0)?;
         }
 
         Ok(self.availability())
     }
 
+    /// Handle a segment for a connection we're actively opening (RFC 793 S3.9, SYN-SENT).
+    fn on_syn_sent_packet(
+        &mut self,
+        nic: &mut dyn Device,
+        ip: &crate::ip::Packet<'_>,
+        tcph: etherparse::TcpHeaderSlice<'_>,
+    ) -> io::Result<Available> {
+        let ackn = tcph.acknowledgment_number();
+        let ack_ok =
+            tcph.ack() && is_between_wrapped(self.send.iss, ackn, self.send.nxt.wrapping_add(1));
+        if tcph.ack() && !ack_ok {
+            if !tcph.rst() {
+                send_reset(nic, ip, &tcph, 0)?;
+            }
+            return Ok(self.availability());
+        }
+
+        if tcph.rst() {
+            if ack_ok {
+                // connection refused
+                self.reset = true;
+                self.state = State::Closed;
+            }
+            return Ok(self.availability());
+        }
+
+        if !tcph.syn() {
+            return Ok(self.availability());
+        }
+
+        self.recv.irs = tcph.sequence_number();
+        self.recv.nxt = tcph.sequence_number().wrapping_add(1);
+        self.send.wnd = tcph.window_size();
+        self.send.wl1 = tcph.sequence_number();
+        self.send.wl2 = ackn;
+        self.on_mss_option(&tcph);
+        self.tcp.ack = true;
+        if ack_ok {
+            // our SYN has been ACKed
+            self.send.una = ackn;
+            self.timers.send_times.clear();
+            self.state = State::Estab;
+            self.write(nic, self.send.nxt, 0)?;
+        } else {
+            // simultaneous open
+            self.state = State::SynRcvd;
+            self.tcp.syn = true;
+            self.write(nic, self.send.iss, 0)?;
+        }
+        Ok(self.availability())
+    }
+
+    /// Handle an ICMP error about a segment we sent with sequence number `seq` (RFC 1122 S4.2.3.9).
+    pub(crate) fn on_icmp_error(&mut self, seq: u32, error: crate::icmp::Error) -> Available {
+        // anyone can claim anything went wrong, so only believe errors about something that is
+        // still in flight (RFC 5927 S4.1)
+        if self.is_closed() || !self.is_in_flight(seq) {
+            return self.availability();
+        }
+
+        if error.hard {
+            self.reset = true;
+            self.error = Some(error.kind);
+            self.state = State::Closed;
+            self.incoming.clear();
+            self.unacked.clear();
+        } else {
+            self.soft_error = Some(error.kind);
+        }
+        self.availability()
+    }
+
+    /// Handle an ICMP message saying that a segment we sent with sequence number `seq` was too big
+    /// for the path, which takes packets of at most `mtu` bytes (RFC 1191 S6.1, RFC 8201 S4).
+    ///
+    /// Returns whether we believe it, and so whether the path MTU should be remembered.
+    pub(crate) fn on_packet_too_big(&mut self, seq: u32, mtu: usize) -> bool {
+        if self.is_closed() || !self.is_in_flight(seq) || mtu > self.path_mtu {
+            // and the path MTU only ever goes down this way
+            return false;
+        }
+        self.path_mtu = mtu;
+        self.search.on_packet_too_big(mtu);
+        // whatever is in flight was likely dropped, so send it again in pieces that fit, rather
+        // than wait for it to time out
+        self.resend = true;
+        true
+    }
+
+    /// Whether a segment starting at `seq` may still be in flight.
+    fn is_in_flight(&self, seq: u32) -> bool {
+        is_between_wrapped(self.send.una.wrapping_sub(1), seq, self.send.nxt)
+    }
+
+    /// Shut down the write half: queue a FIN to be sent after all buffered data.
+    ///
+    /// Like `shutdown(2)`, closing an already-closed write half is not an error.
     pub(crate) fn close(&mut self) -> io::Result<()> {
         self.closed = true;
         match self.state {
+            State::SynSent => {
+                // nothing to tear down yet
+                self.state = State::Closed;
+            }
             State::SynRcvd | State::Estab => {
                 self.state = State::FinWait1;
             }
-            State::FinWait1 | State::FinWait2 => {}
-            _ => {
+            State::CloseWait => {
+                self.state = State::LastAck;
+            }
+            State::FinWait1
+            | State::FinWait2
+            | State::Closing
+            | State::LastAck
+            | State::TimeWait => {}
+            State::Closed => {
                 return Err(io::Error::new(
                     io::ErrorKind::NotConnected,
-                    "already closing",
-                ))
+                    "connection already closed",
+                ));
             }
         };
         Ok(())
     }
-}
 
-fn wrapping_lt(lhs: u32, rhs: u32) -> bool {
-    // From RFC1323:
-    //     TCP determines if a data segment is "old" or "new" by testing
-    //     whether its sequence number is within 2**31 bytes of the left edge
-    //     of the window, and if it is not, discarding the data as "old".  To
-    //     insure that new data is never mistakenly considered old and vice-
-    //     versa, the left edge of the sender's window has to be at most
-    //     2**31 away from the right edge of the receiver's window.
-    lhs.wrapping_sub(rhs) > (1 << 31)
+    /// Reset the connection on the next tick, discarding anything still buffered.
+    pub(crate) fn abort(&mut self) {
+        self.abort = true;
+        self.incoming.clear();
+        self.unacked.clear();
+    }
+
+    /// Shut down the read half: anything buffered is discarded, readers see EOF, and data that
+    /// arrives from now on resets the connection.
+    pub(crate) fn shutdown_read(&mut self) {
+        self.rd_closed = true;
+        self.incoming.clear();
+    }
 }
 
-fn is_between_wrapped(start: u32, x: u32, end: u32) -> bool {
-    wrapping_lt(start, x) && wrapping_lt(x, end)
-}
\ No newline at end of file
+/// Reply to a segment that does not belong to any connection with a RST (RFC 793 S3.4).
+///
+/// `data_len` is the length of the segment's payload.
+pub(crate) fn send_reset(
+    nic: &mut dyn Device,
+    ip: &crate::ip::Packet<'_>,
+    tcph: &etherparse::TcpHeaderSlice<'_>,
+    data_len: usize,
+) -> io::Result<()> {
+    let mut tcp = if tcph.ack() {
+        // <SEQ=SEG.ACK><CTL=RST>
+        etherparse::TcpHeader::new(
+            tcph.destination_port(),
+            tcph.source_port(),
+            tcph.acknowledgment_number(),
+            0,
+        )
+    } else {
+        // <SEQ=0><ACK=SEG.SEQ+SEG.LEN><CTL=RST,ACK>
+        let mut slen = data_len as u32;
+        if tcph.syn() {
+            slen += 1;
+        }
+        if tcph.fin() {
+            slen += 1;
+        }
+        let mut tcp = etherparse::TcpHeader::new(tcph.destination_port(), tcph.source_port(), 0, 0);
+        tcp.ack = true;
+        tcp.acknowledgment_number = tcph.sequence_number().wrapping_add(slen);
+        tcp
+    };
+    tcp.rst = true;
+
+    let mut ip = crate::ip::Header::new(ip.dst, ip.src, etherparse::IpTrafficClass::Tcp);
+    ip.set_payload_len(tcp.header_len() as usize)
+        .expect("empty tcp segment fits in an ip packet");
+    tcp.checksum = ip
+        .tcp_checksum(&tcp, &[])
+        .expect("failed to compute checksum");
+
+    let mut buf = Vec::with_capacity(ip.header_len() + tcp.header_len() as usize);
+    ip.write(&mut buf)?;
+    tcp.write(&mut buf)?;
+    nic.send(&buf)?;
+    Ok(())
+}
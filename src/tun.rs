@@ -0,0 +1,223 @@
+//! TUN/TAP devices, and configuration of their host side.
+//!
+//! The configuration is what `ip addr add` and `ip link set up` would otherwise do for us, done
+//! through the same ioctls so that it works with just `CAP_NET_ADMIN` on our own binary.
+
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{Device, Waker};
+
+/// A kernel TUN or TAP device.
+pub struct TunDevice {
+    iface: tun_tap::Iface,
+    /// written to to wake up a poll
+    wake: Arc<Pipe>,
+}
+
+impl TunDevice {
+    /// Creates (or attaches to) the TUN device called `name`.
+    pub fn new(name: &str) -> io::Result<Self> {
+        let iface = tun_tap::Iface::without_packet_info(name, tun_tap::Mode::Tun)?;
+        Ok(TunDevice {
+            iface,
+            wake: Arc::new(Pipe::new()?),
+        })
+    }
+
+    /// Creates (or attaches to) the TAP device called `name`, which carries Ethernet frames
+    /// rather than IP packets.
+    pub fn new_tap(name: &str) -> io::Result<Self> {
+        let iface = tun_tap::Iface::without_packet_info(name, tun_tap::Mode::Tap)?;
+        Ok(TunDevice {
+            iface,
+            wake: Arc::new(Pipe::new()?),
+        })
+    }
+
+    /// The name the kernel gave the device.
+    pub fn name(&self) -> &str {
+        self.iface.name()
+    }
+}
+
+impl AsRawFd for TunDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        self.iface.as_raw_fd()
+    }
+}
+
+impl Device for TunDevice {
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.iface.recv(buf)
+    }
+
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.iface.send(buf)
+    }
+
+    fn poll(&mut self, timeout: Duration) -> io::Result<bool> {
+        let mut pfd = [
+            nix::poll::PollFd::new(self.as_raw_fd(), nix::poll::EventFlags::POLLIN),
+            nix::poll::PollFd::new(self.wake.read, nix::poll::EventFlags::POLLIN),
+        ];
+        let timeout = i32::try_from(timeout.as_millis()).unwrap_or(i32::MAX);
+        nix::poll::poll(&mut pfd[..], timeout).map_err(|e| {
+            e.as_errno()
+                .map_or_else(|| io::Error::other(e.to_string()), io::Error::from)
+        })?;
+        if pfd[1].revents().is_some_and(|events| !events.is_empty()) {
+            self.wake.drain();
+        }
+        Ok(pfd[0].revents().is_some_and(|events| !events.is_empty()))
+    }
+
+    fn waker(&self) -> Option<Waker> {
+        let wake = self.wake.clone();
+        Some(Arc::new(move || wake.wake()))
+    }
+}
+
+/// A non-blocking pipe, which a poll of the device also waits on.
+struct Pipe {
+    read: RawFd,
+    write: RawFd,
+}
+
+impl Pipe {
+    fn new() -> io::Result<Self> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Pipe {
+            read: fds[0],
+            write: fds[1],
+        })
+    }
+
+    fn wake(&self) {
+        // if the pipe is full, there's a wake-up waiting already
+        unsafe { libc::write(self.write, [0u8].as_ptr().cast(), 1) };
+    }
+
+    fn drain(&self) {
+        let mut buf = [0u8; 64];
+        while unsafe { libc::read(self.read, buf.as_mut_ptr().cast(), buf.len()) } > 0 {}
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.read);
+            libc::close(self.write);
+        }
+    }
+}
+
+struct Socket(RawFd);
+
+impl Socket {
+    fn new(family: libc::c_int) -> io::Result<Self> {
+        let fd = unsafe { libc::socket(family, libc::SOCK_DGRAM, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Socket(fd))
+    }
+
+    fn ioctl<T>(&self, request: libc::c_ulong, arg: &mut T) -> io::Result<()> {
+        if unsafe { libc::ioctl(self.0, request as _, arg as *mut T) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+fn ifreq(name: &str) -> io::Result<libc::ifreq> {
+    if name.len() >= libc::IFNAMSIZ {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "interface name too long",
+        ));
+    }
+    let mut ifr: libc::ifreq = unsafe { std::mem::zeroed() };
+    for (dst, &src) in ifr.ifr_name.iter_mut().zip(name.as_bytes()) {
+        *dst = src as libc::c_char;
+    }
+    Ok(ifr)
+}
+
+fn sockaddr(addr: Ipv4Addr) -> libc::sockaddr {
+    let sin = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: 0,
+        sin_addr: libc::in_addr {
+            s_addr: u32::from(addr).to_be(),
+        },
+        sin_zero: [0; 8],
+    };
+    unsafe { std::mem::transmute(sin) }
+}
+
+/// Assign `addr/prefix_len` to the host side of device `name`.
+pub(crate) fn set_address(name: &str, addr: Ipv4Addr, prefix_len: u8) -> io::Result<()> {
+    let sock = Socket::new(libc::AF_INET)?;
+
+    let mut ifr = ifreq(name)?;
+    ifr.ifr_ifru.ifru_addr = sockaddr(addr);
+    sock.ioctl(libc::SIOCSIFADDR, &mut ifr)?;
+
+    let mask = u32::MAX
+        .checked_shl(32 - u32::from(prefix_len))
+        .unwrap_or(0);
+    let mut ifr = ifreq(name)?;
+    ifr.ifr_ifru.ifru_netmask = sockaddr(Ipv4Addr::from(mask));
+    sock.ioctl(libc::SIOCSIFNETMASK, &mut ifr)
+}
+
+/// Assign `addr/prefix_len` to the host side of device `name`.
+pub(crate) fn set_address6(name: &str, addr: Ipv6Addr, prefix_len: u8) -> io::Result<()> {
+    let sock = Socket::new(libc::AF_INET6)?;
+
+    // IPv6 addresses are assigned by interface index rather than name
+    let mut ifr = ifreq(name)?;
+    sock.ioctl(libc::SIOCGIFINDEX, &mut ifr)?;
+    let mut ifr6 = libc::in6_ifreq {
+        ifr6_addr: libc::in6_addr {
+            s6_addr: addr.octets(),
+        },
+        ifr6_prefixlen: u32::from(prefix_len),
+        ifr6_ifindex: unsafe { ifr.ifr_ifru.ifru_ifindex },
+    };
+    sock.ioctl(libc::SIOCSIFADDR, &mut ifr6)
+}
+
+/// Set the MTU of device `name`.
+pub(crate) fn set_mtu(name: &str, mtu: usize) -> io::Result<()> {
+    let sock = Socket::new(libc::AF_INET)?;
+    let mut ifr = ifreq(name)?;
+    ifr.ifr_ifru.ifru_mtu = mtu as libc::c_int;
+    sock.ioctl(libc::SIOCSIFMTU, &mut ifr)
+}
+
+/// Bring device `name` up.
+pub(crate) fn set_up(name: &str) -> io::Result<()> {
+    let sock = Socket::new(libc::AF_INET)?;
+    let mut ifr = ifreq(name)?;
+    sock.ioctl(libc::SIOCGIFFLAGS, &mut ifr)?;
+    unsafe {
+        ifr.ifr_ifru.ifru_flags |= (libc::IFF_UP | libc::IFF_RUNNING) as libc::c_short;
+    }
+    sock.ioctl(libc::SIOCSIFFLAGS, &mut ifr)
+}
@@ -0,0 +1,144 @@
+//! UDP (RFC 768): handing datagrams to the sockets bound on an interface, and sending the ones
+//! they queue up.
+
+use std::collections::VecDeque;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+use crate::{Device, checksum, ip};
+
+pub(crate) const PROTOCOL: u8 = 17;
+pub(crate) const HEADER_LEN: usize = 8;
+
+/// An incoming datagram.
+pub(crate) struct Datagram<'a> {
+    pub(crate) src_port: u16,
+    pub(crate) dst_port: u16,
+    /// the header and the data, without whatever follows the length the header gives
+    bytes: &'a [u8],
+}
+
+impl<'a> Datagram<'a> {
+    /// Parses the datagram in `payload`, or returns `None` if its header doesn't add up.
+    pub(crate) fn parse(payload: &'a [u8]) -> Option<Self> {
+        let header = payload.get(..HEADER_LEN)?;
+        let len = usize::from(u16::from_be_bytes([header[4], header[5]]));
+        if len < HEADER_LEN || len > payload.len() {
+            return None;
+        }
+        Some(Datagram {
+            src_port: u16::from_be_bytes([header[0], header[1]]),
+            dst_port: u16::from_be_bytes([header[2], header[3]]),
+            bytes: &payload[..len],
+        })
+    }
+
+    pub(crate) fn data(&self) -> &'a [u8] {
+        &self.bytes[HEADER_LEN..]
+    }
+
+    /// Whether the checksum of the datagram `packet` carries is correct.
+    pub(crate) fn checksum_ok(&self, packet: &ip::Packet<'_>) -> bool {
+        if self.bytes[6..8] == [0, 0] {
+            // the sender didn't compute one, which only IPv4 allows (RFC 8200 S8.1)
+            return packet.src.is_ipv4();
+        }
+        checksum::payload(packet.src, packet.dst, PROTOCOL, self.bytes)
+    }
+}
+
+/// A bound port, as the packet loop sees it.
+#[derive(Default)]
+pub(crate) struct Socket {
+    /// where datagrams go by default, and the only place they are accepted from, once connected
+    pub(crate) peer: Option<SocketAddr>,
+    /// datagrams waiting to be read, and who sent them
+    pub(crate) incoming: VecDeque<(SocketAddr, Vec<u8>)>,
+    /// datagrams waiting for the packet loop to send them, and where to
+    pub(crate) outgoing: VecDeque<(SocketAddr, Vec<u8>)>,
+    /// an error ICMP reported about something we sent, for the next read to return
+    pub(crate) error: Option<io::ErrorKind>,
+}
+
+/// Whether `queue` is too full for `len` more bytes. Like Linux, an empty queue takes a datagram
+/// of any size, so that the buffer size doesn't limit that too.
+fn is_full(queue: &VecDeque<(SocketAddr, Vec<u8>)>, len: usize, limit: usize) -> bool {
+    !queue.is_empty() && queue.iter().map(|(_, data)| data.len()).sum::<usize>() + len > limit
+}
+
+impl Socket {
+    /// Queues `data` from `from` for reading, unless the socket only listens to someone else, or
+    /// would hold more than `limit` bytes. Returns whether it was queued.
+    pub(crate) fn on_datagram(&mut self, from: SocketAddr, data: &[u8], limit: usize) -> bool {
+        if self.peer.is_some_and(|peer| peer != from) {
+            return false;
+        }
+        if is_full(&self.incoming, data.len(), limit) {
+            eprintln!(
+                "udp receive buffer full, dropping {}b from {}",
+                data.len(),
+                from
+            );
+            return false;
+        }
+        self.incoming.push_back((from, data.to_vec()));
+        true
+    }
+
+    /// Queues `data` to be sent to `to`, unless that would take more than `limit` bytes.
+    pub(crate) fn queue(&mut self, to: SocketAddr, data: &[u8], limit: usize) -> io::Result<()> {
+        if is_full(&self.outgoing, data.len(), limit) {
+            // TODO: block
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "too many bytes buffered",
+            ));
+        }
+        self.outgoing.push_back((to, data.to_vec()));
+        Ok(())
+    }
+
+    /// Sends everything queued, from `port` on whichever of `local`'s addresses matches.
+    pub(crate) fn flush(
+        &mut self,
+        nic: &mut dyn Device,
+        port: u16,
+        local: impl Fn(IpAddr) -> Option<IpAddr>,
+    ) -> io::Result<()> {
+        while let Some((to, data)) = self.outgoing.pop_front() {
+            // checked when the datagram was queued
+            let src = local(to.ip()).expect("no address to send from");
+            send(nic, (src, port), to, &data)?;
+        }
+        Ok(())
+    }
+}
+
+/// The most data a datagram from `src` may carry, so that it fits in an `mtu`-sized packet. We
+/// don't fragment what we send.
+pub(crate) fn max_data(src: IpAddr, mtu: usize) -> usize {
+    let ip_header_len = match src {
+        IpAddr::V4(_) => 20,
+        IpAddr::V6(_) => 40,
+    };
+    mtu.saturating_sub(ip_header_len + HEADER_LEN)
+}
+
+fn send(nic: &mut dyn Device, src: (IpAddr, u16), dst: SocketAddr, data: &[u8]) -> io::Result<()> {
+    let mut datagram = Vec::with_capacity(HEADER_LEN + data.len());
+    datagram.extend_from_slice(&src.1.to_be_bytes());
+    datagram.extend_from_slice(&dst.port().to_be_bytes());
+    datagram.extend_from_slice(&((HEADER_LEN + data.len()) as u16).to_be_bytes());
+    datagram.extend_from_slice(&[0, 0]);
+    datagram.extend_from_slice(data);
+    checksum::fill_udp(src.0, dst.ip(), &mut datagram);
+
+    let mut ip = ip::Header::new(src.0, dst.ip(), etherparse::IpTrafficClass::Udp);
+    ip.set_payload_len(datagram.len())
+        .expect("datagram fits in an ip packet");
+    let mut buf = Vec::with_capacity(ip.header_len() + datagram.len());
+    ip.write(&mut buf)?;
+    buf.extend_from_slice(&datagram);
+    nic.send(&buf)?;
+    Ok(())
+}
@@ -0,0 +1,92 @@
+use std::io::{ErrorKind, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use trust::{Interface, InterfaceBuilder, MemoryDevice, Mode};
+
+mod common;
+use common::{Peer, WAIT};
+
+#[test]
+fn prefix_length_must_fit_an_address() {
+    let err = InterfaceBuilder::new()
+        .address(Ipv4Addr::new(10, 0, 0, 2), 33)
+        .build()
+        .err()
+        .expect("built with a /33");
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+    let err = InterfaceBuilder::new()
+        .address("fd00::1".parse::<Ipv6Addr>().unwrap(), 129)
+        .build()
+        .err()
+        .expect("built with a /129");
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+}
+
+#[test]
+fn mtu_must_be_at_least_68() {
+    let err = InterfaceBuilder::new()
+        .mtu(67)
+        .build()
+        .err()
+        .expect("built with an MTU of 67");
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+}
+
+#[test]
+fn tap_devices_need_an_ipv4_address() {
+    let err = InterfaceBuilder::new()
+        .mode(Mode::Tap)
+        .build()
+        .err()
+        .expect("built a TAP device without an address");
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+    let err = InterfaceBuilder::new()
+        .mode(Mode::Tap)
+        .address(Ipv4Addr::new(10, 0, 0, 2), 24)
+        .address("fd00::1".parse::<Ipv6Addr>().unwrap(), 64)
+        .build()
+        .err()
+        .expect("built a TAP device with IPv6");
+    assert_eq!(err.kind(), ErrorKind::Unsupported);
+}
+
+#[test]
+fn the_device_gets_the_name_and_mtu() {
+    // not the default name, to see that it is the one used
+    let builder = InterfaceBuilder::new().name("trust1").mtu(1280);
+    let Some(_iface) = common::built(builder) else {
+        return;
+    };
+    let mtu = std::fs::read_to_string("/sys/class/net/trust1/mtu").unwrap();
+    assert_eq!(mtu.trim(), "1280");
+}
+
+#[test]
+fn the_send_buffer_size_limits_writes() {
+    let builder = InterfaceBuilder::new().send_buffer_size(100);
+    let (_iface, _listener, mut stream, _peer) = Peer::connect_with(builder);
+    assert_eq!(stream.write(&[0u8; 1000]).unwrap(), 100);
+}
+
+#[test]
+fn the_recv_buffer_size_limits_what_is_accepted() {
+    let builder = InterfaceBuilder::new().recv_buffer_size(100);
+    let (_iface, _listener, stream, mut peer) = Peer::connect_with(builder);
+    let start = peer.seq;
+    peer.send(&[0u8; 1000]);
+
+    assert_eq!(peer.recv(WAIT).unwrap().ack, start.wrapping_add(100));
+    assert_eq!(stream.peek(&mut [0u8; 1000]).unwrap(), 100);
+}
+
+#[test]
+fn without_an_address_listeners_are_bound_to_any() {
+    let (nic, _peer) = MemoryDevice::pair();
+    let mut iface = Interface::with_device(nic).unwrap();
+    let listener = iface.bind(80).unwrap();
+    assert_eq!(
+        listener.local_addr().unwrap(),
+        SocketAddr::from((Ipv4Addr::UNSPECIFIED, 80))
+    );
+}
@@ -0,0 +1,103 @@
+use std::io::{self, Read};
+use std::time::Duration;
+use trust::{Device, InterfaceBuilder, MemoryDevice};
+
+mod common;
+use common::{PEER, QUIET, WAIT};
+
+/// A device that claims to have verified the checksum of everything it receives.
+struct Offloading(MemoryDevice);
+
+impl Device for Offloading {
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.recv(buf)
+    }
+
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.send(buf)
+    }
+
+    fn poll(&mut self, timeout: Duration) -> io::Result<bool> {
+        self.0.poll(timeout)
+    }
+
+    fn checksum_verified(&self) -> bool {
+        true
+    }
+}
+
+#[test]
+fn corrupted_ip_header_is_dropped() {
+    let (nic, mut peer) = MemoryDevice::pair();
+    let mut iface = trust::Interface::with_device(nic).unwrap();
+    let _listener = iface.bind(8000).unwrap();
+
+    let mut syn = common::segment(PEER, 1000, None, true, false, &[]);
+    // the TTL
+    syn[8] ^= 0x01;
+    peer.send(&syn).unwrap();
+    assert!(common::recv(&mut peer, QUIET).is_none());
+    assert_eq!(iface.stats().ip_checksum_errors, 1);
+    assert_eq!(iface.stats().tcp_checksum_errors, 0);
+
+    peer.send(&common::segment(PEER, 1000, None, true, false, &[]))
+        .unwrap();
+    assert!(common::recv(&mut peer, WAIT).is_some());
+}
+
+#[test]
+fn corrupted_segment_is_dropped() {
+    let (nic, mut peer) = MemoryDevice::pair();
+    let mut iface = trust::Interface::with_device(nic).unwrap();
+    let mut listener = iface.bind(8000).unwrap();
+
+    peer.send(&common::segment(PEER, 1000, None, true, false, &[]))
+        .unwrap();
+    assert!(common::recv(&mut peer, WAIT).is_some());
+    peer.send(&common::segment(PEER, 1001, Some(1), false, false, &[]))
+        .unwrap();
+    let mut stream = listener.accept().unwrap();
+
+    let mut data = common::segment(PEER, 1001, Some(1), false, false, b"hello");
+    *data.last_mut().unwrap() ^= 0x20;
+    peer.send(&data).unwrap();
+    assert!(common::recv(&mut peer, QUIET).is_none());
+    assert_eq!(iface.stats().tcp_checksum_errors, 1);
+
+    // the retransmission gets through, and the corruption never did
+    peer.send(&common::segment(
+        PEER,
+        1001,
+        Some(1),
+        false,
+        false,
+        b"hello",
+    ))
+    .unwrap();
+    assert!(common::recv(&mut peer, WAIT).is_some());
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+}
+
+#[test]
+fn offloaded_checksums_are_trusted_only_if_asked() {
+    let mut syn = common::segment(PEER, 1000, None, true, false, &[]);
+    syn[8] ^= 0x01;
+
+    let (nic, mut peer) = MemoryDevice::pair();
+    let mut iface = trust::Interface::with_device(Offloading(nic)).unwrap();
+    let _listener = iface.bind(8000).unwrap();
+    peer.send(&syn).unwrap();
+    assert!(common::recv(&mut peer, QUIET).is_none());
+
+    let (nic, mut peer) = MemoryDevice::pair();
+    let mut iface = InterfaceBuilder::new()
+        .trust_checksum_offload(true)
+        .build_with_device(Offloading(nic))
+        .unwrap();
+    let _listener = iface.bind(8000).unwrap();
+    peer.send(&syn).unwrap();
+    assert!(common::recv(&mut peer, WAIT).is_some());
+    assert_eq!(iface.stats().ip_checksum_errors, 0);
+}
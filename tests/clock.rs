@@ -0,0 +1,103 @@
+use std::time::Duration;
+use trust::{Device, InterfaceBuilder, MemoryDevice, VirtualClock};
+
+mod common;
+use common::{PEER, QUIET, WAIT, recv_segment};
+
+#[test]
+fn syn_ack_retransmission_backs_off() {
+    let clock = VirtualClock::new();
+    let (nic, mut peer) = MemoryDevice::pair();
+    let mut iface = InterfaceBuilder::new()
+        .clock(clock.clone())
+        .build_with_device(nic)
+        .unwrap();
+    let _listener = iface.bind(8000).unwrap();
+
+    peer.send(&common::segment(PEER, 1000, None, true, false, &[]))
+        .unwrap();
+    assert!(recv_segment(&mut peer, WAIT).unwrap().syn);
+
+    // time stands still, so nothing is retransmitted however long we wait
+    assert!(recv_segment(&mut peer, QUIET).is_none());
+
+    // the initial RTO is 1s
+    clock.advance(Duration::from_millis(1001));
+    assert!(recv_segment(&mut peer, WAIT).unwrap().syn);
+
+    // and then it doubles
+    clock.advance(Duration::from_millis(1001));
+    iface.settle();
+    assert!(recv_segment(&mut peer, Duration::ZERO).is_none());
+    clock.advance(Duration::from_millis(1000));
+    assert!(recv_segment(&mut peer, WAIT).unwrap().syn);
+}
+
+#[test]
+fn time_wait_expires() {
+    let clock = VirtualClock::new();
+    let (nic, mut peer) = MemoryDevice::pair();
+    let mut iface = InterfaceBuilder::new()
+        .clock(clock.clone())
+        .build_with_device(nic)
+        .unwrap();
+    let mut listener = iface.bind(8000).unwrap();
+
+    peer.send(&common::segment(PEER, 1000, None, true, false, &[]))
+        .unwrap();
+    let syn_ack = recv_segment(&mut peer, WAIT).unwrap();
+    let iss = syn_ack.seq;
+    peer.send(&common::segment(
+        PEER,
+        1001,
+        Some(iss.wrapping_add(1)),
+        false,
+        false,
+        &[],
+    ))
+    .unwrap();
+
+    // we close first, so we're the ones who end up in TIME-WAIT
+    drop(listener.accept().unwrap());
+    let fin = recv_segment(&mut peer, WAIT).unwrap();
+    assert!(fin.fin);
+    peer.send(&common::segment(
+        PEER,
+        1001,
+        Some(iss.wrapping_add(2)),
+        false,
+        true,
+        &[],
+    ))
+    .unwrap();
+    let ack = recv_segment(&mut peer, WAIT).unwrap();
+    assert_eq!(ack.ack, 1002);
+
+    // a retransmitted FIN is acknowledged again while in TIME-WAIT
+    peer.send(&common::segment(
+        PEER,
+        1001,
+        Some(iss.wrapping_add(2)),
+        false,
+        true,
+        &[],
+    ))
+    .unwrap();
+    let ack = recv_segment(&mut peer, WAIT).unwrap();
+    assert!(!ack.rst);
+    assert_eq!(ack.ack, 1002);
+
+    // but once 2*MSL have passed, the connection is gone
+    clock.advance(Duration::from_secs(61));
+    iface.settle();
+    peer.send(&common::segment(
+        PEER,
+        1001,
+        Some(iss.wrapping_add(2)),
+        false,
+        true,
+        &[],
+    ))
+    .unwrap();
+    assert!(recv_segment(&mut peer, WAIT).unwrap().rst);
+}
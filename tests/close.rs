@@ -0,0 +1,135 @@
+use std::io::{Read, Write};
+use std::net::Ipv4Addr;
+use std::thread;
+use std::time::{Duration, Instant};
+use trust::{InterfaceBuilder, MemoryDevice, VirtualClock};
+
+mod common;
+use common::{Peer, QUIET, WAIT};
+
+#[test]
+fn dropping_a_stream_sends_a_fin() {
+    let (_iface, _listener, mut stream, mut peer) = Peer::connect();
+    peer.send(b"hello");
+    let mut buf = [0u8; 16];
+    assert_eq!(stream.read(&mut buf).unwrap(), 5);
+    stream.write_all(b"bye").unwrap();
+    drop(stream);
+
+    let mut data = Vec::new();
+    let fin = peer.recv_until(|s| {
+        data.extend_from_slice(&s.data);
+        s.fin || s.rst
+    });
+    assert!(fin.fin && !fin.rst);
+    assert_eq!(data, b"bye");
+
+    // and the connection is closed once the peer closes too
+    peer.fin();
+    let ack = peer.recv(WAIT).unwrap();
+    assert!(!ack.rst);
+    assert_eq!(ack.ack, peer.seq);
+}
+
+#[test]
+fn dropping_a_stream_with_unread_data_resets() {
+    let (_iface, _listener, stream, mut peer) = Peer::connect();
+    peer.send(b"unread");
+    assert_eq!(peer.recv(WAIT).unwrap().ack, peer.seq);
+    drop(stream);
+
+    let rst = peer.recv(WAIT).unwrap();
+    assert!(rst.rst && !rst.fin);
+    assert!(peer.recv(QUIET).is_none());
+}
+
+#[test]
+fn dropping_a_listener_resets_unaccepted_connections() {
+    let (nic, peer) = MemoryDevice::pair();
+    let mut iface = InterfaceBuilder::new()
+        .address(Ipv4Addr::from(common::US), 24)
+        .build_with_device(nic)
+        .unwrap();
+    let listener = iface.bind(8000).unwrap();
+    let mut peer = Peer::open(peer);
+    drop(listener);
+
+    assert!(peer.recv(WAIT).unwrap().rst);
+
+    // and nobody is listening anymore
+    let mut again = Peer {
+        nic: peer.nic,
+        seq: 5000,
+        ack: 0,
+    };
+    again.send(b"anyone?");
+    assert!(again.recv(WAIT).unwrap().rst);
+}
+
+#[test]
+fn without_linger_dropping_closes_in_the_background() {
+    let (_iface, _listener, stream, mut peer) = Peer::connect();
+    assert_eq!(stream.linger().unwrap(), None);
+    stream.set_linger(None).unwrap();
+    let start = Instant::now();
+    drop(stream);
+    assert!(start.elapsed() < QUIET);
+
+    // the FIN goes out even though nobody waits for it to be acknowledged
+    assert!(peer.recv_until(|s| s.fin || s.rst).fin);
+}
+
+#[test]
+fn lingering_waits_for_the_fin_to_be_acknowledged() {
+    let clock = VirtualClock::new();
+    let (_iface, _listener, stream, mut peer) =
+        Peer::connect_with(InterfaceBuilder::new().clock(clock.clone()));
+    stream.set_linger(Some(Duration::from_secs(5))).unwrap();
+    let dropped = thread::spawn(move || drop(stream));
+
+    assert!(peer.recv_until(|s| s.fin || s.rst).fin);
+    thread::sleep(QUIET);
+    assert!(!dropped.is_finished());
+
+    peer.send(b"");
+    dropped.join().unwrap();
+    assert!(peer.recv(QUIET).is_none());
+}
+
+#[test]
+fn lingering_resets_once_the_timeout_passes() {
+    let clock = VirtualClock::new();
+    let (_iface, _listener, stream, mut peer) =
+        Peer::connect_with(InterfaceBuilder::new().clock(clock.clone()));
+    stream.set_linger(Some(Duration::from_secs(5))).unwrap();
+    let dropped = thread::spawn(move || drop(stream));
+
+    assert!(peer.recv_until(|s| s.fin || s.rst).fin);
+    // time stands still, so the drop waits however long it takes in real time
+    clock.advance(Duration::from_millis(4900));
+    thread::sleep(QUIET);
+    assert!(!dropped.is_finished());
+
+    clock.advance(Duration::from_millis(200));
+    dropped.join().unwrap();
+    // after however many retransmissions of the FIN
+    peer.recv_until(|s| s.rst);
+}
+
+#[test]
+fn lingering_for_no_time_resets_immediately() {
+    let (_iface, _listener, mut stream, mut peer) = Peer::connect();
+    stream.set_linger(Some(Duration::ZERO)).unwrap();
+    assert_eq!(stream.linger().unwrap(), Some(Duration::ZERO));
+    // whatever hasn't been sent yet is thrown away
+    stream.write_all(b"discarded").unwrap();
+    drop(stream);
+
+    let mut data = Vec::new();
+    let rst = peer.recv_until(|s| {
+        data.extend_from_slice(&s.data);
+        s.rst || s.fin
+    });
+    assert!(rst.rst && !rst.fin);
+    assert!(data.is_empty() || data == b"discarded");
+}
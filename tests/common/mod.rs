@@ -0,0 +1,300 @@
+//! Helpers shared by the integration tests.
+//!
+//! Each test file only uses some of these, so the rest look dead to it.
+#![allow(dead_code)]
+
+pub mod tracked;
+
+use std::io;
+use std::net::Ipv6Addr;
+use std::time::Duration;
+use trust::{Device, Interface, InterfaceBuilder, MemoryDevice, TcpListener, TcpStream};
+
+/// How long to wait for something that should happen.
+pub const WAIT: Duration = Duration::from_secs(1);
+/// How long to wait to be reasonably sure that something doesn't happen.
+pub const QUIET: Duration = Duration::from_millis(100);
+
+/// Builds an interface on a real device, or returns `None` if we may not create one.
+pub fn built(builder: InterfaceBuilder) -> Option<Interface> {
+    match builder.build() {
+        Ok(iface) => Some(iface),
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::PermissionDenied | io::ErrorKind::NotFound
+            ) =>
+        {
+            eprintln!("skipping: cannot create the device: {}", e);
+            None
+        }
+        Err(e) => panic!("creating the device: {}", e),
+    }
+}
+
+/// Takes the next packet the stack sent, waiting up to `timeout` for one.
+pub fn recv(peer: &mut MemoryDevice, timeout: Duration) -> Option<Vec<u8>> {
+    if !peer.poll(timeout).unwrap() {
+        return None;
+    }
+    let mut buf = [0u8; 65535];
+    let n = peer.recv(&mut buf).unwrap();
+    Some(buf[..n].to_vec())
+}
+
+/// Takes the next segment the stack sent, waiting up to `timeout` for one.
+pub fn recv_segment(peer: &mut MemoryDevice, timeout: Duration) -> Option<Segment> {
+    Some(Segment::parse(&recv(peer, timeout)?))
+}
+
+/// A segment the stack sent, as the peer sees it.
+#[derive(Debug)]
+pub struct Segment {
+    pub seq: u32,
+    pub ack: u32,
+    pub syn: bool,
+    pub fin: bool,
+    pub rst: bool,
+    pub data: Vec<u8>,
+}
+
+impl Segment {
+    pub fn parse(packet: &[u8]) -> Segment {
+        let sliced = etherparse::SlicedPacket::from_ip(packet).unwrap();
+        let Some(etherparse::TransportSlice::Tcp(tcph)) = sliced.transport else {
+            panic!("not a segment");
+        };
+        Segment {
+            seq: tcph.sequence_number(),
+            ack: tcph.acknowledgment_number(),
+            syn: tcph.syn(),
+            fin: tcph.fin(),
+            rst: tcph.rst(),
+            data: sliced.payload.to_vec(),
+        }
+    }
+}
+
+/// The stack's address in [`Peer`] connections.
+pub const US: [u8; 4] = [10, 0, 0, 1];
+/// The peer's address in [`Peer`] connections.
+pub const PEER: [u8; 4] = [10, 0, 0, 2];
+
+/// The stack's IPv6 address, where a test gives it one.
+pub const US6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1);
+/// The peer's IPv6 address, where a test gives it one.
+pub const PEER6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2);
+
+/// Adds `data` to the one's complement sum `acc`, as the Internet checksum does (RFC 1071).
+pub fn sum(data: &[u8], mut acc: u32) -> u32 {
+    for word in data.chunks(2) {
+        acc += u32::from(u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)]));
+    }
+    acc
+}
+
+/// Folds the carries of `acc` back in.
+pub fn fold(mut acc: u32) -> u16 {
+    while acc > 0xffff {
+        acc = (acc & 0xffff) + (acc >> 16);
+    }
+    acc as u16
+}
+
+/// The sum of the pseudo-header of an ICMPv6 message of `len` bytes (RFC 8200 S8.1).
+pub fn pseudo6(src: Ipv6Addr, dst: Ipv6Addr, len: usize) -> u32 {
+    sum(&dst.octets(), sum(&src.octets(), 0)) + 58 + len as u32
+}
+
+/// Fills in the zeroed checksum of an ICMP `message`, starting from the sum of its
+/// pseudo-header, if it has one.
+pub fn icmp_checksum(message: &mut [u8], pseudo: u32) {
+    let checksum = !fold(sum(message, pseudo));
+    message[2..4].copy_from_slice(&checksum.to_be_bytes());
+}
+
+/// An IPv4 packet of `protocol` from `src` to `dst`.
+pub fn ipv4(
+    src: [u8; 4],
+    dst: [u8; 4],
+    protocol: etherparse::IpTrafficClass,
+    payload: &[u8],
+) -> Vec<u8> {
+    let ip = etherparse::Ipv4Header::new(payload.len() as u16, 64, protocol, src, dst);
+    let mut packet = Vec::new();
+    ip.write(&mut packet).unwrap();
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// An ICMP message from [`PEER`] to [`US`], with its checksum filled in.
+pub fn icmp4(kind: u8, code: u8, rest: [u8; 4], body: &[u8]) -> Vec<u8> {
+    let mut message = vec![kind, code, 0, 0];
+    message.extend_from_slice(&rest);
+    message.extend_from_slice(body);
+    icmp_checksum(&mut message, 0);
+    ipv4(PEER, US, etherparse::IpTrafficClass::Icmp, &message)
+}
+
+/// An ICMPv6 message from [`PEER6`] to [`US6`], with its checksum filled in.
+pub fn icmp6(kind: u8, rest: [u8; 4], body: &[u8]) -> Vec<u8> {
+    let mut message = vec![kind, 0, 0, 0];
+    message.extend_from_slice(&rest);
+    message.extend_from_slice(body);
+    let pseudo = pseudo6(PEER6, US6, message.len());
+    icmp_checksum(&mut message, pseudo);
+    let ip = etherparse::Ipv6Header {
+        traffic_class: 0,
+        flow_label: 0,
+        payload_length: message.len() as u16,
+        next_header: 58,
+        hop_limit: 64,
+        source: PEER6.octets(),
+        destination: US6.octets(),
+    };
+    let mut packet = Vec::new();
+    ip.write(&mut packet).unwrap();
+    packet.extend_from_slice(&message);
+    packet
+}
+
+/// A UDP datagram from port `from` of [`PEER`] to port `to` of [`US`].
+pub fn datagram(from: u16, to: u16, data: &[u8]) -> Vec<u8> {
+    datagram_to(US, from, to, data)
+}
+
+/// A UDP datagram from port `from` of [`PEER`] to port `to` of `dst`, which may be a group.
+pub fn datagram_to(dst: [u8; 4], from: u16, to: u16, data: &[u8]) -> Vec<u8> {
+    let builder = etherparse::PacketBuilder::ipv4(PEER, dst, 64).udp(from, to);
+    let mut packet = Vec::new();
+    builder.write(&mut packet, data).unwrap();
+    packet
+}
+
+/// A UDP datagram from port `from` of [`PEER6`] to port `to` of [`US6`].
+pub fn datagram6(from: u16, to: u16, data: &[u8]) -> Vec<u8> {
+    let builder = etherparse::PacketBuilder::ipv6(PEER6.octets(), US6.octets(), 64).udp(from, to);
+    let mut packet = Vec::new();
+    builder.write(&mut packet, data).unwrap();
+    packet
+}
+
+/// Builds a segment from port 40000 of `src` to port 8000 of [`US`], acknowledging `ack` if
+/// given.
+pub fn segment(
+    src: [u8; 4],
+    seq: u32,
+    ack: Option<u32>,
+    syn: bool,
+    fin: bool,
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut builder = etherparse::PacketBuilder::ipv4(src, US, 64).tcp(40000, 8000, seq, 64240);
+    if let Some(ack) = ack {
+        builder = builder.ack(ack);
+    }
+    if syn {
+        builder = builder.syn();
+    }
+    if fin {
+        builder = builder.fin();
+    }
+    let mut packet = Vec::with_capacity(builder.size(payload.len()));
+    builder.write(&mut packet, payload).unwrap();
+    packet
+}
+
+/// Builds a SYN from port 40000 of `src` to port 8000 of `dst`, over IPv6.
+pub fn syn6(src: Ipv6Addr, dst: Ipv6Addr) -> Vec<u8> {
+    let builder = etherparse::PacketBuilder::ipv6(src.octets(), dst.octets(), 64)
+        .tcp(40000, 8000, 1000, 64240)
+        .syn();
+    let mut packet = Vec::with_capacity(builder.size(0));
+    builder.write(&mut packet, &[]).unwrap();
+    packet
+}
+
+/// The other end of a single connection from port 40000 of [`PEER`] to port 8000 of [`US`],
+/// speaking TCP by hand.
+pub struct Peer {
+    pub nic: MemoryDevice,
+    /// the next sequence number we send
+    pub seq: u32,
+    /// everything before this has been received from the stack
+    pub ack: u32,
+}
+
+impl Peer {
+    /// Builds an interface on [`US`] that listens on port 8000, and connects to it.
+    pub fn connect() -> (Interface, TcpListener, TcpStream, Peer) {
+        Self::connect_with(InterfaceBuilder::new())
+    }
+
+    /// Like [`Peer::connect`], with an interface built by `builder`.
+    pub fn connect_with(builder: InterfaceBuilder) -> (Interface, TcpListener, TcpStream, Peer) {
+        let (nic, peer) = MemoryDevice::pair();
+        let mut iface = builder
+            .address(std::net::Ipv4Addr::from(US), 24)
+            .build_with_device(nic)
+            .unwrap();
+        let mut listener = iface.bind(8000).unwrap();
+        let peer = Peer::open(peer);
+        let stream = listener.accept().unwrap();
+        (iface, listener, stream, peer)
+    }
+
+    /// Connects through `nic` to a listener on the other end, without waiting for it to be
+    /// accepted.
+    pub fn open(nic: MemoryDevice) -> Peer {
+        let mut peer = Peer {
+            nic,
+            seq: 1000,
+            ack: 0,
+        };
+        peer.send_segment(true, false, &[]);
+        let syn_ack = peer.recv(WAIT).unwrap();
+        assert!(syn_ack.syn);
+        peer.send_segment(false, false, &[]);
+        peer
+    }
+
+    /// Sends `data`, acknowledging everything received so far.
+    pub fn send(&mut self, data: &[u8]) {
+        self.send_segment(false, false, data);
+    }
+
+    /// Sends a FIN, acknowledging everything received so far.
+    pub fn fin(&mut self) {
+        self.send_segment(false, true, &[]);
+    }
+
+    fn send_segment(&mut self, syn: bool, fin: bool, data: &[u8]) {
+        let ack = if syn { None } else { Some(self.ack) };
+        self.nic
+            .send(&segment(PEER, self.seq, ack, syn, fin, data))
+            .unwrap();
+        self.seq = self
+            .seq
+            .wrapping_add(data.len() as u32 + u32::from(syn) + u32::from(fin));
+    }
+
+    /// Takes the next segment the stack sent, and counts what it carried as received.
+    pub fn recv(&mut self, timeout: Duration) -> Option<Segment> {
+        let segment = recv_segment(&mut self.nic, timeout)?;
+        let len = segment.data.len() as u32 + u32::from(segment.syn) + u32::from(segment.fin);
+        if len != 0 {
+            self.ack = segment.seq.wrapping_add(len);
+        }
+        Some(segment)
+    }
+
+    /// Takes segments until one has `what`, and returns it.
+    pub fn recv_until(&mut self, mut what: impl FnMut(&Segment) -> bool) -> Segment {
+        loop {
+            let segment = self.recv(WAIT).expect("nothing sent");
+            if what(&segment) {
+                return segment;
+            }
+        }
+    }
+}
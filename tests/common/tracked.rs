@@ -0,0 +1,63 @@
+//! A device that lets a test keep up with the packet loop.
+//!
+//! The fuzz harness includes this file too, so it only depends on `trust` and `std`.
+
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use trust::{Device, MemoryDevice};
+
+/// Counts the frames the packet loop has read, those it is done with, and how often it has gone
+/// around.
+#[derive(Default)]
+pub struct Progress {
+    pub received: AtomicUsize,
+    pub done: AtomicUsize,
+    pub polls: AtomicUsize,
+}
+
+/// Keeps track of how far the packet loop has got with what we gave it.
+pub struct Tracked {
+    inner: MemoryDevice,
+    progress: Arc<Progress>,
+}
+
+impl Tracked {
+    /// Wraps `inner`, and hands back what it will keep count in.
+    pub fn new(inner: MemoryDevice) -> (Tracked, Arc<Progress>) {
+        let progress = Arc::new(Progress::default());
+        let tracked = Tracked {
+            inner,
+            progress: progress.clone(),
+        };
+        (tracked, progress)
+    }
+}
+
+impl Device for Tracked {
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.recv(buf)?;
+        self.progress.received.fetch_add(1, Ordering::SeqCst);
+        Ok(n)
+    }
+
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.send(buf)
+    }
+
+    fn poll(&mut self, timeout: Duration) -> io::Result<bool> {
+        // the packet loop only polls again once it has dealt with the last frame and ticked
+        let received = self.progress.received.load(Ordering::SeqCst);
+        self.progress.done.store(received, Ordering::SeqCst);
+        self.progress.polls.fetch_add(1, Ordering::SeqCst);
+        self.inner.poll(timeout)
+    }
+
+    fn checksum_verified(&self) -> bool {
+        // only taken at its word by interfaces built to trust checksum offload, which the fuzzer
+        // does, since it has better things to do than getting checksums right
+        true
+    }
+}
@@ -0,0 +1,205 @@
+use std::io::Read;
+use std::time::{Duration, Instant};
+use trust::{Device, Interface, InterfaceBuilder, MemoryDevice, TcpStream, VirtualClock};
+
+mod common;
+use common::{PEER, QUIET, WAIT};
+
+/// Cuts the payload of `packet` into fragments at `cuts`, which must be multiples of 8.
+fn fragment(packet: &[u8], id: u16, cuts: &[usize]) -> Vec<Vec<u8>> {
+    let (header, payload) = etherparse::Ipv4Header::read_from_slice(packet).unwrap();
+    let mut bounds = vec![0];
+    bounds.extend_from_slice(cuts);
+    bounds.push(payload.len());
+    bounds
+        .windows(2)
+        .map(|w| {
+            raw_fragment(
+                &header,
+                id,
+                w[0],
+                w[1] < payload.len(),
+                &payload[w[0]..w[1]],
+            )
+        })
+        .collect()
+}
+
+fn raw_fragment(
+    header: &etherparse::Ipv4Header,
+    id: u16,
+    offset: usize,
+    more: bool,
+    data: &[u8],
+) -> Vec<u8> {
+    let mut header = header.clone();
+    header.identification = id;
+    header.dont_fragment = false;
+    header.more_fragments = more;
+    header.fragments_offset = (offset / 8) as u16;
+    header.payload_len = data.len() as u16;
+    let mut packet = Vec::new();
+    header.write(&mut packet).unwrap();
+    packet.extend_from_slice(data);
+    packet
+}
+
+/// An interface with a connection from the peer, whose next segment starts at 1001.
+fn established(clock: VirtualClock) -> (Interface, MemoryDevice, TcpStream) {
+    let (nic, mut peer) = MemoryDevice::pair();
+    let mut iface = InterfaceBuilder::new()
+        .clock(clock)
+        .build_with_device(nic)
+        .unwrap();
+    let mut listener = iface.bind(8000).unwrap();
+    peer.send(&common::segment(PEER, 1000, None, true, false, &[]))
+        .unwrap();
+    assert!(common::recv(&mut peer, WAIT).is_some());
+    peer.send(&common::segment(PEER, 1001, Some(1), false, false, &[]))
+        .unwrap();
+    let stream = listener.accept().unwrap();
+    (iface, peer, stream)
+}
+
+fn wait_for(mut done: impl FnMut() -> bool) {
+    let deadline = Instant::now() + WAIT;
+    while !done() {
+        assert!(Instant::now() < deadline, "timed out");
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+#[test]
+fn out_of_order_fragments_are_reassembled() {
+    let (iface, mut peer, mut stream) = established(VirtualClock::new());
+    let data: Vec<u8> = (0..100).collect();
+    let mut fragments = fragment(
+        &common::segment(PEER, 1001, Some(1), false, false, &data),
+        7,
+        &[40, 80],
+    );
+    fragments.reverse();
+    // and a duplicate, which changes nothing
+    fragments.insert(1, fragments[0].clone());
+    for f in &fragments[..3] {
+        peer.send(f).unwrap();
+    }
+    assert!(common::recv(&mut peer, QUIET).is_none());
+    peer.send(&fragments[3]).unwrap();
+    assert!(common::recv(&mut peer, WAIT).is_some());
+
+    let mut buf = [0u8; 100];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(buf[..], data[..]);
+    assert_eq!(iface.stats().reassembled, 1);
+    assert_eq!(iface.stats().reassembly_failures, 0);
+}
+
+#[test]
+fn overlapping_fragments_drop_the_datagram() {
+    let (iface, mut peer, mut stream) = established(VirtualClock::new());
+    let data = [b'a'; 100];
+    let fragments = fragment(
+        &common::segment(PEER, 1001, Some(1), false, false, &data),
+        7,
+        &[40, 80],
+    );
+    let (header, _) = etherparse::Ipv4Header::read_from_slice(&fragments[0]).unwrap();
+    // rewrites the middle of the first fragment with something else
+    let overlap = raw_fragment(&header, 7, 32, true, &[b'b'; 16]);
+
+    peer.send(&fragments[0]).unwrap();
+    peer.send(&overlap).unwrap();
+    peer.send(&fragments[1]).unwrap();
+    peer.send(&fragments[2]).unwrap();
+    assert!(common::recv(&mut peer, QUIET).is_none());
+    assert_eq!(iface.stats().reassembled, 0);
+    assert_eq!(iface.stats().reassembly_failures, 1);
+
+    // the whole segment, unfragmented, gets through, and none of the overlap ever did
+    peer.send(&common::segment(PEER, 1001, Some(1), false, false, &data))
+        .unwrap();
+    assert!(common::recv(&mut peer, WAIT).is_some());
+    let mut buf = [0u8; 100];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, data);
+}
+
+#[test]
+fn incomplete_datagrams_time_out() {
+    let clock = VirtualClock::new();
+    let (iface, mut peer, _stream) = established(clock.clone());
+    let fragments = fragment(
+        &common::segment(PEER, 1001, Some(1), false, false, &[0; 100]),
+        7,
+        &[40, 80],
+    );
+    peer.send(&fragments[0]).unwrap();
+    peer.send(&fragments[1]).unwrap();
+    assert!(common::recv(&mut peer, QUIET).is_none());
+    assert_eq!(iface.stats().reassembly_failures, 0);
+
+    clock.advance(Duration::from_secs(31));
+    wait_for(|| iface.stats().reassembly_failures == 1);
+
+    // and the last fragment alone is not enough anymore
+    peer.send(&fragments[2]).unwrap();
+    assert!(common::recv(&mut peer, QUIET).is_none());
+    assert_eq!(iface.stats().reassembled, 0);
+}
+
+#[test]
+fn datagrams_larger_than_ip_allows_are_dropped() {
+    let (iface, mut peer, _stream) = established(VirtualClock::new());
+    let (header, _) = etherparse::Ipv4Header::read_from_slice(&common::segment(
+        PEER,
+        1001,
+        None,
+        false,
+        false,
+        &[],
+    ))
+    .unwrap();
+    peer.send(&raw_fragment(&header, 7, 0, true, &[0; 64]))
+        .unwrap();
+    peer.send(&raw_fragment(&header, 7, 65528, false, &[0; 16]))
+        .unwrap();
+    wait_for(|| iface.stats().reassembly_failures == 1);
+    assert_eq!(iface.stats().reassembled, 0);
+}
+
+#[test]
+fn each_source_gets_limited_memory() {
+    let (iface, mut peer, mut stream) = established(VirtualClock::new());
+
+    // fragments far into their datagrams take up a lot of memory for what they carry
+    let (greedy, _) = etherparse::Ipv4Header::read_from_slice(&common::segment(
+        [10, 0, 0, 3],
+        0,
+        None,
+        false,
+        false,
+        &[],
+    ))
+    .unwrap();
+    for id in 0..5 {
+        peer.send(&raw_fragment(&greedy, id, 60000, true, &[0; 8]))
+            .unwrap();
+    }
+    wait_for(|| iface.stats().reassembly_failures == 1);
+
+    // while others are unaffected
+    let data = [b'x'; 100];
+    for f in fragment(
+        &common::segment(PEER, 1001, Some(1), false, false, &data),
+        7,
+        &[40],
+    ) {
+        peer.send(&f).unwrap();
+    }
+    assert!(common::recv(&mut peer, WAIT).is_some());
+    let mut buf = [0u8; 100];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, data);
+    assert_eq!(iface.stats().reassembled, 1);
+}
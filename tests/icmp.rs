@@ -0,0 +1,238 @@
+use std::io::{ErrorKind, Write};
+use std::thread;
+use std::time::Duration;
+use trust::{Device, Interface, InterfaceBuilder, MemoryDevice, VirtualClock};
+
+mod common;
+use common::{PEER, PEER6, QUIET, US, US6, WAIT, fold, icmp4, icmp6, ipv4, pseudo6, recv, sum};
+
+/// The ICMPv4 message in `packet`, after checking that it is one, and that its checksum is right.
+fn icmp4_message(packet: &[u8]) -> Vec<u8> {
+    let (ip, message) = etherparse::Ipv4Header::read_from_slice(packet).unwrap();
+    assert_eq!(ip.protocol, 1);
+    assert_eq!((ip.source, ip.destination), (US, PEER));
+    assert_eq!(fold(sum(message, 0)), 0xffff, "bad checksum");
+    message.to_vec()
+}
+
+fn icmp6_message(packet: &[u8]) -> Vec<u8> {
+    let (ip, message) = etherparse::Ipv6Header::read_from_slice(packet).unwrap();
+    assert_eq!(ip.next_header, 58);
+    assert_eq!((ip.source, ip.destination), (US6.octets(), PEER6.octets()));
+    assert_eq!(
+        fold(sum(message, pseudo6(US6, PEER6, message.len()))),
+        0xffff,
+        "bad checksum"
+    );
+    message.to_vec()
+}
+
+#[test]
+fn pings_are_answered() {
+    let (nic, mut peer) = MemoryDevice::pair();
+    let _iface = Interface::with_device(nic).unwrap();
+
+    peer.send(&icmp4(8, 0, [0x12, 0x34, 0, 1], b"ping"))
+        .unwrap();
+    let reply = icmp4_message(&recv(&mut peer, WAIT).unwrap());
+    assert_eq!(reply[..2], [0, 0]);
+    assert_eq!(reply[4..], [0x12, 0x34, 0, 1, b'p', b'i', b'n', b'g']);
+}
+
+#[test]
+fn ipv6_pings_are_answered() {
+    let (nic, mut peer) = MemoryDevice::pair();
+    let _iface = InterfaceBuilder::new()
+        .address(US6, 64)
+        .build_with_device(nic)
+        .unwrap();
+
+    peer.send(&icmp6(128, [0x12, 0x34, 0, 1], b"ping")).unwrap();
+    let reply = icmp6_message(&recv(&mut peer, WAIT).unwrap());
+    assert_eq!(reply[..2], [129, 0]);
+    assert_eq!(reply[4..], [0x12, 0x34, 0, 1, b'p', b'i', b'n', b'g']);
+}
+
+#[test]
+fn pings_with_bad_checksums_are_ignored() {
+    let (nic, mut peer) = MemoryDevice::pair();
+    let iface = Interface::with_device(nic).unwrap();
+
+    let mut ping = icmp4(8, 0, [0x12, 0x34, 0, 1], b"ping");
+    *ping.last_mut().unwrap() ^= 0x01;
+    peer.send(&ping).unwrap();
+    assert!(recv(&mut peer, QUIET).is_none());
+    assert_eq!(iface.stats().icmp_checksum_errors, 1);
+}
+
+#[test]
+fn unknown_protocols_are_unreachable() {
+    let (nic, mut peer) = MemoryDevice::pair();
+    let _iface = Interface::with_device(nic).unwrap();
+
+    let packet = ipv4(
+        PEER,
+        US,
+        etherparse::IpTrafficClass::ExperimentalAndTesting0,
+        &[0xaa; 32],
+    );
+    peer.send(&packet).unwrap();
+    let error = icmp4_message(&recv(&mut peer, WAIT).unwrap());
+    assert_eq!(error[..2], [3, 2]);
+    // the header, and the first 8 bytes of what it carried
+    assert_eq!(error[8..], packet[..28]);
+}
+
+#[test]
+fn unknown_next_headers_are_a_parameter_problem() {
+    let (nic, mut peer) = MemoryDevice::pair();
+    let _iface = InterfaceBuilder::new()
+        .address(US6, 64)
+        .build_with_device(nic)
+        .unwrap();
+
+    let mut packet = icmp6(128, [0; 4], &[0xaa; 32]);
+    packet[6] = 253;
+    peer.send(&packet).unwrap();
+    let error = icmp6_message(&recv(&mut peer, WAIT).unwrap());
+    assert_eq!(error[..2], [4, 1]);
+    // pointing at the next header field
+    assert_eq!(error[4..8], [0, 0, 0, 6]);
+    assert_eq!(error[8..], packet[..]);
+}
+
+#[test]
+fn errors_are_not_sent_to_broadcasts() {
+    let (nic, mut peer) = MemoryDevice::pair();
+    let _iface = Interface::with_device(nic).unwrap();
+
+    let experimental = || etherparse::IpTrafficClass::ExperimentalAndTesting0;
+    peer.send(&ipv4(PEER, [255, 255, 255, 255], experimental(), &[0; 8]))
+        .unwrap();
+    peer.send(&ipv4([0, 0, 0, 0], US, experimental(), &[0; 8]))
+        .unwrap();
+    assert!(recv(&mut peer, QUIET).is_none());
+}
+
+#[test]
+fn errors_are_rate_limited() {
+    let clock = VirtualClock::new();
+    let (nic, mut peer) = MemoryDevice::pair();
+    let _iface = InterfaceBuilder::new()
+        .clock(clock.clone())
+        .build_with_device(nic)
+        .unwrap();
+
+    let packet = ipv4(
+        PEER,
+        US,
+        etherparse::IpTrafficClass::ExperimentalAndTesting0,
+        &[0; 8],
+    );
+    let mut errors = 0;
+    for _ in 0..15 {
+        peer.send(&packet).unwrap();
+    }
+    while recv(&mut peer, QUIET).is_some() {
+        errors += 1;
+    }
+    assert_eq!(errors, 10);
+
+    clock.advance(Duration::from_millis(100));
+    peer.send(&packet).unwrap();
+    peer.send(&packet).unwrap();
+    assert!(recv(&mut peer, WAIT).is_some());
+    assert!(recv(&mut peer, QUIET).is_none());
+}
+
+type Connecting = thread::JoinHandle<(Interface, std::io::Result<trust::TcpStream>)>;
+
+/// Starts connecting from an interface at [`US`] to port 80 of the peer, and returns the SYN.
+fn connect() -> (MemoryDevice, Connecting, Vec<u8>) {
+    let (nic, mut peer) = MemoryDevice::pair();
+    let mut iface = InterfaceBuilder::new()
+        .address(US, 24)
+        .build_with_device(nic)
+        .unwrap();
+    let jh = thread::spawn(move || {
+        let r = iface.connect("10.0.0.2:80".parse().unwrap());
+        (iface, r)
+    });
+    let syn = recv(&mut peer, WAIT).unwrap();
+    (peer, jh, syn)
+}
+
+#[test]
+fn hard_errors_abort_connecting() {
+    let (mut peer, jh, syn) = connect();
+    peer.send(&icmp4(3, 3, [0; 4], &syn[..28])).unwrap();
+    let (_iface, r) = jh.join().unwrap();
+    assert_eq!(r.err().unwrap().kind(), ErrorKind::ConnectionRefused);
+}
+
+#[test]
+fn errors_about_segments_not_in_flight_are_ignored() {
+    let (mut peer, jh, syn) = connect();
+    let mut quoted = syn[..28].to_vec();
+    // a sequence number we never sent
+    quoted[24..28].copy_from_slice(&12345u32.to_be_bytes());
+    peer.send(&icmp4(3, 3, [0; 4], &quoted)).unwrap();
+
+    let tcph = etherparse::TcpHeaderSlice::from_slice(&syn[20..]).unwrap();
+    let syn_ack = etherparse::PacketBuilder::ipv4(PEER, US, 64)
+        .tcp(80, tcph.source_port(), 1000, 64240)
+        .syn()
+        .ack(tcph.sequence_number() + 1);
+    let mut packet = Vec::new();
+    syn_ack.write(&mut packet, &[]).unwrap();
+    peer.send(&packet).unwrap();
+    let (_iface, r) = jh.join().unwrap();
+    assert!(r.is_ok());
+}
+
+#[test]
+fn soft_errors_are_reported_but_not_fatal() {
+    let (mut peer, jh, syn) = connect();
+    let tcph = etherparse::TcpHeaderSlice::from_slice(&syn[20..]).unwrap();
+    let (port, iss) = (tcph.source_port(), tcph.sequence_number());
+    let segment = |syn: bool, ack: u32| {
+        let mut builder = etherparse::PacketBuilder::ipv4(PEER, US, 64).tcp(
+            80,
+            port,
+            if syn { 1000 } else { 1001 },
+            64240,
+        );
+        if syn {
+            builder = builder.syn();
+        }
+        let mut packet = Vec::new();
+        builder.ack(ack).write(&mut packet, &[]).unwrap();
+        packet
+    };
+    peer.send(&segment(true, iss + 1)).unwrap();
+    let (_iface, r) = jh.join().unwrap();
+    let mut stream = r.unwrap();
+    assert!(recv(&mut peer, WAIT).is_some());
+
+    stream.write_all(b"hello").unwrap();
+    let data = recv(&mut peer, WAIT).unwrap();
+    // time exceeded in transit
+    peer.send(&icmp4(11, 0, [0; 4], &data[..28])).unwrap();
+    let deadline = std::time::Instant::now() + WAIT;
+    let err = loop {
+        if let Some(err) = stream.take_error().unwrap() {
+            break err;
+        }
+        assert!(std::time::Instant::now() < deadline, "no error reported");
+        thread::sleep(Duration::from_millis(1));
+    };
+    assert_eq!(err.kind(), ErrorKind::HostUnreachable);
+    assert!(stream.take_error().unwrap().is_none());
+
+    // and the connection carries on
+    peer.send(&segment(false, iss + 6)).unwrap();
+    assert!(recv(&mut peer, QUIET).is_none());
+    assert_eq!(stream.state().unwrap(), trust::State::Estab);
+    stream.write_all(b"again").unwrap();
+    assert!(recv(&mut peer, WAIT).is_some());
+}
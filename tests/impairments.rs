@@ -0,0 +1,137 @@
+use std::io::prelude::*;
+use std::net::Ipv4Addr;
+use std::thread;
+use std::time::{Duration, Instant};
+use trust::sim::{Impaired, Impairments, Link};
+use trust::{Device, InterfaceBuilder, MemoryDevice};
+
+/// Sends 100 numbered packets through `impairments` and returns what comes out the other end.
+fn transmit(impairments: Impairments) -> Vec<Vec<u8>> {
+    let (a, mut b) = MemoryDevice::pair();
+    let mut a = Impaired::new(a, impairments);
+    for i in 0..100u8 {
+        a.send(&[i; 16]).unwrap();
+    }
+
+    let mut received = Vec::new();
+    let mut buf = [0u8; 64];
+    let deadline = Instant::now() + Duration::from_millis(200);
+    while Instant::now() < deadline {
+        a.poll(Duration::from_millis(1)).unwrap();
+        while let Ok(n) = b.recv(&mut buf) {
+            received.push(buf[..n].to_vec());
+        }
+    }
+    received
+}
+
+#[test]
+fn loss_is_seeded() {
+    let lossy = || transmit(Impairments::new().seed(7).loss(0.3));
+    let received = lossy();
+    assert!(
+        received.len() > 50 && received.len() < 90,
+        "{}",
+        received.len()
+    );
+    assert_eq!(received, lossy());
+    assert_ne!(received, transmit(Impairments::new().seed(8).loss(0.3)));
+}
+
+#[test]
+fn duplication() {
+    let received = transmit(Impairments::new().duplicate(1.0));
+    assert_eq!(received.len(), 200);
+    assert!(received.chunks(2).all(|p| p[0] == p[1]));
+}
+
+#[test]
+fn corruption_flips_one_bit() {
+    let received = transmit(Impairments::new().corrupt(1.0));
+    assert_eq!(received.len(), 100);
+    for (i, packet) in received.iter().enumerate() {
+        let flipped: u32 = packet.iter().map(|b| (b ^ i as u8).count_ones()).sum();
+        assert_eq!(flipped, 1);
+    }
+}
+
+#[test]
+fn reordering() {
+    let received = transmit(
+        Impairments::new()
+            .seed(1)
+            .reorder(0.2, Duration::from_millis(20)),
+    );
+    assert_eq!(received.len(), 100);
+    assert!(received.windows(2).any(|w| w[0][0] > w[1][0]));
+}
+
+#[test]
+fn delay_and_bandwidth() {
+    let (a, mut b) = MemoryDevice::pair();
+    let mut a = Impaired::new(
+        a,
+        Impairments::new()
+            .delay(Duration::from_millis(20))
+            .bandwidth(100_000),
+    );
+    let start = Instant::now();
+    for _ in 0..10 {
+        // 1000 bytes at 100kB/s takes 10ms each
+        a.send(&[0; 1000]).unwrap();
+    }
+
+    let mut buf = [0u8; 1000];
+    let mut arrivals = Vec::new();
+    while arrivals.len() < 10 {
+        a.poll(Duration::from_millis(1)).unwrap();
+        while b.recv(&mut buf).is_ok() {
+            arrivals.push(start.elapsed());
+        }
+    }
+    assert!(arrivals[0] >= Duration::from_millis(30));
+    assert!(arrivals[9] >= Duration::from_millis(120));
+}
+
+#[test]
+fn transfer_over_bad_link() {
+    let (mut server, mut client) = Link::new()
+        .impair(
+            Impairments::new()
+                .seed(42)
+                .loss(0.05)
+                .delay(Duration::from_millis(1))
+                .jitter(Duration::from_millis(2))
+                .reorder(0.05, Duration::from_millis(5))
+                .duplicate(0.05),
+        )
+        .build(
+            InterfaceBuilder::new().address(Ipv4Addr::new(10, 0, 0, 1), 24),
+            InterfaceBuilder::new().address(Ipv4Addr::new(10, 0, 0, 2), 24),
+        )
+        .unwrap();
+
+    let mut listener = server.bind(9).unwrap();
+    let jh = thread::spawn(move || {
+        let mut stream = listener.accept().unwrap();
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).unwrap();
+        buf
+    });
+
+    let data: Vec<u8> = (0..50_000u32).map(|i| (i % 251) as u8).collect();
+    let mut stream = client.connect("10.0.0.1:9".parse().unwrap()).unwrap();
+    let mut sent = 0;
+    while sent < data.len() {
+        match stream.write(&data[sent..]) {
+            Ok(n) => sent += n,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(1))
+            }
+            Err(e) => panic!("{}", e),
+        }
+    }
+    stream.shutdown(std::net::Shutdown::Write).unwrap();
+
+    assert_eq!(jh.join().unwrap(), data);
+}
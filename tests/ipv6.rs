@@ -0,0 +1,133 @@
+use std::io::prelude::*;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::thread;
+use trust::{Device, InterfaceBuilder, MemoryDevice, sim::Link};
+
+mod common;
+use common::{QUIET, WAIT};
+
+const SERVER: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1);
+const CLIENT: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2);
+
+#[test]
+fn echo() {
+    let (mut server, mut client) = Link::new()
+        .build(
+            InterfaceBuilder::new().address(SERVER, 64),
+            InterfaceBuilder::new().address(CLIENT, 64),
+        )
+        .unwrap();
+    let mut listener = server.bind(7).unwrap();
+    assert_eq!(
+        listener.local_addr().unwrap(),
+        "[fd00::1]:7".parse().unwrap()
+    );
+    let jh = thread::spawn(move || {
+        let mut stream = listener.accept().unwrap();
+        assert_eq!(stream.peer_addr().unwrap().ip(), CLIENT);
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).unwrap();
+        stream.write_all(&buf).unwrap();
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+        buf
+    });
+
+    let mut stream = client.connect("[fd00::1]:7".parse().unwrap()).unwrap();
+    assert_eq!(stream.peer_addr().unwrap(), "[fd00::1]:7".parse().unwrap());
+    assert_eq!(stream.local_addr().unwrap().ip(), CLIENT);
+    stream.write_all(b"hello, world").unwrap();
+    stream.shutdown(std::net::Shutdown::Write).unwrap();
+
+    let mut echoed = Vec::new();
+    stream.read_to_end(&mut echoed).unwrap();
+    assert_eq!(echoed, b"hello, world");
+    assert_eq!(jh.join().unwrap(), b"hello, world");
+}
+
+#[test]
+fn listeners_accept_both_families() {
+    let (mut server, mut client) = Link::new()
+        .build(
+            InterfaceBuilder::new()
+                .address(Ipv4Addr::new(10, 0, 0, 1), 24)
+                .address(SERVER, 64),
+            InterfaceBuilder::new()
+                .address(Ipv4Addr::new(10, 0, 0, 2), 24)
+                .address(CLIENT, 64),
+        )
+        .unwrap();
+    let mut listener = server.bind(7).unwrap();
+    assert_eq!(listener.local_addr().unwrap(), "[::]:7".parse().unwrap());
+
+    let targets: [SocketAddr; 2] = [
+        "10.0.0.1:7".parse().unwrap(),
+        "[fd00::1]:7".parse().unwrap(),
+    ];
+    for target in targets {
+        let mut stream = client.connect(target).unwrap();
+        let accepted = listener.accept().unwrap();
+        assert_eq!(accepted.local_addr().unwrap(), target);
+        assert_eq!(accepted.peer_addr().unwrap(), stream.local_addr().unwrap());
+        stream.write_all(b"hi").unwrap();
+        let mut buf = [0u8; 2];
+        accepted.try_clone().unwrap().read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hi");
+    }
+}
+
+#[test]
+fn connect_needs_an_address_of_the_same_family() {
+    let (_server, mut client) = Link::new()
+        .build(
+            InterfaceBuilder::new().address(SERVER, 64),
+            InterfaceBuilder::new().address(Ipv4Addr::new(10, 0, 0, 2), 24),
+        )
+        .unwrap();
+    let err = client
+        .connect("[fd00::1]:7".parse().unwrap())
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::AddrNotAvailable);
+}
+
+#[test]
+fn checksums_cover_the_pseudo_header() {
+    let (nic, mut peer) = MemoryDevice::pair();
+    let mut iface = InterfaceBuilder::new()
+        .address(SERVER, 64)
+        .build_with_device(nic)
+        .unwrap();
+    let _listener = iface.bind(8000).unwrap();
+
+    // the segment is intact, but the address it was checksummed with is not
+    let mut corrupted = common::syn6(CLIENT, SERVER);
+    corrupted[8 + 15] ^= 0x03;
+    peer.send(&corrupted).unwrap();
+    assert!(!peer.poll(QUIET).unwrap());
+    assert_eq!(iface.stats().tcp_checksum_errors, 1);
+
+    peer.send(&common::syn6(CLIENT, SERVER)).unwrap();
+    assert!(peer.poll(WAIT).unwrap());
+    let mut buf = [0u8; 1500];
+    let n = peer.recv(&mut buf).unwrap();
+    let (ip, rest) = etherparse::Ipv6Header::read_from_slice(&buf[..n]).unwrap();
+    assert_eq!(
+        (ip.source, ip.destination),
+        (SERVER.octets(), CLIENT.octets())
+    );
+    let (tcp, payload) = etherparse::TcpHeader::read_from_slice(rest).unwrap();
+    assert!(tcp.syn && tcp.ack);
+    assert_eq!(tcp.checksum, tcp.calc_checksum_ipv6(&ip, payload).unwrap());
+}
+
+#[test]
+fn ipv6_needs_a_bigger_mtu() {
+    let (nic, _peer) = MemoryDevice::pair();
+    let err = InterfaceBuilder::new()
+        .address(SERVER, 64)
+        .mtu(1000)
+        .build_with_device(nic)
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
@@ -0,0 +1,36 @@
+use trust::{Device, MemoryDevice};
+
+mod common;
+use common::{PEER, WAIT};
+
+#[test]
+fn syn_ack_without_tun() {
+    let (nic, mut peer) = MemoryDevice::pair();
+    let mut iface = trust::Interface::with_device(nic).unwrap();
+    let _listener = iface.bind(8000).unwrap();
+
+    peer.send(&common::segment(PEER, 1000, None, true, false, &[]))
+        .unwrap();
+
+    let packet = common::recv(&mut peer, WAIT).unwrap();
+    let iph = etherparse::Ipv4HeaderSlice::from_slice(&packet).unwrap();
+    let tcph = etherparse::TcpHeaderSlice::from_slice(&packet[iph.slice().len()..]).unwrap();
+    assert!(tcph.syn());
+    assert!(tcph.ack());
+    assert_eq!(tcph.acknowledgment_number(), 1001);
+    assert_eq!(tcph.source_port(), 8000);
+    assert_eq!(tcph.destination_port(), 40000);
+}
+
+#[test]
+fn polls_can_be_woken() {
+    let (mut nic, _peer) = MemoryDevice::pair();
+    let wake = nic.waker().unwrap();
+    let jh = std::thread::spawn(move || nic.poll(WAIT * 10).unwrap());
+    std::thread::sleep(common::QUIET);
+    let started = std::time::Instant::now();
+    wake();
+    // woken, without a packet to show for it
+    assert!(!jh.join().unwrap());
+    assert!(started.elapsed() < WAIT);
+}
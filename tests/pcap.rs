@@ -0,0 +1,155 @@
+use std::io::prelude::*;
+use std::net::Ipv4Addr;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use trust::{InterfaceBuilder, MemoryDevice, VirtualClock, sim::Link};
+
+mod common;
+
+/// Splits a pcap file into its packets, with their timestamps.
+fn packets(file: &[u8]) -> Vec<(Duration, Vec<u8>)> {
+    let u32_at = |at: usize| u32::from_le_bytes(file[at..at + 4].try_into().unwrap());
+    assert_eq!(u32_at(0), 0xa1b2_3c4d);
+    assert_eq!(u32_at(20), 101);
+
+    let mut packets = Vec::new();
+    let mut at = 24;
+    while at < file.len() {
+        let time = Duration::new(u32_at(at) as u64, u32_at(at + 4));
+        let len = u32_at(at + 8) as usize;
+        assert_eq!(len, u32_at(at + 12) as usize);
+        packets.push((time, file[at + 16..at + 16 + len].to_vec()));
+        at += 16 + len;
+    }
+    packets
+}
+
+#[test]
+fn capture_in_memory_traffic() {
+    let path = std::env::temp_dir().join(format!("trust-capture-{}.pcap", std::process::id()));
+    let start = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+
+    {
+        let (mut server, mut client) = Link::new()
+            .build(
+                InterfaceBuilder::new().address(Ipv4Addr::new(10, 0, 0, 1), 24),
+                InterfaceBuilder::new()
+                    .address(Ipv4Addr::new(10, 0, 0, 2), 24)
+                    .capture(&path),
+            )
+            .unwrap();
+        let mut listener = server.bind(7).unwrap();
+        let jh = thread::spawn(move || {
+            let mut stream = listener.accept().unwrap();
+            stream.write_all(b"hello").unwrap();
+            stream.shutdown(std::net::Shutdown::Write).unwrap();
+            stream.read_to_end(&mut Vec::new()).unwrap();
+        });
+
+        let mut stream = client.connect("10.0.0.1:7".parse().unwrap()).unwrap();
+        stream.read_to_end(&mut Vec::new()).unwrap();
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+        jh.join().unwrap();
+    }
+
+    let packets = packets(&std::fs::read(&path).unwrap());
+    std::fs::remove_file(&path).unwrap();
+
+    // SYN, SYN-ACK, ACK at the very least, and then the data
+    assert!(packets.len() > 4);
+    let (_, syn) = &packets[0];
+    let iph = etherparse::Ipv4HeaderSlice::from_slice(syn).unwrap();
+    let tcph = etherparse::TcpHeaderSlice::from_slice(&syn[iph.slice().len()..]).unwrap();
+    assert_eq!(iph.source_addr(), Ipv4Addr::new(10, 0, 0, 2));
+    assert!(tcph.syn() && !tcph.ack());
+
+    // both directions are recorded
+    assert!(packets.iter().any(|(_, p)| p[12..16] == [10, 0, 0, 1]));
+    assert!(packets.iter().any(|(_, p)| p.ends_with(b"hello")));
+
+    assert!(packets[0].0 >= start);
+    assert!(packets.windows(2).all(|w| w[0].0 <= w[1].0));
+}
+
+#[test]
+fn capture_timestamps_follow_the_interface_clock() {
+    let path =
+        std::env::temp_dir().join(format!("trust-capture-clock-{}.pcap", std::process::id()));
+    let clock = VirtualClock::new();
+
+    {
+        let (mut server, mut client) = Link::new()
+            .clock(clock.clone())
+            .build(
+                InterfaceBuilder::new().address(Ipv4Addr::new(10, 0, 0, 1), 24),
+                InterfaceBuilder::new()
+                    .address(Ipv4Addr::new(10, 0, 0, 2), 24)
+                    .capture(&path),
+            )
+            .unwrap();
+        let mut listener = server.bind(7).unwrap();
+        let mut stream = client.connect("10.0.0.1:7".parse().unwrap()).unwrap();
+        let mut accepted = listener.accept().unwrap();
+
+        clock.advance(Duration::from_secs(30));
+        stream.write_all(b"later").unwrap();
+        let mut buf = [0u8; 5];
+        accepted.read_exact(&mut buf).unwrap();
+    }
+
+    let packets = packets(&std::fs::read(&path).unwrap());
+    std::fs::remove_file(&path).unwrap();
+
+    let (syn_at, _) = &packets[0];
+    let (data_at, _) = packets.iter().find(|(_, p)| p.ends_with(b"later")).unwrap();
+    // virtual time moved on by 30s, however little real time it took
+    let elapsed = *data_at - *syn_at;
+    assert!(
+        elapsed >= Duration::from_secs(30) && elapsed < Duration::from_secs(31),
+        "{:?}",
+        elapsed
+    );
+}
+
+/// Takes a pcap file header and part of a packet record, and then runs out of room.
+struct Full {
+    room: usize,
+}
+
+impl Write for Full {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.room == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::StorageFull,
+                "disk full",
+            ));
+        }
+        let n = std::cmp::min(buf.len(), self.room);
+        self.room -= n;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn traffic_keeps_flowing_when_the_capture_fails() {
+    let (nic, peer) = MemoryDevice::pair();
+    let writer = trust::pcap::Writer::new(Full { room: 24 + 8 }).unwrap();
+    let nic = trust::pcap::Capture::new(nic, writer);
+    let mut iface = InterfaceBuilder::new()
+        .address(Ipv4Addr::from(common::US), 24)
+        .build_with_device(nic)
+        .unwrap();
+    let mut listener = iface.bind(8000).unwrap();
+    let mut peer = common::Peer::open(peer);
+    let mut stream = listener.accept().unwrap();
+
+    peer.send(b"hello");
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).unwrap();
+    stream.write_all(b"world").unwrap();
+    assert_eq!(peer.recv_until(|s| !s.data.is_empty()).data, b"world");
+}
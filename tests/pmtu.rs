@@ -0,0 +1,382 @@
+use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr};
+use std::thread;
+use std::time::Duration;
+use trust::{
+    Device, Interface, InterfaceBuilder, MemoryDevice, TcpListener, TcpStream, VirtualClock,
+};
+
+mod common;
+use common::{PEER6, QUIET, US6, WAIT, icmp_checksum, pseudo6, recv};
+
+const US: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const PEER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+/// The other end of the link, with connections from `addr` to port 8000 of `us`.
+struct Peer {
+    nic: MemoryDevice,
+    addr: IpAddr,
+    us: IpAddr,
+}
+
+/// A segment we sent, as the peer sees it.
+#[derive(Debug)]
+struct Segment {
+    packet: Vec<u8>,
+    seq: u32,
+    len: usize,
+    mss: Option<u16>,
+}
+
+impl Peer {
+    /// A SYN from `port` that carries `mss`, if any.
+    fn syn(&self, port: u16, mss: Option<u16>) -> Vec<u8> {
+        let options: Vec<_> = mss
+            .map(etherparse::TcpOptionElement::MaximumSegmentSize)
+            .into_iter()
+            .collect();
+        self.build(port, 1000, None, Some(&options))
+    }
+
+    /// An ACK from `port`, of everything before `ack`.
+    fn ack(&self, port: u16, ack: u32) -> Vec<u8> {
+        self.build(port, 1001, Some(ack), None)
+    }
+
+    fn build(
+        &self,
+        port: u16,
+        seq: u32,
+        ack: Option<u32>,
+        syn: Option<&[etherparse::TcpOptionElement]>,
+    ) -> Vec<u8> {
+        let builder = match (self.addr, self.us) {
+            (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                etherparse::PacketBuilder::ipv4(src.octets(), dst.octets(), 64)
+            }
+            (IpAddr::V6(src), IpAddr::V6(dst)) => {
+                etherparse::PacketBuilder::ipv6(src.octets(), dst.octets(), 64)
+            }
+            _ => unreachable!(),
+        };
+        let mut builder = builder.tcp(port, 8000, seq, 64240);
+        if let Some(ack) = ack {
+            builder = builder.ack(ack);
+        }
+        if let Some(options) = syn {
+            builder = builder.syn().options(options).unwrap();
+        }
+        let mut packet = Vec::with_capacity(builder.size(0));
+        builder.write(&mut packet, &[]).unwrap();
+        packet
+    }
+
+    fn recv(&mut self, timeout: Duration) -> Option<Segment> {
+        if !self.nic.poll(timeout).unwrap() {
+            return None;
+        }
+        let mut buf = [0u8; 2000];
+        let n = self.nic.recv(&mut buf).unwrap();
+        let packet = buf[..n].to_vec();
+        let sliced = etherparse::SlicedPacket::from_ip(&packet).unwrap();
+        let Some(etherparse::TransportSlice::Tcp(tcph)) = sliced.transport else {
+            panic!("not a segment");
+        };
+        let mss = tcph.options_iterator().find_map(|option| match option {
+            Ok(etherparse::TcpOptionElement::MaximumSegmentSize(mss)) => Some(mss),
+            _ => None,
+        });
+        let (seq, len) = (tcph.sequence_number(), sliced.payload.len());
+        Some(Segment {
+            packet,
+            seq,
+            len,
+            mss,
+        })
+    }
+
+    /// Connects from `port` with a SYN that carries `mss`, if any, and returns the SYN-ACK.
+    fn open(
+        &mut self,
+        listener: &mut TcpListener,
+        port: u16,
+        mss: Option<u16>,
+    ) -> (TcpStream, Segment) {
+        self.nic.send(&self.syn(port, mss)).unwrap();
+        let syn_ack = self.recv(WAIT).unwrap();
+        self.nic.send(&self.ack(port, 1)).unwrap();
+        (listener.accept().unwrap(), syn_ack)
+    }
+
+    /// Tells us that `packet` was too big for a path that takes `mtu` bytes.
+    fn packet_too_big(&mut self, packet: &[u8], mtu: u32) {
+        let message = match self.addr {
+            IpAddr::V4(_) => {
+                let mut message = vec![3, 4, 0, 0, 0, 0];
+                message.extend_from_slice(&(mtu as u16).to_be_bytes());
+                message.extend_from_slice(&packet[..28]);
+                message
+            }
+            IpAddr::V6(_) => {
+                let mut message = vec![2, 0, 0, 0];
+                message.extend_from_slice(&mtu.to_be_bytes());
+                message.extend_from_slice(&packet[..std::cmp::min(packet.len(), 1232)]);
+                message
+            }
+        };
+        self.icmp(message);
+    }
+
+    fn icmp(&mut self, mut message: Vec<u8>) {
+        let pseudo = match (self.addr, self.us) {
+            (IpAddr::V6(src), IpAddr::V6(dst)) => pseudo6(src, dst, message.len()),
+            _ => 0,
+        };
+        icmp_checksum(&mut message, pseudo);
+
+        let mut packet = Vec::new();
+        match (self.addr, self.us) {
+            (IpAddr::V4(src), IpAddr::V4(dst)) => etherparse::Ipv4Header::new(
+                message.len() as u16,
+                64,
+                etherparse::IpTrafficClass::Icmp,
+                src.octets(),
+                dst.octets(),
+            )
+            .write(&mut packet)
+            .unwrap(),
+            (IpAddr::V6(src), IpAddr::V6(dst)) => etherparse::Ipv6Header {
+                traffic_class: 0,
+                flow_label: 0,
+                payload_length: message.len() as u16,
+                next_header: 58,
+                hop_limit: 64,
+                source: src.octets(),
+                destination: dst.octets(),
+            }
+            .write(&mut packet)
+            .unwrap(),
+            _ => unreachable!(),
+        }
+        packet.extend_from_slice(&message);
+        self.nic.send(&packet).unwrap();
+    }
+
+    /// Receives segments until there are none for a while, and returns their lengths.
+    fn lengths(&mut self) -> Vec<usize> {
+        std::iter::from_fn(|| self.recv(QUIET))
+            .map(|s| s.len)
+            .collect()
+    }
+}
+
+fn listen(builder: InterfaceBuilder, v6: bool) -> (Interface, TcpListener, Peer) {
+    let (nic, peer) = MemoryDevice::pair();
+    let (us, addr): (IpAddr, IpAddr) = if v6 {
+        (US6.into(), PEER6.into())
+    } else {
+        (US.into(), PEER.into())
+    };
+    let mut iface = builder
+        .address(us, 24)
+        .send_buffer_size(64 * 1024)
+        .build_with_device(nic)
+        .unwrap();
+    let listener = iface.bind(8000).unwrap();
+    (
+        iface,
+        listener,
+        Peer {
+            nic: peer,
+            addr,
+            us,
+        },
+    )
+}
+
+#[test]
+fn segments_are_as_large_as_the_peer_takes() {
+    let (_iface, mut listener, mut peer) =
+        listen(InterfaceBuilder::new().clock(VirtualClock::new()), false);
+    let (mut stream, syn_ack) = peer.open(&mut listener, 40000, Some(1000));
+    // and we take as much as fits in the link
+    assert_eq!(syn_ack.mss, Some(1460));
+    stream.write_all(&[0; 2500]).unwrap();
+    assert_eq!(peer.lengths(), [1000, 1000, 500]);
+
+    // a peer that doesn't say takes the least any host does
+    let (mut stream, _) = peer.open(&mut listener, 40001, None);
+    stream.write_all(&[0; 1000]).unwrap();
+    assert_eq!(peer.lengths(), [536, 464]);
+}
+
+#[test]
+fn fragmentation_needed_shrinks_segments() {
+    let (_iface, mut listener, mut peer) =
+        listen(InterfaceBuilder::new().clock(VirtualClock::new()), false);
+    let (mut stream, _) = peer.open(&mut listener, 40000, Some(1460));
+    stream.write_all(&[0; 2000]).unwrap();
+    let first = peer.recv(WAIT).unwrap();
+    assert_eq!(first.packet.len(), 1500);
+    let (ip, _) = etherparse::Ipv4Header::read_from_slice(&first.packet).unwrap();
+    assert!(ip.dont_fragment);
+    assert_eq!(peer.lengths(), [540]);
+
+    // what was in flight is sent again right away, in pieces that fit
+    peer.packet_too_big(&first.packet, 1000);
+    let resent: Vec<(u32, usize)> = std::iter::from_fn(|| peer.recv(QUIET))
+        .map(|s| (s.seq, s.len))
+        .collect();
+    assert_eq!(resent, [(1, 960), (961, 960), (1921, 80)]);
+}
+
+#[test]
+fn implausible_packet_too_big_messages_are_ignored() {
+    let (_iface, mut listener, mut peer) =
+        listen(InterfaceBuilder::new().clock(VirtualClock::new()), false);
+    let (mut stream, _) = peer.open(&mut listener, 40000, Some(1460));
+    stream.write_all(&[0; 2000]).unwrap();
+    let first = peer.recv(WAIT).unwrap();
+    assert_eq!(peer.lengths(), [540]);
+
+    // about a segment that isn't in flight
+    let mut stale = first.packet.clone();
+    stale[24..28].copy_from_slice(&5000u32.to_be_bytes());
+    peer.packet_too_big(&stale, 1000);
+    assert!(peer.recv(QUIET).is_none());
+
+    // and a path MTU too small to be worth believing is taken for the smallest we believe
+    peer.packet_too_big(&first.packet, 100);
+    assert_eq!(peer.lengths(), [512, 512, 512, 464]);
+}
+
+#[test]
+fn ipv6_packet_too_big_shrinks_segments() {
+    let (_iface, mut listener, mut peer) =
+        listen(InterfaceBuilder::new().clock(VirtualClock::new()), true);
+    let (mut stream, syn_ack) = peer.open(&mut listener, 40000, Some(1440));
+    assert_eq!(syn_ack.mss, Some(1440));
+    stream.write_all(&[0; 2000]).unwrap();
+    let first = peer.recv(WAIT).unwrap();
+    assert_eq!(first.packet.len(), 1500);
+    assert_eq!(peer.lengths(), [560]);
+
+    peer.packet_too_big(&first.packet, 1280);
+    assert_eq!(peer.lengths(), [1220, 780]);
+}
+
+#[test]
+fn path_mtus_are_shared_and_forgotten() {
+    let clock = VirtualClock::new();
+    let (iface, mut listener, mut peer) =
+        listen(InterfaceBuilder::new().clock(clock.clone()), false);
+    let (mut a, _) = peer.open(&mut listener, 40000, Some(1460));
+    let (mut b, _) = peer.open(&mut listener, 40001, Some(1460));
+
+    a.write_all(&[0; 1500]).unwrap();
+    let first = peer.recv(WAIT).unwrap();
+    assert_eq!(peer.lengths(), [40]);
+    peer.packet_too_big(&first.packet, 1000);
+    assert_eq!(peer.lengths(), [960, 540]);
+    peer.nic.send(&peer.ack(40000, 1501)).unwrap();
+
+    // another connection to the same peer takes the hint
+    b.write_all(&[0; 1500]).unwrap();
+    assert_eq!(peer.lengths(), [960, 540]);
+    peer.nic.send(&peer.ack(40001, 1501)).unwrap();
+    assert!(peer.recv(QUIET).is_none());
+
+    // until the path might have changed again
+    clock.advance(Duration::from_secs(10 * 60));
+    iface.settle();
+    b.write_all(&[0; 1500]).unwrap();
+    assert_eq!(peer.lengths(), [1460, 40]);
+}
+
+#[test]
+fn packet_too_big_for_a_datagram_shrinks_segments_to_the_peer() {
+    let (mut iface, mut listener, mut peer) =
+        listen(InterfaceBuilder::new().clock(VirtualClock::new()), false);
+    let (mut stream, _) = peer.open(&mut listener, 40000, Some(1460));
+    let socket = iface.bind_udp(5353).unwrap();
+    socket.send_to(&[0; 1400], (PEER, 5353).into()).unwrap();
+    let datagram = recv(&mut peer.nic, WAIT).unwrap();
+
+    peer.packet_too_big(&datagram, 1000);
+    iface.settle();
+    stream.write_all(&[0; 1500]).unwrap();
+    assert_eq!(peer.lengths(), [960, 540]);
+}
+
+/// Writes `total` bytes through a path that silently drops packets larger than `mtu`, and returns
+/// the sizes of the packets that made it through, and how many didn't.
+fn through_black_hole(
+    builder: InterfaceBuilder,
+    clock: VirtualClock,
+    total: usize,
+    mtu: usize,
+) -> (Vec<usize>, usize) {
+    let (_iface, mut listener, mut peer) = listen(builder.clock(clock.clone()), false);
+    let (mut stream, _) = peer.open(&mut listener, 40000, Some(1460));
+    let writer = thread::spawn(move || {
+        let mut written = 0;
+        while written < total {
+            // writes don't block yet when the send buffer is full
+            match stream.write(&vec![0; total - written]) {
+                Ok(n) => written += n,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(1))
+                }
+                Err(e) => panic!("{}", e),
+            }
+        }
+        stream
+    });
+
+    let (mut sizes, mut dropped) = (Vec::new(), 0);
+    let mut expected = 1;
+    while expected != total as u32 + 1 {
+        let Some(segment) = peer.recv(QUIET) else {
+            // let the retransmission timer run out
+            clock.advance(Duration::from_secs(1));
+            continue;
+        };
+        if segment.packet.len() > mtu {
+            dropped += 1;
+            continue;
+        }
+        if segment.seq == expected {
+            expected += segment.len as u32;
+            sizes.push(segment.packet.len());
+        }
+        peer.nic.send(&peer.ack(40000, expected)).unwrap();
+    }
+    drop(writer.join().unwrap());
+    (sizes, dropped)
+}
+
+#[test]
+fn black_holes_are_found_and_searched_around() {
+    let (sizes, dropped) =
+        through_black_hole(InterfaceBuilder::new(), VirtualClock::new(), 300_000, 1300);
+    // having given up on the link MTU, we start over from what surely fits
+    assert_eq!(sizes[0], 1024);
+    assert!(dropped > 3);
+    // and then find something close to what really does
+    let largest = *sizes.iter().max().unwrap();
+    assert!(largest > 1300 - 16, "{:?}", sizes);
+}
+
+#[test]
+fn probing_finds_the_path_mtu_from_the_start() {
+    let (sizes, dropped) = through_black_hole(
+        InterfaceBuilder::new().mtu_probing(true),
+        VirtualClock::new(),
+        300_000,
+        1300,
+    );
+    // only probes get lost
+    assert!(dropped <= 3, "{}", dropped);
+    let largest = *sizes.iter().max().unwrap();
+    assert!(largest > 1300 - 16, "{:?}", sizes);
+}
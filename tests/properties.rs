@@ -0,0 +1,220 @@
+use std::io::Read;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use proptest::prelude::*;
+use trust::{Device, Interface, MemoryDevice};
+
+const PEER: [u8; 4] = [192, 168, 0, 1];
+const US: [u8; 4] = [192, 168, 0, 2];
+const HALF: u32 = 1 << 31;
+
+/// Sequence numbers anywhere, but mostly around where they wrap and where comparisons flip.
+fn seq() -> impl Strategy<Value = u32> {
+    prop_oneof![
+        any::<u32>(),
+        0u32..16,
+        HALF - 16..HALF + 16,
+        u32::MAX - 16..=u32::MAX,
+    ]
+}
+
+/// A segment the peer sends once the connection is established.
+#[derive(Clone, Debug)]
+enum Segment {
+    /// Part of the stream, at an offset into it.
+    Data { offset: usize, len: usize },
+    /// Anything else that doesn't end the connection. Only SYNs carry data, since the stack
+    /// would rightly accept anything else that lands in its window.
+    Noise {
+        /// relative to the peer's first byte of data
+        seq: u32,
+        /// relative to the stack's first byte of data
+        ack: u32,
+        window: u16,
+        syn: bool,
+        with_ack: bool,
+        len: usize,
+    },
+}
+
+fn segments(len: usize) -> impl Strategy<Value = Vec<Segment>> {
+    let data = (0..len, 1..1500usize).prop_map(|(offset, len)| Segment::Data { offset, len });
+    // mostly close to where the connection is, so that the stack has to take them seriously
+    let near = || prop_oneof![0u32..4096, seq()];
+    let noise = (
+        near(),
+        near(),
+        any::<u16>(),
+        any::<bool>(),
+        any::<bool>(),
+        0..64usize,
+    )
+        .prop_map(|(seq, ack, window, syn, with_ack, len)| Segment::Noise {
+            seq,
+            ack,
+            window,
+            syn,
+            with_ack,
+            len,
+        });
+    prop::collection::vec(prop_oneof![4 => data, 1 => noise], 0..64)
+}
+
+fn transfer() -> impl Strategy<Value = (u32, Vec<u8>, Vec<Segment>)> {
+    (seq(), prop::collection::vec(any::<u8>(), 1..4096)).prop_flat_map(|(isn, data)| {
+        let len = data.len();
+        (Just(isn), Just(data), segments(len))
+    })
+}
+
+struct Peer {
+    nic: MemoryDevice,
+    port: u16,
+}
+
+impl Peer {
+    fn send(&mut self, seq: u32, ack: Option<u32>, window: u16, syn: bool, payload: &[u8]) {
+        let mut builder =
+            etherparse::PacketBuilder::ipv4(PEER, US, 64).tcp(self.port, 8000, seq, window);
+        if let Some(ack) = ack {
+            builder = builder.ack(ack);
+        }
+        if syn {
+            builder = builder.syn();
+        }
+        let mut packet = Vec::with_capacity(builder.size(payload.len()));
+        builder.write(&mut packet, payload).unwrap();
+        self.nic.send(&packet).unwrap();
+    }
+
+    /// The acknowledgment numbers of whatever the stack has sent, waiting up to `timeout` for the first.
+    fn acks(&mut self, timeout: Duration) -> Vec<u32> {
+        let mut acks = Vec::new();
+        let mut buf = [0u8; 1500];
+        let mut timeout = timeout;
+        while self.nic.poll(timeout).unwrap() {
+            let n = self.nic.recv(&mut buf).unwrap();
+            let iph = etherparse::Ipv4HeaderSlice::from_slice(&buf[..n]).unwrap();
+            let tcph = etherparse::TcpHeaderSlice::from_slice(&buf[iph.slice().len()..n]).unwrap();
+            assert!(!tcph.rst(), "the stack reset the connection");
+            if tcph.ack() {
+                acks.push(tcph.acknowledgment_number());
+            }
+            timeout = Duration::ZERO;
+        }
+        acks
+    }
+}
+
+/// Whatever the peer throws at an established connection, the stack doesn't fall over, and once
+/// the peer retransmits whatever wasn't acknowledged, the application reads exactly what was sent.
+fn deliver(isn: u32, data: Vec<u8>, segments: Vec<Segment>) -> Result<(), TestCaseError> {
+    let (nic, peer) = MemoryDevice::pair();
+    let mut iface = Interface::with_device(nic).unwrap();
+    let mut listener = iface.bind(8000).unwrap();
+    let mut peer = Peer {
+        nic: peer,
+        port: 40000,
+    };
+
+    peer.send(isn, None, 65535, true, &[]);
+    let acks = peer.acks(Duration::from_secs(1));
+    prop_assert_eq!(acks, vec![isn.wrapping_add(1)]);
+    peer.send(isn.wrapping_add(1), Some(1), 65535, false, &[]);
+    let mut stream = listener.accept().unwrap();
+
+    let len = data.len();
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut received = vec![0u8; len];
+        let _ = tx.send(stream.read_exact(&mut received).map(|_| received));
+    });
+
+    let start = isn.wrapping_add(1);
+    for segment in segments {
+        match segment {
+            Segment::Data { offset, len } => {
+                let end = std::cmp::min(offset + len, data.len());
+                peer.send(
+                    start.wrapping_add(offset as u32),
+                    Some(1),
+                    65535,
+                    false,
+                    &data[offset..end],
+                );
+            }
+            Segment::Noise {
+                seq,
+                ack,
+                window,
+                syn,
+                with_ack,
+                len,
+            } => {
+                let payload = if syn { &[0xaa; 64][..len] } else { &[] };
+                peer.send(
+                    start.wrapping_add(seq),
+                    with_ack.then_some(ack.wrapping_add(1)),
+                    window,
+                    syn,
+                    payload,
+                );
+            }
+        }
+    }
+
+    // and then behave, until everything has been acknowledged and read
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut acked = 0;
+    loop {
+        match rx.try_recv() {
+            Ok(received) => {
+                let received =
+                    received.map_err(|e| TestCaseError::fail(format!("read failed: {}", e)))?;
+                prop_assert!(
+                    received == data,
+                    "received something other than what was sent"
+                );
+                break;
+            }
+            Err(mpsc::TryRecvError::Empty) => {}
+            Err(mpsc::TryRecvError::Disconnected) => {
+                return Err(TestCaseError::fail("read panicked"));
+            }
+        }
+        prop_assert!(
+            Instant::now() < deadline,
+            "stalled with {} of {} bytes acknowledged",
+            acked,
+            len
+        );
+
+        for ack in peer.acks(Duration::from_millis(5)) {
+            let n = ack.wrapping_sub(start) as usize;
+            if n <= len {
+                acked = std::cmp::max(acked, n);
+            }
+        }
+        if acked < len {
+            let end = std::cmp::min(acked + 1000, len);
+            peer.send(
+                start.wrapping_add(acked as u32),
+                Some(1),
+                65535,
+                false,
+                &data[acked..end],
+            );
+        }
+    }
+    Ok(())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn delivers_what_was_sent((isn, data, segments) in transfer()) {
+        deliver(isn, data, segments)?;
+    }
+}
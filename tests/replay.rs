@@ -0,0 +1,63 @@
+use std::io::prelude::*;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::thread;
+use trust::sim::{Link, Replay};
+use trust::{Interface, InterfaceBuilder};
+
+const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+fn recording() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/data/passive-close.pcap")
+}
+
+/// Reads everything the client sends, and then closes.
+fn server(iface: &mut Interface) -> thread::JoinHandle<()> {
+    let mut listener = iface.bind(7).unwrap();
+    thread::spawn(move || {
+        let mut stream = listener.accept().unwrap();
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"ping");
+    })
+}
+
+/// Regenerates the recording, with `cargo test --test replay -- --ignored`.
+#[test]
+#[ignore]
+fn record() {
+    let (mut server_iface, mut client) = Link::new()
+        .build(
+            InterfaceBuilder::new()
+                .address(SERVER, 24)
+                .capture(recording()),
+            InterfaceBuilder::new().address(CLIENT, 24),
+        )
+        .unwrap();
+    let app = server(&mut server_iface);
+
+    let mut stream = client.connect((SERVER, 7).into()).unwrap();
+    stream.write_all(b"ping").unwrap();
+    stream.shutdown(std::net::Shutdown::Write).unwrap();
+    stream.read_to_end(&mut Vec::new()).unwrap();
+    app.join().unwrap();
+}
+
+#[test]
+fn passive_close() {
+    Replay::from_file(SERVER, recording())
+        .unwrap()
+        .run(InterfaceBuilder::new(), server)
+        .unwrap();
+}
+
+#[test]
+fn detects_differences() {
+    // nobody is listening this time, so the SYN is answered with a RST instead
+    let err = Replay::from_file(SERVER, recording())
+        .unwrap()
+        .run(InterfaceBuilder::new(), |_| ())
+        .unwrap_err();
+    assert!(err.to_string().contains("[SYN, ACK]"), "{}", err);
+}
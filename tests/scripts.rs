@@ -0,0 +1,57 @@
+//! Runs the conformance scripts in `tests/scripts`; see `trust::sim::script` for the format.
+
+use std::io::ErrorKind;
+use trust::sim::script::Script;
+
+macro_rules! scripts {
+    ($($name:ident => $file:literal,)*) => {
+        $(
+            #[test]
+            fn $name() {
+                let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/scripts/", $file);
+                if let Err(e) = Script::from_file(path).and_then(|script| script.run()) {
+                    panic!("{}: {}", $file, e);
+                }
+            }
+        )*
+    };
+}
+
+scripts! {
+    active_close => "active-close.pkt",
+    active_open => "active-open.pkt",
+    connect_refused => "connect-refused.pkt",
+    data_in => "data-in.pkt",
+    data_out => "data-out.pkt",
+    passive_close => "passive-close.pkt",
+    passive_open => "passive-open.pkt",
+    receive_window => "receive-window.pkt",
+    reset_in => "reset-in.pkt",
+    reset_no_connection => "reset-no-connection.pkt",
+    retransmit => "retransmit.pkt",
+    send_window => "send-window.pkt",
+    syn_ack_retransmit => "syn-ack-retransmit.pkt",
+    syn_retransmit => "syn-retransmit.pkt",
+    unacceptable => "unacceptable.pkt",
+    zero_window => "zero-window.pkt",
+}
+
+#[test]
+fn times_must_make_sense() {
+    for time in ["-1", "+-0.1", "inf", "+inf", "NaN", "1e30", "x"] {
+        let source = format!("{} listen 8000", time);
+        assert_eq!(
+            Script::parse(&source).unwrap_err().kind(),
+            ErrorKind::InvalidData,
+            "{}",
+            time
+        );
+    }
+    // each step may be fine, and the sum still not
+    let source = "1e19 listen 8000\n+1e19 close";
+    assert_eq!(
+        Script::parse(source).unwrap_err().kind(),
+        ErrorKind::InvalidData
+    );
+    assert!(Script::parse("0 listen 8000\n+0.1 close").is_ok());
+}
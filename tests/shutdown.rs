@@ -0,0 +1,65 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net::Shutdown;
+
+mod common;
+use common::{Peer, WAIT};
+
+#[test]
+fn reads_return_eof_after_shutting_down_reading() {
+    let (_iface, _listener, mut stream, mut peer) = Peer::connect();
+    peer.send(b"unread");
+    assert_eq!(peer.recv(WAIT).unwrap().ack, peer.seq);
+
+    stream.shutdown(Shutdown::Read).unwrap();
+    assert_eq!(stream.read(&mut [0u8; 16]).unwrap(), 0);
+    // and nothing was sent, since the peer doesn't find out about it
+    assert!(peer.recv(common::QUIET).is_none());
+}
+
+#[test]
+fn shutting_down_writing_sends_a_fin() {
+    let (_iface, _listener, mut stream, mut peer) = Peer::connect();
+    stream.write_all(b"bye").unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+
+    let mut data = Vec::new();
+    let fin = peer.recv_until(|s| {
+        data.extend_from_slice(&s.data);
+        s.fin
+    });
+    assert_eq!(data, b"bye");
+    assert!(!fin.rst);
+    assert_eq!(
+        stream.write(b"more").unwrap_err().kind(),
+        ErrorKind::BrokenPipe
+    );
+
+    // the read half is still open
+    peer.send(b"hello");
+    let mut buf = [0u8; 16];
+    assert_eq!(stream.read(&mut buf).unwrap(), 5);
+    assert_eq!(&buf[..5], b"hello");
+}
+
+#[test]
+fn shutting_down_both_halves() {
+    let (_iface, _listener, mut stream, mut peer) = Peer::connect();
+    stream.shutdown(Shutdown::Both).unwrap();
+
+    assert!(peer.recv_until(|s| s.fin).fin);
+    assert_eq!(stream.read(&mut [0u8; 16]).unwrap(), 0);
+    assert_eq!(
+        stream.write(b"more").unwrap_err().kind(),
+        ErrorKind::BrokenPipe
+    );
+}
+
+#[test]
+fn data_after_shutting_down_reading_is_reset() {
+    let (_iface, _listener, mut stream, mut peer) = Peer::connect();
+    stream.shutdown(Shutdown::Read).unwrap();
+
+    peer.send(b"too late");
+    assert!(peer.recv_until(|s| s.rst || !s.data.is_empty()).rst);
+    assert_eq!(stream.read(&mut [0u8; 16]).unwrap(), 0);
+}
@@ -0,0 +1,64 @@
+use std::io::prelude::*;
+use std::net::Ipv4Addr;
+use std::thread;
+use trust::{InterfaceBuilder, sim::Link};
+
+fn pair() -> (trust::Interface, trust::Interface) {
+    Link::new()
+        .build(
+            InterfaceBuilder::new().address(Ipv4Addr::new(10, 0, 0, 1), 24),
+            InterfaceBuilder::new().address(Ipv4Addr::new(10, 0, 0, 2), 24),
+        )
+        .unwrap()
+}
+
+#[test]
+fn echo() {
+    let (mut server, mut client) = pair();
+    let mut listener = server.bind(7).unwrap();
+    let jh = thread::spawn(move || {
+        let mut stream = listener.accept().unwrap();
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).unwrap();
+        stream.write_all(&buf).unwrap();
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+        buf
+    });
+
+    let mut stream = client.connect("10.0.0.1:7".parse().unwrap()).unwrap();
+    assert_eq!(stream.peer_addr().unwrap(), "10.0.0.1:7".parse().unwrap());
+    stream.write_all(b"hello, world").unwrap();
+    stream.shutdown(std::net::Shutdown::Write).unwrap();
+
+    let mut echoed = Vec::new();
+    stream.read_to_end(&mut echoed).unwrap();
+    assert_eq!(echoed, b"hello, world");
+    assert_eq!(jh.join().unwrap(), b"hello, world");
+}
+
+#[test]
+fn connect_to_closed_port_is_refused() {
+    let (_server, mut client) = pair();
+    let err = client.connect("10.0.0.1:7".parse().unwrap()).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused);
+}
+
+#[test]
+fn write_and_close_right_after_accept() {
+    let (mut server, mut client) = pair();
+    let mut listener = server.bind(13).unwrap();
+    let jh = thread::spawn(move || {
+        let mut stream = listener.accept().unwrap();
+        stream.write_all(b"hello").unwrap();
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+        // keep the stream around until the client has read everything
+        stream.read_to_end(&mut Vec::new()).unwrap();
+    });
+
+    let mut stream = client.connect("10.0.0.1:13".parse().unwrap()).unwrap();
+    let mut buf = Vec::new();
+    stream.read_to_end(&mut buf).unwrap();
+    assert_eq!(buf, b"hello");
+    stream.shutdown(std::net::Shutdown::Write).unwrap();
+    jh.join().unwrap();
+}
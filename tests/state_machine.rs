@@ -0,0 +1,443 @@
+//! Runs random sequences of events against both an executable model of the connection state
+//! diagram in RFC 9293 and the stack, and checks that they agree on the state after every event.
+
+use std::io;
+use std::net::{Ipv4Addr, Shutdown, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use proptest::prelude::*;
+use trust::{Device, InterfaceBuilder, MemoryDevice, State, TcpStream, VirtualClock};
+
+mod common;
+use common::tracked::{Progress, Tracked};
+
+const US: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const PEER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+const PEER_ISN: u32 = 5000;
+const STALL: Duration = Duration::from_secs(5);
+
+/// Something that happens to a connection.
+#[derive(Clone, Copy, Debug)]
+enum Event {
+    /// The peer sends a SYN, or retransmits the one it sent.
+    Syn,
+    /// The peer sends a SYN that acknowledges ours.
+    SynAck,
+    /// The peer acknowledges everything it has received.
+    Ack,
+    /// The peer sends data.
+    Data,
+    /// The peer sends a FIN (or retransmits it), acknowledging everything it has received.
+    Fin,
+    /// The peer sends a FIN, acknowledging everything but our FIN.
+    FinBeforeOurs,
+    /// The peer resets the connection.
+    Rst,
+    /// The application shuts down its side of the connection.
+    Close,
+    /// Long enough passes for any timer to run out.
+    Timeout,
+}
+
+fn event() -> impl Strategy<Value = Event> {
+    prop_oneof![
+        1 => Just(Event::Syn),
+        1 => Just(Event::SynAck),
+        3 => Just(Event::Ack),
+        2 => Just(Event::Data),
+        2 => Just(Event::Fin),
+        1 => Just(Event::FinBeforeOurs),
+        1 => Just(Event::Rst),
+        2 => Just(Event::Close),
+        1 => Just(Event::Timeout),
+    ]
+}
+
+/// A segment from the peer, as far as the state diagram is concerned.
+#[derive(Clone, Copy, Debug, Default)]
+struct Segment {
+    syn: bool,
+    fin: bool,
+    rst: bool,
+    ack: bool,
+    /// whether it acknowledges our SYN
+    acks_syn: bool,
+    /// whether it acknowledges our FIN
+    acks_fin: bool,
+    /// whether it is a retransmission of something that already arrived
+    old: bool,
+}
+
+/// The reference model: the state diagram of RFC 9293 S3.3.2, with the transitions spelled out in
+/// S3.10. The peer plays by the rules, so only its retransmissions fall outside the window.
+mod model {
+    use super::{Segment, State};
+
+    /// A segment arrives (RFC 9293 S3.10.7).
+    pub fn segment(state: State, seg: Segment) -> State {
+        if let State::SynSent = state {
+            if seg.ack && !seg.acks_syn {
+                return state;
+            }
+            if seg.rst {
+                return if seg.ack { State::Closed } else { state };
+            }
+            if seg.syn {
+                return if seg.acks_syn {
+                    State::Estab
+                } else {
+                    State::SynRcvd
+                };
+            }
+            return state;
+        }
+        if let State::Closed = state {
+            return state;
+        }
+        if seg.old {
+            // it's outside the window, so all it gets is an ACK
+            return state;
+        }
+
+        if seg.rst {
+            // from SYN-RECEIVED after a passive open, the connection goes back to LISTEN, which as
+            // far as the connection is concerned is as good as CLOSED
+            return State::Closed;
+        }
+        if seg.syn {
+            // a challenge ACK, and nothing else (RFC 5961 S4)
+            return state;
+        }
+        if !seg.ack {
+            return state;
+        }
+
+        let state = match state {
+            State::SynRcvd if seg.acks_syn => State::Estab,
+            State::FinWait1 if seg.acks_fin => State::FinWait2,
+            State::Closing if seg.acks_fin => State::TimeWait,
+            State::LastAck if seg.acks_fin => return State::Closed,
+            state => state,
+        };
+
+        if !seg.fin {
+            return state;
+        }
+        match state {
+            State::SynRcvd | State::Estab => State::CloseWait,
+            State::FinWait1 => State::Closing,
+            State::FinWait2 => State::TimeWait,
+            state => state,
+        }
+    }
+
+    /// The application calls CLOSE (RFC 9293 S3.10.4).
+    pub fn close(state: State) -> State {
+        match state {
+            State::SynSent => State::Closed,
+            State::SynRcvd | State::Estab => State::FinWait1,
+            State::CloseWait => State::LastAck,
+            state => state,
+        }
+    }
+
+    /// Every timer runs out (RFC 9293 S3.10.8). Retransmissions don't change the state.
+    pub fn timeout(state: State) -> State {
+        match state {
+            State::TimeWait => State::Closed,
+            state => state,
+        }
+    }
+}
+
+/// The other end of the connection, which keeps track of what it has sent and seen.
+struct Peer {
+    nic: MemoryDevice,
+    progress: Arc<Progress>,
+    injected: usize,
+    port: u16,
+    remote_port: u16,
+    /// our SYN, once we have sent it
+    synced: bool,
+    /// the sequence number of our FIN, once we have sent it
+    fin: Option<u32>,
+    nxt: u32,
+    /// the stack's SYN and FIN, and the end of everything it has sent
+    their_syn: Option<u32>,
+    their_fin: Option<u32>,
+    their_nxt: u32,
+}
+
+impl Peer {
+    fn send(
+        &mut self,
+        seq: u32,
+        ack: Option<u32>,
+        syn: bool,
+        fin: bool,
+        rst: bool,
+        payload: &[u8],
+    ) {
+        let mut builder = etherparse::PacketBuilder::ipv4(PEER.octets(), US.octets(), 64).tcp(
+            self.port,
+            self.remote_port,
+            seq,
+            65535,
+        );
+        if let Some(ack) = ack {
+            builder = builder.ack(ack);
+        }
+        if syn {
+            builder = builder.syn();
+        }
+        if fin {
+            builder = builder.fin();
+        }
+        if rst {
+            builder = builder.rst();
+        }
+        let mut frame = Vec::with_capacity(builder.size(payload.len()));
+        builder.write(&mut frame, payload).unwrap();
+        self.nic.send(&frame).unwrap();
+        self.injected += 1;
+    }
+
+    /// Sends a segment on behalf of the event, and describes it for the model.
+    fn segment(&mut self, event: Event) -> Option<Segment> {
+        let acks_syn = self.their_syn.is_some();
+        let acks_fin = self.their_fin.is_some();
+        let ack = acks_syn.then_some(self.their_nxt);
+        let mut seg = Segment {
+            ack: ack.is_some(),
+            acks_syn,
+            acks_fin,
+            ..Segment::default()
+        };
+        match event {
+            Event::Syn | Event::SynAck => {
+                let ack = if let Event::SynAck = event { ack } else { None };
+                self.send(PEER_ISN, ack, true, false, false, &[]);
+                seg.old = self.synced;
+                if !self.synced {
+                    self.synced = true;
+                    self.nxt = PEER_ISN.wrapping_add(1);
+                }
+                seg.syn = true;
+                seg.ack = ack.is_some();
+                seg.acks_syn &= seg.ack;
+                seg.acks_fin &= seg.ack;
+            }
+            Event::Ack => {
+                // nothing to acknowledge before the handshake
+                self.synced.then_some(())?;
+                self.send(self.nxt, ack, false, false, false, &[]);
+            }
+            Event::Data => {
+                // and nothing to send after our FIN
+                (self.synced && self.fin.is_none()).then_some(())?;
+                self.send(self.nxt, ack, false, false, false, &[0xaa; 10]);
+                self.nxt = self.nxt.wrapping_add(10);
+            }
+            Event::Fin | Event::FinBeforeOurs => {
+                self.synced.then_some(())?;
+                let ack = match (event, self.their_fin) {
+                    (Event::FinBeforeOurs, Some(fin)) => {
+                        seg.acks_fin = false;
+                        Some(fin)
+                    }
+                    _ => ack,
+                };
+                seg.old = self.fin.is_some();
+                let fin = *self.fin.get_or_insert(self.nxt);
+                self.send(fin, ack, false, true, false, &[]);
+                self.nxt = fin.wrapping_add(1);
+                seg.fin = true;
+            }
+            Event::Rst => {
+                self.send(self.nxt, ack, false, false, true, &[]);
+                seg.rst = true;
+            }
+            Event::Close | Event::Timeout => unreachable!(),
+        }
+        Some(seg)
+    }
+
+    /// Waits until the stack has dealt with everything we sent, and gone around at least once
+    /// more, then takes note of whatever it sent.
+    fn settle(&mut self) {
+        let deadline = Instant::now() + STALL;
+        while self.progress.done.load(Ordering::SeqCst) < self.injected {
+            assert!(Instant::now() < deadline, "the packet loop has stopped");
+            self.receive(Duration::from_millis(1));
+        }
+        let polls = self.progress.polls.load(Ordering::SeqCst);
+        while self.progress.polls.load(Ordering::SeqCst) < polls + 2 {
+            assert!(Instant::now() < deadline, "the packet loop has stopped");
+            self.receive(Duration::from_millis(1));
+        }
+        self.receive(Duration::ZERO);
+    }
+
+    fn receive(&mut self, timeout: Duration) {
+        let mut buf = [0u8; 1500];
+        while self.nic.poll(timeout).unwrap() {
+            let n = self.nic.recv(&mut buf).unwrap();
+            let iph = etherparse::Ipv4HeaderSlice::from_slice(&buf[..n]).unwrap();
+            let tcph = etherparse::TcpHeaderSlice::from_slice(&buf[iph.slice().len()..n]).unwrap();
+            if tcph.rst() {
+                continue;
+            }
+            // in case the stack picked the port
+            self.remote_port = tcph.source_port();
+            let seq = tcph.sequence_number();
+            let len = n - iph.slice().len() - tcph.slice().len();
+            let mut end = seq.wrapping_add(len as u32);
+            if tcph.syn() {
+                self.their_syn = Some(seq);
+                end = end.wrapping_add(1);
+            }
+            if tcph.fin() {
+                self.their_fin = Some(end);
+                end = end.wrapping_add(1);
+            }
+            if self.their_syn.is_some() && end.wrapping_sub(self.their_nxt) as i32 > 0 {
+                self.their_nxt = end;
+            }
+        }
+    }
+}
+
+/// What the test can see of the connection: once there is a stream, its state, and until then,
+/// whether `connect` is still waiting.
+enum Observed {
+    Connecting(mpsc::Receiver<io::Result<TcpStream>>),
+    Stream(TcpStream),
+    Failed,
+}
+
+impl Observed {
+    fn check(&mut self, expected: State) -> Result<(), TestCaseError> {
+        let connecting = matches!(expected, State::SynSent | State::SynRcvd);
+        if let Observed::Connecting(rx) = self {
+            // connect returns once the connection is established, or not, so give it a moment
+            let wait = if connecting { Duration::ZERO } else { STALL };
+            match rx.recv_timeout(wait) {
+                Ok(Ok(stream)) => *self = Observed::Stream(stream),
+                Ok(Err(_)) => *self = Observed::Failed,
+                Err(_) => {}
+            }
+        }
+        match self {
+            Observed::Connecting(_) => prop_assert!(
+                connecting,
+                "still connecting, but the model is in {:?}",
+                expected
+            ),
+            Observed::Stream(stream) => prop_assert_eq!(stream.state().unwrap(), expected),
+            Observed::Failed => prop_assert_eq!(expected, State::Closed, "connect failed"),
+        }
+        Ok(())
+    }
+}
+
+fn run(active: bool, events: Vec<Event>) -> Result<(), TestCaseError> {
+    let clock = VirtualClock::new();
+    let (nic, peer) = MemoryDevice::pair();
+    let (nic, progress) = Tracked::new(nic);
+    let mut iface = InterfaceBuilder::new()
+        .address(US, 24)
+        .clock(clock.clone())
+        .build_with_device(nic)
+        .unwrap();
+    let mut peer = Peer {
+        nic: peer,
+        progress,
+        injected: 0,
+        port: if active { 8000 } else { 40000 },
+        remote_port: 8000,
+        synced: false,
+        fin: None,
+        nxt: PEER_ISN,
+        their_syn: None,
+        their_fin: None,
+        their_nxt: 0,
+    };
+
+    let mut connecting = None;
+    let mut _listener = None;
+    let (mut state, mut observed) = if active {
+        // the peer plays the listener
+        let (tx, rx) = mpsc::channel();
+        connecting = Some(std::thread::spawn(move || {
+            let _ = tx.send(iface.connect(SocketAddr::from((PEER, 8000))));
+            iface
+        }));
+        let deadline = Instant::now() + STALL;
+        while peer.their_syn.is_none() {
+            prop_assert!(Instant::now() < deadline, "no SYN");
+            peer.receive(Duration::from_millis(1));
+        }
+        (State::SynSent, Observed::Connecting(rx))
+    } else {
+        let mut listener = iface.bind(8000).unwrap();
+        peer.segment(Event::Syn);
+        peer.settle();
+        let stream = listener.accept().unwrap();
+        _listener = Some((listener, iface));
+        (State::SynRcvd, Observed::Stream(stream))
+    };
+    observed.check(state)?;
+
+    for event in events {
+        match event {
+            Event::Close => {
+                let Observed::Stream(stream) = &observed else {
+                    continue;
+                };
+                let _ = stream.shutdown(Shutdown::Write);
+                state = model::close(state);
+            }
+            Event::Timeout => {
+                clock.advance(Duration::from_secs(61));
+                state = model::timeout(state);
+            }
+            event => {
+                let Some(seg) = peer.segment(event) else {
+                    continue;
+                };
+                state = model::segment(state, seg);
+            }
+        }
+        peer.settle();
+        observed
+            .check(state)
+            .map_err(|e| TestCaseError::fail(format!("after {:?}: {}", event, e)))?;
+    }
+
+    if let Observed::Connecting(_) = observed {
+        // give up, so that connect returns the interface
+        peer.segment(Event::Rst);
+        peer.settle();
+    }
+    if let Some(connecting) = connecting {
+        connecting.join().unwrap();
+    }
+    Ok(())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(32))]
+
+    #[test]
+    fn passive_open(events in prop::collection::vec(event(), 0..16)) {
+        run(false, events)?;
+    }
+
+    #[test]
+    fn active_open(events in prop::collection::vec(event(), 0..16)) {
+        run(true, events)?;
+    }
+}
@@ -0,0 +1,146 @@
+use std::io::{IoSlice, IoSliceMut, Read, Write};
+use std::net::SocketAddr;
+
+mod common;
+use common::{Peer, QUIET, WAIT};
+
+/// Writes `data` through `w`, and checks that the peer gets it.
+fn written(peer: &mut Peer, mut w: impl Write, data: &[u8]) {
+    w.write_all(data).unwrap();
+    let segment = peer.recv_until(|s| !s.data.is_empty() || s.fin || s.rst);
+    assert_eq!(segment.data, data);
+    peer.send(b"");
+}
+
+#[test]
+fn addresses_of_an_accepted_stream() {
+    let (_iface, listener, stream, _peer) = Peer::connect();
+    let us = SocketAddr::from((common::US, 8000));
+    let peer = SocketAddr::from((common::PEER, 40000));
+    assert_eq!(listener.local_addr().unwrap(), us);
+    assert_eq!(stream.local_addr().unwrap(), us);
+    assert_eq!(stream.peer_addr().unwrap(), peer);
+
+    let (read, write) = stream.split();
+    assert_eq!(
+        (read.local_addr().unwrap(), read.peer_addr().unwrap()),
+        (us, peer)
+    );
+    assert_eq!(
+        (write.local_addr().unwrap(), write.peer_addr().unwrap()),
+        (us, peer)
+    );
+}
+
+#[test]
+fn dropping_a_clone_leaves_the_connection_open() {
+    let (_iface, _listener, mut stream, mut peer) = Peer::connect();
+    let clone = stream.try_clone().unwrap();
+    drop(clone);
+    assert!(peer.recv(QUIET).is_none());
+    written(&mut peer, &mut stream, b"still here");
+
+    // and the clone saw the same connection while it lasted
+    let mut clone = stream.try_clone().unwrap();
+    peer.send(b"hello");
+    let mut buf = [0u8; 16];
+    assert_eq!(clone.read(&mut buf).unwrap(), 5);
+    assert_eq!(&buf[..5], b"hello");
+}
+
+#[test]
+fn only_the_last_handle_to_go_sends_the_fin() {
+    let (_iface, _listener, stream, mut peer) = Peer::connect();
+    let clone = stream.try_clone().unwrap();
+    let (read, mut write) = stream.split();
+
+    drop(read);
+    drop(clone);
+    assert!(peer.recv(QUIET).is_none());
+    written(&mut peer, &mut write, b"one left");
+
+    drop(write);
+    let fin = peer.recv(WAIT).unwrap();
+    assert!(fin.fin && !fin.rst);
+}
+
+#[test]
+fn shutting_down_the_write_half_leaves_the_read_half_open() {
+    let (_iface, _listener, stream, mut peer) = Peer::connect();
+    let (mut read, write) = stream.split();
+
+    write.shutdown().unwrap();
+    assert!(peer.recv(WAIT).unwrap().fin);
+
+    peer.send(b"hello");
+    let mut buf = [0u8; 16];
+    assert_eq!(read.read(&mut buf).unwrap(), 5);
+    assert_eq!(&buf[..5], b"hello");
+    peer.fin();
+    assert_eq!(read.read(&mut buf).unwrap(), 0);
+}
+
+#[test]
+fn peeking_leaves_the_data_to_be_read() {
+    let (_iface, _listener, mut stream, mut peer) = Peer::connect();
+    peer.send(b"hello");
+
+    let mut buf = [0u8; 3];
+    assert_eq!(stream.peek(&mut buf).unwrap(), 3);
+    assert_eq!(&buf, b"hel");
+    let mut buf = [0u8; 16];
+    assert_eq!(stream.peek(&mut buf).unwrap(), 5);
+    assert_eq!(stream.read(&mut buf).unwrap(), 5);
+    assert_eq!(&buf[..5], b"hello");
+}
+
+#[test]
+fn read_vectored_fills_buffers_in_order() {
+    // with room for everything sent, so that the peer needn't keep track of our window
+    let builder = trust::InterfaceBuilder::new().recv_buffer_size(4096);
+    let (_iface, _listener, mut stream, mut peer) = Peer::connect_with(builder);
+    let byte = |i: usize| (i % 251) as u8;
+    let (mut sent, mut read) = (0, 0);
+    // reading less than has arrived moves the start of the queue along, so that it eventually
+    // wraps around the end of its buffer
+    for (send, take) in [(600, 500), (600, 650), (500, 300), (700, 800), (400, 550)] {
+        let data: Vec<u8> = (sent..sent + send).map(byte).collect();
+        peer.send(&data);
+        sent += send;
+        let ack = peer.seq;
+        peer.recv_until(|s| s.ack == ack);
+
+        let (mut a, mut b, mut c) = ([0u8; 7], [0u8; 100], vec![0u8; take - 107]);
+        let n = stream
+            .read_vectored(&mut [
+                IoSliceMut::new(&mut a),
+                IoSliceMut::new(&mut b),
+                IoSliceMut::new(&mut c),
+            ])
+            .unwrap();
+        assert_eq!(n, std::cmp::min(take, sent - read));
+        let got: Vec<u8> = a.iter().chain(&b).chain(&c).take(n).copied().collect();
+        assert_eq!(got, (read..read + n).map(byte).collect::<Vec<_>>());
+        read += n;
+    }
+    assert_eq!(read, sent);
+}
+
+#[test]
+fn write_vectored_sends_buffers_in_order() {
+    let (_iface, _listener, mut stream, mut peer) = Peer::connect();
+    let bufs = [
+        IoSlice::new(b"one "),
+        IoSlice::new(b""),
+        IoSlice::new(b"two "),
+        IoSlice::new(b"three"),
+    ];
+    assert_eq!(stream.write_vectored(&bufs).unwrap(), 13);
+
+    let mut data = Vec::new();
+    peer.recv_until(|s| {
+        data.extend_from_slice(&s.data);
+        data.len() >= 13
+    });
+    assert_eq!(data, b"one two three");
+}
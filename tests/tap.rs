@@ -0,0 +1,293 @@
+use std::io::ErrorKind;
+use std::net::Ipv4Addr;
+use std::thread;
+use std::time::Duration;
+use trust::{Device, Interface, InterfaceBuilder, MemoryDevice, Mode, VirtualClock};
+
+mod common;
+use common::{PEER, QUIET, US, WAIT, datagram, datagram_to, recv};
+
+const ROUTER: [u8; 4] = [10, 0, 0, 254];
+const PEER_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x02];
+const OUR_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x01];
+const BROADCAST: [u8; 6] = [0xff; 6];
+
+fn tap(clock: VirtualClock) -> (Interface, MemoryDevice) {
+    let (nic, peer) = MemoryDevice::pair();
+    let iface = InterfaceBuilder::new()
+        .mode(Mode::Tap)
+        .address(US, 24)
+        .hardware_address(OUR_MAC)
+        .gateway(Ipv4Addr::from(ROUTER))
+        .clock(clock)
+        .build_with_device(nic)
+        .unwrap();
+    (iface, peer)
+}
+
+fn frame(dst: [u8; 6], ethertype: u16, payload: &[u8]) -> Vec<u8> {
+    let mut frame = dst.to_vec();
+    frame.extend_from_slice(&PEER_MAC);
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn arp(op: u16, sender: ([u8; 6], [u8; 4]), target: ([u8; 6], [u8; 4])) -> Vec<u8> {
+    let mut packet = vec![0, 1, 0x08, 0x00, 6, 4];
+    packet.extend_from_slice(&op.to_be_bytes());
+    packet.extend_from_slice(&sender.0);
+    packet.extend_from_slice(&sender.1);
+    packet.extend_from_slice(&target.0);
+    packet.extend_from_slice(&target.1);
+    frame(if op == 1 { BROADCAST } else { OUR_MAC }, 0x0806, &packet)
+}
+
+/// The destination, type and payload of an Ethernet frame, after checking that it is from us.
+fn parse(frame: &[u8]) -> ([u8; 6], u16, &[u8]) {
+    assert_eq!(frame[6..12], OUR_MAC);
+    (
+        frame[..6].try_into().unwrap(),
+        u16::from_be_bytes([frame[12], frame[13]]),
+        &frame[14..],
+    )
+}
+
+/// Checks that `frame` is an ARP request from us for `ip`.
+fn assert_request(frame: &[u8], ip: [u8; 4]) {
+    let (dst, ethertype, arp) = parse(frame);
+    assert_eq!((dst, ethertype), (BROADCAST, 0x0806));
+    assert_eq!(arp[6..8], [0, 1]);
+    assert_eq!(arp[8..18], [&OUR_MAC[..], &US[..]].concat()[..]);
+    assert_eq!(arp[24..28], ip);
+}
+
+#[test]
+fn arp_requests_for_our_address_are_answered() {
+    let (_iface, mut peer) = tap(VirtualClock::new());
+
+    // for someone else
+    peer.send(&arp(1, (PEER_MAC, PEER), ([0; 6], [10, 0, 0, 3])))
+        .unwrap();
+    assert!(recv(&mut peer, QUIET).is_none());
+
+    peer.send(&arp(1, (PEER_MAC, PEER), ([0; 6], US))).unwrap();
+    let reply = recv(&mut peer, WAIT).unwrap();
+    let (dst, ethertype, arp) = parse(&reply);
+    assert_eq!((dst, ethertype), (PEER_MAC, 0x0806));
+    assert_eq!(arp[6..8], [0, 2]);
+    assert_eq!(arp[8..18], [&OUR_MAC[..], &US[..]].concat()[..]);
+    assert_eq!(arp[18..28], [&PEER_MAC[..], &PEER[..]].concat()[..]);
+}
+
+#[test]
+fn packets_wait_for_arp_replies() {
+    let (mut iface, mut peer) = tap(VirtualClock::new());
+    let socket = iface.bind_udp(5353).unwrap();
+    socket
+        .send_to(b"first", "10.0.0.2:53".parse().unwrap())
+        .unwrap();
+    socket
+        .send_to(b"second", "10.0.0.2:53".parse().unwrap())
+        .unwrap();
+    // asked once, for both
+    assert_request(&recv(&mut peer, WAIT).unwrap(), PEER);
+    assert!(recv(&mut peer, QUIET).is_none());
+
+    peer.send(&arp(2, (PEER_MAC, PEER), (OUR_MAC, US))).unwrap();
+    for expected in [&b"first"[..], b"second"] {
+        let frame = recv(&mut peer, WAIT).unwrap();
+        let (dst, ethertype, ip) = parse(&frame);
+        assert_eq!((dst, ethertype), (PEER_MAC, 0x0800));
+        assert!(ip.ends_with(expected));
+    }
+
+    // and once known, without asking again
+    socket
+        .send_to(b"third", "10.0.0.2:53".parse().unwrap())
+        .unwrap();
+    let frame = recv(&mut peer, WAIT).unwrap();
+    let (dst, _, ip) = parse(&frame);
+    assert_eq!(dst, PEER_MAC);
+    assert!(ip.ends_with(b"third"));
+}
+
+#[test]
+fn neighbours_asking_for_us_are_remembered() {
+    let (mut iface, mut peer) = tap(VirtualClock::new());
+    let socket = iface.bind_udp(5353).unwrap();
+    peer.send(&arp(1, (PEER_MAC, PEER), ([0; 6], US))).unwrap();
+    recv(&mut peer, WAIT).unwrap();
+
+    // the answer goes straight back
+    peer.send(&frame(OUR_MAC, 0x0800, &datagram(40000, 5353, b"hello")))
+        .unwrap();
+    let mut buf = [0; 64];
+    let (n, from) = socket.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"hello");
+    socket.send_to(b"hello yourself", from).unwrap();
+    let frame = recv(&mut peer, WAIT).unwrap();
+    let (dst, ethertype, _) = parse(&frame);
+    assert_eq!((dst, ethertype), (PEER_MAC, 0x0800));
+}
+
+#[test]
+fn frames_for_others_are_ignored() {
+    let (mut iface, mut peer) = tap(VirtualClock::new());
+    let socket = iface.bind_udp(5353).unwrap();
+    peer.send(&arp(1, (PEER_MAC, PEER), ([0; 6], US))).unwrap();
+    recv(&mut peer, WAIT).unwrap();
+
+    peer.send(&frame(
+        [0x02, 0, 0, 0, 0, 0x03],
+        0x0800,
+        &datagram(40000, 5353, b"not for us"),
+    ))
+    .unwrap();
+    peer.send(&frame(OUR_MAC, 0x0800, &datagram(40000, 5353, b"for us")))
+        .unwrap();
+    let mut buf = [0; 64];
+    let n = socket.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"for us");
+}
+
+#[test]
+fn multicasts_are_received() {
+    let (mut iface, mut peer) = tap(VirtualClock::new());
+    let socket = iface.bind_udp(5353).unwrap();
+
+    let mdns = datagram_to([224, 0, 0, 251], 40000, 5353, b"everyone in the group");
+    peer.send(&frame([0x01, 0x00, 0x5e, 0x00, 0x00, 0xfb], 0x0800, &mdns))
+        .unwrap();
+    let mut buf = [0; 64];
+    let (n, from) = socket.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"everyone in the group");
+    assert_eq!(from, (Ipv4Addr::from(PEER), 40000).into());
+}
+
+#[test]
+fn closed_ports_are_unreachable_over_ethernet() {
+    let (_iface, mut peer) = tap(VirtualClock::new());
+    peer.send(&arp(1, (PEER_MAC, PEER), ([0; 6], US))).unwrap();
+    recv(&mut peer, WAIT).unwrap();
+
+    peer.send(&frame(OUR_MAC, 0x0800, &datagram(40000, 9, b"anyone?")))
+        .unwrap();
+    let frame = recv(&mut peer, WAIT).unwrap();
+    let (dst, ethertype, ip) = parse(&frame);
+    assert_eq!((dst, ethertype), (PEER_MAC, 0x0800));
+    let (ip, message) = etherparse::Ipv4Header::read_from_slice(ip).unwrap();
+    assert_eq!(ip.protocol, 1);
+    assert_eq!(message[..2], [3, 3]);
+}
+
+#[test]
+fn unanswered_requests_are_retried_then_given_up() {
+    let clock = VirtualClock::new();
+    let (mut iface, mut peer) = tap(clock.clone());
+    let socket = iface.bind_udp(5353).unwrap();
+    socket
+        .send_to(b"hello?", "10.0.0.2:53".parse().unwrap())
+        .unwrap();
+
+    assert_request(&recv(&mut peer, WAIT).unwrap(), PEER);
+    for _ in 0..2 {
+        assert!(recv(&mut peer, QUIET).is_none());
+        clock.advance(Duration::from_secs(1));
+        assert_request(&recv(&mut peer, WAIT).unwrap(), PEER);
+    }
+    clock.advance(Duration::from_secs(1));
+    assert!(recv(&mut peer, QUIET).is_none());
+
+    // what was waiting is gone by the time the answer comes
+    peer.send(&arp(2, (PEER_MAC, PEER), (OUR_MAC, US))).unwrap();
+    assert!(recv(&mut peer, QUIET).is_none());
+}
+
+#[test]
+fn what_neighbours_said_is_forgotten() {
+    let clock = VirtualClock::new();
+    let (mut iface, mut peer) = tap(clock.clone());
+    let socket = iface.bind_udp(5353).unwrap();
+    peer.send(&arp(1, (PEER_MAC, PEER), ([0; 6], US))).unwrap();
+    recv(&mut peer, WAIT).unwrap();
+
+    clock.advance(Duration::from_secs(61));
+    socket
+        .send_to(b"still there?", "10.0.0.2:53".parse().unwrap())
+        .unwrap();
+    assert_request(&recv(&mut peer, WAIT).unwrap(), PEER);
+}
+
+#[test]
+fn packets_off_the_network_go_through_the_gateway() {
+    let (mut iface, mut peer) = tap(VirtualClock::new());
+    let socket = iface.bind_udp(5353).unwrap();
+    socket
+        .send_to(b"far away", "192.0.2.1:53".parse().unwrap())
+        .unwrap();
+    assert_request(&recv(&mut peer, WAIT).unwrap(), ROUTER);
+
+    let router_mac = [0x02, 0, 0, 0, 0, 0xfe];
+    peer.send(&arp(2, (router_mac, ROUTER), (OUR_MAC, US)))
+        .unwrap();
+    let frame = recv(&mut peer, WAIT).unwrap();
+    let (dst, _, ip) = parse(&frame);
+    assert_eq!(dst, router_mac);
+    let (ip, _) = etherparse::Ipv4Header::read_from_slice(ip).unwrap();
+    assert_eq!(ip.destination, [192, 0, 2, 1]);
+}
+
+#[test]
+fn broadcasts_need_no_asking() {
+    let (mut iface, mut peer) = tap(VirtualClock::new());
+    let socket = iface.bind_udp(5353).unwrap();
+    socket
+        .send_to(b"everyone", "10.0.0.255:53".parse().unwrap())
+        .unwrap();
+    let frame = recv(&mut peer, WAIT).unwrap();
+    let (dst, ethertype, _) = parse(&frame);
+    assert_eq!((dst, ethertype), (BROADCAST, 0x0800));
+}
+
+#[test]
+fn connections_work_over_ethernet() {
+    let (mut iface, mut peer) = tap(VirtualClock::new());
+    let jh = thread::spawn(move || {
+        let r = iface.connect("10.0.0.2:80".parse().unwrap());
+        (iface, r)
+    });
+    assert_request(&recv(&mut peer, WAIT).unwrap(), PEER);
+    peer.send(&arp(2, (PEER_MAC, PEER), (OUR_MAC, US))).unwrap();
+
+    let frame = recv(&mut peer, WAIT).unwrap();
+    let (dst, _, syn) = parse(&frame);
+    assert_eq!(dst, PEER_MAC);
+    let tcph = etherparse::TcpHeaderSlice::from_slice(&syn[20..]).unwrap();
+    assert!(tcph.syn());
+    let syn_ack = etherparse::PacketBuilder::ipv4(PEER, US, 64)
+        .tcp(80, tcph.source_port(), 1000, 64240)
+        .syn()
+        .ack(tcph.sequence_number() + 1);
+    let mut packet = Vec::new();
+    syn_ack.write(&mut packet, &[]).unwrap();
+    peer.send(&self::frame(OUR_MAC, 0x0800, &packet)).unwrap();
+
+    let (_iface, r) = jh.join().unwrap();
+    assert!(r.is_ok());
+}
+
+#[test]
+fn tap_devices_are_ipv4_only() {
+    let builder = InterfaceBuilder::new().mode(Mode::Tap);
+    let (nic, _peer) = MemoryDevice::pair();
+    let err = builder.clone().build_with_device(nic).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+    let (nic, _peer) = MemoryDevice::pair();
+    let builder = builder
+        .address(US, 24)
+        .address("fd00::1".parse::<std::net::Ipv6Addr>().unwrap(), 64);
+    let err = builder.build_with_device(nic).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::Unsupported);
+}
@@ -0,0 +1,167 @@
+use std::io::{ErrorKind, Read};
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use trust::{Device, InterfaceBuilder, MemoryDevice, ShutdownPolicy};
+
+mod common;
+use common::{Peer, QUIET, WAIT};
+
+/// Starts a blocking read on `stream`, and an accept on `listener`, and makes sure both are
+/// blocked.
+fn block(
+    mut stream: trust::TcpStream,
+    mut listener: trust::TcpListener,
+) -> (
+    thread::JoinHandle<std::io::Result<usize>>,
+    thread::JoinHandle<std::io::Result<trust::TcpStream>>,
+) {
+    let read = thread::spawn(move || stream.read(&mut [0u8; 16]));
+    let accept = thread::spawn(move || listener.accept());
+    thread::sleep(QUIET);
+    assert!(!read.is_finished() && !accept.is_finished());
+    (read, accept)
+}
+
+#[test]
+fn dropping_resets_connections_and_wakes_everyone_up() {
+    let (mut iface, listener, stream, mut peer) = Peer::connect();
+    iface.set_shutdown_policy(ShutdownPolicy::Reset);
+    let (read, accept) = block(stream, listener);
+
+    drop(iface);
+    let rst = peer.recv_until(|s| s.rst || s.fin);
+    assert!(rst.rst && !rst.fin);
+
+    assert_eq!(
+        read.join().unwrap().unwrap_err().kind(),
+        ErrorKind::ConnectionAborted
+    );
+    assert_eq!(
+        accept.join().unwrap().err().unwrap().kind(),
+        ErrorKind::ConnectionAborted
+    );
+}
+
+#[test]
+fn dropping_closes_connections_gracefully_when_asked_to() {
+    let (mut iface, listener, stream, mut peer) = Peer::connect();
+    iface.set_shutdown_policy(ShutdownPolicy::Close(Duration::from_secs(10)));
+    let (read, accept) = block(stream, listener);
+
+    let dropped = thread::spawn(move || drop(iface));
+    assert!(peer.recv_until(|s| s.fin || s.rst).fin);
+    // nothing new is accepted while we wait for the FIN to be acknowledged
+    assert_eq!(
+        accept.join().unwrap().err().unwrap().kind(),
+        ErrorKind::ConnectionAborted
+    );
+
+    peer.send(b"");
+    dropped.join().unwrap();
+    assert!(peer.recv(QUIET).is_none());
+    assert_eq!(
+        read.join().unwrap().unwrap_err().kind(),
+        ErrorKind::ConnectionAborted
+    );
+}
+
+#[test]
+fn dropping_resets_whatever_is_left_once_the_close_timeout_passes() {
+    let (mut iface, listener, stream, mut peer) = Peer::connect();
+    iface.set_shutdown_policy(ShutdownPolicy::Close(Duration::from_millis(200)));
+    let (read, accept) = block(stream, listener);
+
+    // the peer never acknowledges our FIN
+    drop(iface);
+    assert!(peer.recv_until(|s| s.rst).rst);
+
+    assert_eq!(
+        read.join().unwrap().unwrap_err().kind(),
+        ErrorKind::ConnectionAborted
+    );
+    assert_eq!(
+        accept.join().unwrap().err().unwrap().kind(),
+        ErrorKind::ConnectionAborted
+    );
+}
+
+/// A device that fails once told to, which ends the packet loop as surely as dropping the
+/// interface does.
+struct Failing {
+    inner: MemoryDevice,
+    failed: Arc<AtomicBool>,
+}
+
+impl Device for Failing {
+    fn recv(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.inner.recv(buf)
+    }
+
+    fn send(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.inner.send(buf)
+    }
+
+    fn poll(&mut self, timeout: Duration) -> std::io::Result<bool> {
+        if self.failed.load(Ordering::Relaxed) {
+            return Err(std::io::Error::other("device went away"));
+        }
+        self.inner.poll(timeout)
+    }
+}
+
+#[test]
+fn nothing_can_be_bound_once_the_packet_loop_is_gone() {
+    let (nic, _peer) = MemoryDevice::pair();
+    let failed = Arc::new(AtomicBool::new(false));
+    let nic = Failing {
+        inner: nic,
+        failed: failed.clone(),
+    };
+    let mut iface = InterfaceBuilder::new()
+        .address(Ipv4Addr::from(common::US), 24)
+        .build_with_device(nic)
+        .unwrap();
+    let listener = iface.bind(8000).unwrap();
+    let accept = thread::spawn(move || {
+        let mut listener = listener;
+        listener.accept().map(|_| ())
+    });
+
+    failed.store(true, Ordering::Relaxed);
+    let err = accept.join().unwrap().unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::ConnectionAborted);
+    let err = iface
+        .bind(8001)
+        .err()
+        .expect("bound after the packet loop exited");
+    assert_eq!(err.kind(), ErrorKind::ConnectionAborted);
+}
+
+#[test]
+fn connecting_fails_once_the_packet_loop_is_gone() {
+    // connect borrows the interface, so the interface can't be dropped while a connect is
+    // blocked; the packet loop can still go away underneath it, though
+    let (nic, mut peer) = MemoryDevice::pair();
+    let failed = Arc::new(AtomicBool::new(false));
+    let nic = Failing {
+        inner: nic,
+        failed: failed.clone(),
+    };
+    let mut iface = InterfaceBuilder::new()
+        .address(Ipv4Addr::from(common::US), 24)
+        .build_with_device(nic)
+        .unwrap();
+    let connect = thread::spawn(move || {
+        let r = iface.connect("10.0.0.2:80".parse().unwrap()).map(|_| ());
+        (iface, r)
+    });
+    assert!(common::recv_segment(&mut peer, WAIT).unwrap().syn);
+
+    failed.store(true, Ordering::Relaxed);
+    let (iface, r) = connect.join().unwrap();
+    assert_eq!(r.unwrap_err().kind(), ErrorKind::ConnectionAborted);
+    drop(iface);
+}
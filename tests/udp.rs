@@ -0,0 +1,304 @@
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr};
+use std::thread;
+use trust::{Device, Interface, InterfaceBuilder, MemoryDevice};
+
+mod common;
+use common::{PEER, PEER6, QUIET, US, US6, WAIT, datagram, datagram_to, datagram6, icmp4, recv};
+
+/// The ports and data of the UDP datagram in `packet`, after checking that its checksum is right.
+fn parse(packet: &[u8]) -> (u16, u16, Vec<u8>) {
+    let sliced = etherparse::SlicedPacket::from_ip(packet).unwrap();
+    let udp = match sliced.transport {
+        Some(etherparse::TransportSlice::Udp(udp)) => udp.to_header(),
+        _ => panic!("not udp"),
+    };
+    let expected = match sliced.ip {
+        Some(etherparse::InternetSlice::Ipv4(ip)) => udp
+            .calc_checksum_ipv4(&ip.to_header(), sliced.payload)
+            .unwrap(),
+        Some(etherparse::InternetSlice::Ipv6(ip, _)) => udp
+            .calc_checksum_ipv6(&ip.to_header(), sliced.payload)
+            .unwrap(),
+        None => unreachable!(),
+    };
+    assert_eq!(udp.checksum, expected, "bad checksum");
+    (
+        udp.source_port,
+        udp.destination_port,
+        sliced.payload.to_vec(),
+    )
+}
+
+#[test]
+fn datagrams_are_received_and_answered() {
+    let (nic, mut peer) = MemoryDevice::pair();
+    let mut iface = InterfaceBuilder::new()
+        .address(US, 24)
+        .build_with_device(nic)
+        .unwrap();
+    let socket = iface.bind_udp(5353).unwrap();
+    assert_eq!(
+        socket.local_addr().unwrap(),
+        "10.0.0.1:5353".parse().unwrap()
+    );
+
+    peer.send(&datagram(40000, 5353, b"hello")).unwrap();
+    let mut buf = [0; 64];
+    let (n, from) = socket.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"hello");
+    assert_eq!(from, "10.0.0.2:40000".parse().unwrap());
+
+    socket.send_to(b"hello yourself", from).unwrap();
+    let (src, dst, data) = parse(&recv(&mut peer, WAIT).unwrap());
+    assert_eq!((src, dst), (5353, 40000));
+    assert_eq!(data, b"hello yourself");
+}
+
+#[test]
+fn ipv6_datagrams_are_received_and_answered() {
+    let (nic, mut peer) = MemoryDevice::pair();
+    let mut iface = InterfaceBuilder::new()
+        .address(US6, 64)
+        .build_with_device(nic)
+        .unwrap();
+    let socket = iface.bind_udp(5353).unwrap();
+
+    peer.send(&datagram6(40000, 5353, b"hello")).unwrap();
+    let mut buf = [0; 64];
+    let (n, from) = socket.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"hello");
+    assert_eq!(from, SocketAddr::new(IpAddr::V6(PEER6), 40000));
+
+    socket.send_to(b"hello yourself", from).unwrap();
+    let (src, dst, data) = parse(&recv(&mut peer, WAIT).unwrap());
+    assert_eq!((src, dst), (5353, 40000));
+    assert_eq!(data, b"hello yourself");
+}
+
+#[test]
+fn datagrams_are_read_one_at_a_time() {
+    let (nic, mut peer) = MemoryDevice::pair();
+    let mut iface = Interface::with_device(nic).unwrap();
+    let socket = iface.bind_udp(5353).unwrap();
+
+    peer.send(&datagram(40000, 5353, b"first")).unwrap();
+    peer.send(&datagram(40000, 5353, b"second")).unwrap();
+    let mut buf = [0; 3];
+    // what doesn't fit is gone
+    assert_eq!(socket.recv(&mut buf).unwrap(), 3);
+    assert_eq!(&buf, b"fir");
+    assert_eq!(socket.recv(&mut buf).unwrap(), 3);
+    assert_eq!(&buf, b"sec");
+}
+
+#[test]
+fn closed_ports_are_unreachable() {
+    let (nic, mut peer) = MemoryDevice::pair();
+    let _iface = Interface::with_device(nic).unwrap();
+
+    let packet = datagram(40000, 9, b"anyone?");
+    peer.send(&packet).unwrap();
+    let error = recv(&mut peer, WAIT).unwrap();
+    let (ip, message) = etherparse::Ipv4Header::read_from_slice(&error).unwrap();
+    assert_eq!(ip.protocol, 1);
+    assert_eq!(message[..2], [3, 3]);
+    // the header, and the first 8 bytes of what it carried
+    assert_eq!(message[8..], packet[..28]);
+}
+
+#[test]
+fn ipv6_closed_ports_are_unreachable() {
+    let (nic, mut peer) = MemoryDevice::pair();
+    let _iface = InterfaceBuilder::new()
+        .address(US6, 64)
+        .build_with_device(nic)
+        .unwrap();
+
+    let packet = datagram6(40000, 9, b"anyone?");
+    peer.send(&packet).unwrap();
+    let error = recv(&mut peer, WAIT).unwrap();
+    let (ip, message) = etherparse::Ipv6Header::read_from_slice(&error).unwrap();
+    assert_eq!(ip.next_header, 58);
+    assert_eq!(message[..2], [1, 4]);
+    assert_eq!(message[8..], packet[..]);
+}
+
+#[test]
+fn bad_checksums_are_dropped() {
+    let (nic, mut peer) = MemoryDevice::pair();
+    let mut iface = Interface::with_device(nic).unwrap();
+    let socket = iface.bind_udp(5353).unwrap();
+
+    let mut bad = datagram(40000, 5353, b"bad");
+    *bad.last_mut().unwrap() ^= 0x01;
+    peer.send(&bad).unwrap();
+    // over IPv4, a sender may leave the checksum out
+    let mut unchecked = datagram(40000, 5353, b"unchecked");
+    unchecked[26..28].copy_from_slice(&[0, 0]);
+    peer.send(&unchecked).unwrap();
+
+    let mut buf = [0; 64];
+    let n = socket.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"unchecked");
+    assert_eq!(iface.stats().udp_checksum_errors, 1);
+    // and no error goes back for either
+    assert!(recv(&mut peer, QUIET).is_none());
+}
+
+#[test]
+fn connected_sockets_only_hear_from_their_peer() {
+    let (nic, mut peer) = MemoryDevice::pair();
+    let mut iface = InterfaceBuilder::new()
+        .address(US, 24)
+        .build_with_device(nic)
+        .unwrap();
+    let socket = iface.bind_udp(0).unwrap();
+    let port = socket.local_addr().unwrap().port();
+    assert!(port >= 49152);
+    assert_eq!(
+        socket.peer_addr().unwrap_err().kind(),
+        ErrorKind::NotConnected
+    );
+
+    let server: SocketAddr = "10.0.0.2:53".parse().unwrap();
+    socket.connect(server).unwrap();
+    assert_eq!(socket.peer_addr().unwrap(), server);
+    socket.send(b"query").unwrap();
+    let (src, dst, data) = parse(&recv(&mut peer, WAIT).unwrap());
+    assert_eq!((src, dst), (port, 53));
+    assert_eq!(data, b"query");
+
+    peer.send(&datagram(5353, port, b"spoofed")).unwrap();
+    peer.send(&datagram(53, port, b"answer")).unwrap();
+    let mut buf = [0; 64];
+    let (n, from) = socket.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"answer");
+    assert_eq!(from, server);
+}
+
+#[test]
+fn connected_sockets_hear_about_refusals() {
+    let (nic, mut peer) = MemoryDevice::pair();
+    let mut iface = InterfaceBuilder::new()
+        .address(US, 24)
+        .build_with_device(nic)
+        .unwrap();
+    let socket = iface.bind_udp(0).unwrap();
+    socket.connect("10.0.0.2:53".parse().unwrap()).unwrap();
+    socket.send(b"query").unwrap();
+    let sent = recv(&mut peer, WAIT).unwrap();
+
+    // port unreachable
+    let packet = icmp4(3, 3, [0; 4], &sent[..28]);
+    peer.send(&packet).unwrap();
+
+    let mut buf = [0; 64];
+    assert_eq!(
+        socket.recv(&mut buf).unwrap_err().kind(),
+        ErrorKind::ConnectionRefused
+    );
+    // only once, and the socket carries on
+    peer.send(&datagram(
+        53,
+        socket.local_addr().unwrap().port(),
+        b"answer",
+    ))
+    .unwrap();
+    assert_eq!(socket.recv(&mut buf).unwrap(), 6);
+}
+
+#[test]
+fn ports_are_bound_once() {
+    let (nic, _peer) = MemoryDevice::pair();
+    let mut iface = Interface::with_device(nic).unwrap();
+    let socket = iface.bind_udp(5353).unwrap();
+    assert_eq!(
+        iface.bind_udp(5353).err().unwrap().kind(),
+        ErrorKind::AddrInUse
+    );
+    // separately from TCP
+    let _listener = iface.bind(5353).unwrap();
+    drop(socket);
+    assert!(iface.bind_udp(5353).is_ok());
+}
+
+#[test]
+fn datagrams_too_large_for_the_link_are_refused() {
+    let (nic, mut peer) = MemoryDevice::pair();
+    let mut iface = InterfaceBuilder::new()
+        .address(US, 24)
+        .build_with_device(nic)
+        .unwrap();
+    let socket = iface.bind_udp(5353).unwrap();
+    let to: SocketAddr = "10.0.0.2:40000".parse().unwrap();
+
+    assert_eq!(
+        socket.send_to(&[0; 1473], to).unwrap_err().raw_os_error(),
+        Some(libc::EMSGSIZE)
+    );
+    assert_eq!(socket.send_to(&[0; 1472], to).unwrap(), 1472);
+    assert_eq!(recv(&mut peer, WAIT).unwrap().len(), 1500);
+}
+
+#[test]
+fn datagrams_too_large_for_the_path_are_refused() {
+    let (nic, mut peer) = MemoryDevice::pair();
+    let mut iface = InterfaceBuilder::new()
+        .address(US, 24)
+        .build_with_device(nic)
+        .unwrap();
+    let socket = iface.bind_udp(5353).unwrap();
+    let to: SocketAddr = "10.0.0.2:40000".parse().unwrap();
+    socket.send_to(&[0; 1400], to).unwrap();
+    let sent = recv(&mut peer, WAIT).unwrap();
+
+    // fragmentation needed, with a next-hop MTU of 1000
+    peer.send(&icmp4(3, 4, [0, 0, 0x03, 0xe8], &sent[..28]))
+        .unwrap();
+    iface.settle();
+    assert_eq!(
+        socket.send_to(&[0; 973], to).unwrap_err().raw_os_error(),
+        Some(libc::EMSGSIZE)
+    );
+    assert_eq!(socket.send_to(&[0; 972], to).unwrap(), 972);
+    assert_eq!(recv(&mut peer, WAIT).unwrap().len(), 1000);
+}
+
+#[test]
+fn datagrams_to_groups_are_received() {
+    let (nic, mut peer) = MemoryDevice::pair();
+    let mut iface = InterfaceBuilder::new()
+        .address(US, 24)
+        .build_with_device(nic)
+        .unwrap();
+    let socket = iface.bind_udp(5353).unwrap();
+
+    // nobody complains about a port nobody listens on, though
+    peer.send(&datagram_to([224, 0, 0, 251], 40000, 9, b"anyone?"))
+        .unwrap();
+    assert!(recv(&mut peer, QUIET).is_none());
+
+    let mut buf = [0; 64];
+    for group in [[224, 0, 0, 251], [255, 255, 255, 255]] {
+        peer.send(&datagram_to(group, 40000, 5353, b"everyone"))
+            .unwrap();
+        let (n, from) = socket.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"everyone");
+        assert_eq!(from, SocketAddr::from((PEER, 40000)));
+    }
+}
+
+#[test]
+fn blocked_readers_find_out_about_shutdown() {
+    let (nic, _peer) = MemoryDevice::pair();
+    let mut iface = Interface::with_device(nic).unwrap();
+    let socket = iface.bind_udp(5353).unwrap();
+    let jh = thread::spawn(move || socket.recv(&mut [0; 64]));
+    thread::sleep(QUIET);
+    drop(iface);
+    assert_eq!(
+        jh.join().unwrap().unwrap_err().kind(),
+        ErrorKind::ConnectionAborted
+    );
+}